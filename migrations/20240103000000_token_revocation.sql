@@ -0,0 +1,11 @@
+-- Tokens de acceso revocados (por jti) hasta su expiración natural
+CREATE TABLE IF NOT EXISTS revoked_tokens (
+    jti TEXT PRIMARY KEY,
+    user_id INTEGER NOT NULL,
+    expires_at INTEGER NOT NULL, -- epoch seconds, igual que 'exp' en el JWT
+    revoked_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+-- Tokens con 'iat' anterior a este valor (epoch seconds) se rechazan
+ALTER TABLE users ADD COLUMN tokens_valid_after INTEGER;
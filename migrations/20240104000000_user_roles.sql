@@ -0,0 +1,2 @@
+-- Rol del usuario ('user' | 'admin')
+ALTER TABLE users ADD COLUMN role TEXT NOT NULL DEFAULT 'user';
@@ -0,0 +1,7 @@
+-- Intentos de login fallidos por cuenta ('account:<email>') o IP ('ip:<addr>')
+CREATE TABLE IF NOT EXISTS login_attempts (
+    key TEXT PRIMARY KEY,
+    failures INTEGER NOT NULL,
+    last_failure_at INTEGER NOT NULL, -- epoch seconds
+    locked_until INTEGER -- epoch seconds
+);
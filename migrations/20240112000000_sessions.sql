@@ -0,0 +1,22 @@
+-- Sesiones por dispositivo: una por login. Cada sesión agrupa una familia de
+-- refresh tokens y su id viaja en el claim `sid` de los access tokens.
+CREATE TABLE IF NOT EXISTS sessions (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER NOT NULL,
+    family_id TEXT NOT NULL UNIQUE,
+    user_agent TEXT,
+    ip TEXT,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    last_seen_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    revoked_at DATETIME,
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS idx_sessions_user ON sessions(user_id);
+
+-- Las familias vigentes antes de esta migración pasan a ser sesiones (sin dispositivo)
+INSERT INTO sessions (user_id, family_id, created_at, last_seen_at)
+SELECT user_id, family_id, MIN(created_at), MAX(created_at)
+FROM refresh_tokens
+WHERE revoked_at IS NULL
+GROUP BY user_id, family_id;
@@ -0,0 +1,25 @@
+-- Datos de perfil editables por el usuario
+ALTER TABLE users ADD COLUMN display_name TEXT;
+ALTER TABLE users ADD COLUMN timezone TEXT;
+ALTER TABLE users ADD COLUMN locale TEXT;
+ALTER TABLE users ADD COLUMN avatar_url TEXT;
+
+-- Las tareas se borran con su dueño. SQLite no permite cambiar una FOREIGN KEY,
+-- así que se reconstruye la tabla.
+CREATE TABLE tasks_new (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    title TEXT NOT NULL,
+    description TEXT,
+    completed BOOLEAN NOT NULL DEFAULT FALSE,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    owner_id INTEGER NOT NULL,
+    FOREIGN KEY (owner_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+INSERT INTO tasks_new (id, title, description, completed, created_at, owner_id)
+SELECT id, title, description, completed, created_at, owner_id FROM tasks;
+
+DROP TABLE tasks;
+ALTER TABLE tasks_new RENAME TO tasks;
+
+CREATE INDEX IF NOT EXISTS idx_tasks_owner ON tasks(owner_id);
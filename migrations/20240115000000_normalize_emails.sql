@@ -0,0 +1,19 @@
+-- Emails sin distinción de mayúsculas. Si ya existen cuentas que solo difieren en
+-- mayúsculas o espacios, la migración falla con el CHECK de abajo: hay que fusionarlas
+-- o renombrarlas a mano antes de desplegar (ver README, "Emails duplicados").
+CREATE TEMP TABLE email_duplicates (
+    email TEXT CONSTRAINT duplicate_emails_must_be_merged_first CHECK (email IS NULL)
+);
+
+INSERT INTO email_duplicates (email)
+SELECT lower(trim(email)) FROM users
+GROUP BY lower(trim(email))
+HAVING COUNT(*) > 1;
+
+DROP TABLE email_duplicates;
+
+-- Forma canónica (lower() de SQLite solo pliega ASCII; los dominios IDNA los
+-- normaliza la aplicación al registrarse)
+UPDATE users SET email = lower(trim(email));
+
+CREATE UNIQUE INDEX IF NOT EXISTS idx_users_email_nocase ON users (email COLLATE NOCASE);
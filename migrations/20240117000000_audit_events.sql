@@ -0,0 +1,31 @@
+-- Registro de auditoría de eventos de autenticación y de cuenta. Sin clave foránea
+-- a users: los eventos sobreviven al borrado de la cuenta.
+CREATE TABLE IF NOT EXISTS audit_events (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    action TEXT NOT NULL,
+    -- Cuenta a la que se refiere el evento (NULL en logins fallidos de emails desconocidos)
+    user_id INTEGER,
+    -- Quién lo hizo: el propio usuario, un admin, o NULL si no estaba autenticado
+    actor_id INTEGER,
+    ip TEXT,
+    user_agent TEXT,
+    payload TEXT NOT NULL DEFAULT '{}',
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS idx_audit_events_user ON audit_events(user_id, id);
+CREATE INDEX IF NOT EXISTS idx_audit_events_actor ON audit_events(actor_id, id);
+CREATE INDEX IF NOT EXISTS idx_audit_events_action ON audit_events(action, id);
+
+-- Solo se añaden eventos: ni la aplicación ni un error pueden reescribir el historial
+CREATE TRIGGER IF NOT EXISTS audit_events_no_update
+BEFORE UPDATE ON audit_events
+BEGIN
+    SELECT RAISE(ABORT, 'audit_events is append-only');
+END;
+
+CREATE TRIGGER IF NOT EXISTS audit_events_no_delete
+BEFORE DELETE ON audit_events
+BEGIN
+    SELECT RAISE(ABORT, 'audit_events is append-only');
+END;
@@ -0,0 +1,2 @@
+-- Peticiones de autorización iniciadas desde una sesión para vincular la identidad a esa cuenta
+ALTER TABLE oidc_auth_requests ADD COLUMN link_user_id INTEGER REFERENCES users(id) ON DELETE CASCADE;
@@ -0,0 +1,2 @@
+-- Token del enlace de descarga que se envía por correo (solo se guarda el hash)
+ALTER TABLE data_exports ADD COLUMN download_token_hash TEXT;
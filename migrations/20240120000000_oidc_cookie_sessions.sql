@@ -0,0 +1,2 @@
+-- El login por SSO puede terminar en una sesión de cookies, como `/token` con `cookie=true`
+ALTER TABLE oidc_auth_requests ADD COLUMN cookie BOOLEAN NOT NULL DEFAULT FALSE;
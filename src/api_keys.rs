@@ -0,0 +1,82 @@
+use axum::http::Method;
+use chrono::{Duration, Utc};
+use rand_core::{OsRng, RngCore};
+use sqlx::{FromRow, SqlitePool};
+
+use crate::{
+    error::AppError,
+    models::{ApiKeyScope, User},
+    tokens,
+};
+
+/// Prefijo que distingue una API key de un JWT en `Authorization: Bearer`.
+pub const KEY_PREFIX: &str = "bak_";
+// No se actualiza `last_used_at` más de una vez por minuto y clave
+const LAST_USED_RESOLUTION_SECS: i64 = 60;
+
+#[derive(Debug, FromRow)]
+struct ActiveKey {
+    id: i64,
+    user_id: i64,
+    scope: ApiKeyScope,
+}
+
+/// Genera una clave nueva `bak_<prefix>_<secreto>` y devuelve `(clave, prefix)`.
+pub fn generate_key() -> (String, String) {
+    let mut bytes = [0u8; 4];
+    OsRng.fill_bytes(&mut bytes);
+    let prefix: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+
+    let key = format!("{}{}_{}", KEY_PREFIX, prefix, tokens::generate_opaque_token());
+    (key, prefix)
+}
+
+/// Las API keys solo dan acceso a `/tasks`; las de solo lectura, además, solo a
+/// métodos seguros.
+pub fn permits(scope: ApiKeyScope, method: &Method, path: &str) -> Result<(), AppError> {
+    if !path.starts_with("/tasks") {
+        return Err(AppError::Forbidden(
+            "API keys can only be used on /tasks endpoints".to_string(),
+        ));
+    }
+
+    let read_only = matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS);
+    if scope == ApiKeyScope::Read && !read_only {
+        return Err(AppError::Forbidden("API key is read-only".to_string()));
+    }
+
+    Ok(())
+}
+
+/// Resuelve una API key presentada a su usuario y scope, registrando el último uso.
+pub async fn authenticate(pool: &SqlitePool, key: &str) -> Result<(User, ApiKeyScope), AppError> {
+    let now = Utc::now().naive_utc();
+
+    let active = sqlx::query_as::<_, ActiveKey>(
+        "SELECT id, user_id, scope FROM api_keys
+        WHERE key_hash = ? AND revoked_at IS NULL AND (expires_at IS NULL OR expires_at > ?)",
+    )
+    .bind(tokens::hash_token(key))
+    .bind(now)
+    .fetch_optional(pool)
+    .await?
+    .ok_or(AppError::AuthError("Invalid API key".to_string()))?;
+
+    sqlx::query(
+        "UPDATE api_keys SET last_used_at = ?
+        WHERE id = ? AND (last_used_at IS NULL OR last_used_at < ?)",
+    )
+    .bind(now)
+    .bind(active.id)
+    .bind(now - Duration::seconds(LAST_USED_RESOLUTION_SECS))
+    .execute(pool)
+    .await?;
+
+    let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = ?")
+        .bind(active.user_id)
+        .fetch_optional(pool)
+        .await?
+        .ok_or(AppError::AuthError("User not found".to_string()))?;
+
+    Ok((user, active.scope))
+}
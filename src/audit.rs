@@ -0,0 +1,115 @@
+use sqlx::{Executor, QueryBuilder, Sqlite, SqlitePool};
+
+use crate::{
+    error::AppError,
+    handlers::tasks::page_bounds,
+    middleware::ClientInfo,
+    models::{AuditAction, AuditEvent, AuditQuery},
+    sessions::MAX_USER_AGENT_LEN,
+};
+
+pub(crate) const AUDIT_COLUMNS: &str = "id, action, user_id, actor_id, ip, user_agent, payload, created_at";
+
+/// Añade un evento al registro de auditoría. `user_id` es la cuenta afectada y
+/// `actor_id` quien hizo la acción (el propio usuario, un admin o nadie autenticado).
+/// Con la transacción de la acción como `executor`, se guardan los dos o ninguno.
+pub async fn record<'e, E>(
+    executor: E,
+    client: &ClientInfo,
+    action: AuditAction,
+    user_id: Option<i64>,
+    actor_id: Option<i64>,
+    payload: serde_json::Value,
+) -> Result<(), AppError>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
+    let user_agent = client
+        .user_agent
+        .as_deref()
+        .map(|ua| ua.chars().take(MAX_USER_AGENT_LEN).collect::<String>());
+
+    sqlx::query(
+        "INSERT INTO audit_events (action, user_id, actor_id, ip, user_agent, payload) VALUES (?, ?, ?, ?, ?, ?)",
+    )
+    .bind(action)
+    .bind(user_id)
+    .bind(actor_id)
+    .bind(client.ip.map(|ip| ip.to_string()))
+    .bind(user_agent)
+    .bind(payload.to_string())
+    .execute(executor)
+    .await?;
+
+    Ok(())
+}
+
+/// Registra una acción que ya se aplicó y no puede deshacerse (un login, una renovación de token...):
+/// si la auditoría falla se deja en el log en lugar de responder con un error.
+pub async fn record_applied(
+    pool: &SqlitePool,
+    client: &ClientInfo,
+    action: AuditAction,
+    user_id: Option<i64>,
+    actor_id: Option<i64>,
+    payload: serde_json::Value,
+) {
+    if let Err(e) = record(pool, client, action, user_id, actor_id, payload.clone()).await {
+        tracing::error!(?action, ?user_id, ?actor_id, %payload, "Cannot record audit event: {}", e);
+    }
+}
+
+/// Eventos que cumplen los filtros, del más reciente al más antiguo.
+pub async fn search(pool: &SqlitePool, query: &AuditQuery) -> Result<Vec<AuditEvent>, AppError> {
+    let mut sql = QueryBuilder::<Sqlite>::new(format!("SELECT {} FROM audit_events WHERE 1 = 1", AUDIT_COLUMNS));
+
+    if let Some(user_id) = query.user_id {
+        sql.push(" AND user_id = ").push_bind(user_id);
+    }
+    if let Some(actor_id) = query.actor_id {
+        sql.push(" AND actor_id = ").push_bind(actor_id);
+    }
+    if let Some(action) = query.action {
+        sql.push(" AND action = ").push_bind(action);
+    }
+    if let Some(ip) = &query.ip {
+        sql.push(" AND ip = ").push_bind(ip.clone());
+    }
+    if let Some(since) = query.since {
+        sql.push(" AND created_at >= ").push_bind(since);
+    }
+    if let Some(until) = query.until {
+        sql.push(" AND created_at < ").push_bind(until);
+    }
+
+    let (skip, limit) = page_bounds(query.skip, query.limit);
+    sql.push(" ORDER BY id DESC LIMIT ")
+        .push_bind(limit)
+        .push(" OFFSET ")
+        .push_bind(skip);
+
+    let events = sql.build_query_as::<AuditEvent>().fetch_all(pool).await?;
+
+    Ok(events)
+}
+
+/// Historial de un usuario (su actividad y lo que otros hicieron sobre su cuenta).
+pub async fn for_user(
+    pool: &SqlitePool,
+    user_id: i64,
+    skip: Option<i64>,
+    limit: Option<i64>,
+) -> Result<Vec<AuditEvent>, AppError> {
+    let (skip, limit) = page_bounds(skip, limit);
+    let events = sqlx::query_as::<_, AuditEvent>(&format!(
+        "SELECT {} FROM audit_events WHERE user_id = ? ORDER BY id DESC LIMIT ? OFFSET ?",
+        AUDIT_COLUMNS
+    ))
+    .bind(user_id)
+    .bind(limit)
+    .bind(skip)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(events)
+}
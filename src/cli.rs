@@ -0,0 +1,58 @@
+use sqlx::SqlitePool;
+
+use crate::{
+    audit, email,
+    login_attempts::{self, SqliteLoginAttemptStore},
+    middleware::ClientInfo,
+    models::{AuditAction, Role},
+};
+
+const USAGE: &str = "Usage:
+  backend-axum-rust                         Start the HTTP server
+  backend-axum-rust set-role <email> <role> Set a user's role (user | admin)
+  backend-axum-rust unlock <email>          Clear a login lockout (SQLite attempt store only)";
+
+/// Comandos de administración que se ejecutan contra la DB sin levantar el servidor.
+pub async fn run(pool: &SqlitePool, args: &[String]) -> anyhow::Result<()> {
+    match args {
+        [cmd, email, role] if cmd == "set-role" => {
+            let role: Role = role.parse().map_err(anyhow::Error::msg)?;
+            let email = email::lookup_key(email);
+
+            let mut tx = pool.begin().await?;
+            let user_id: Option<i64> = sqlx::query_scalar("UPDATE users SET role = ? WHERE email = ? RETURNING id")
+                .bind(role)
+                .bind(&email)
+                .fetch_optional(&mut *tx)
+                .await?;
+
+            let Some(user_id) = user_id else {
+                anyhow::bail!("User not found: {}", email);
+            };
+
+            // Sin actor ni IP: el cambio se hizo desde la consola del servidor
+            audit::record(
+                &mut *tx,
+                &ClientInfo::default(),
+                AuditAction::RoleChanged,
+                Some(user_id),
+                None,
+                serde_json::json!({ "role": role, "via": "cli" }),
+            )
+            .await?;
+            tx.commit().await?;
+
+            println!("Role of {} set to {:?}", email, role);
+            Ok(())
+        }
+        [cmd, email] if cmd == "unlock" => {
+            let email = email::lookup_key(email);
+            let store = SqliteLoginAttemptStore::new(pool.clone());
+            login_attempts::unlock(&store, &email).await?;
+
+            println!("Login lockout of {} cleared", email);
+            Ok(())
+        }
+        _ => anyhow::bail!("{}", USAGE),
+    }
+}
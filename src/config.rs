@@ -0,0 +1,207 @@
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// Acciones que pueden permitirse a cuentas con el email sin verificar.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UnverifiedAction {
+    Login,
+    ReadTasks,
+    WriteTasks,
+}
+
+impl FromStr for UnverifiedAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "login" => Ok(UnverifiedAction::Login),
+            "read_tasks" => Ok(UnverifiedAction::ReadTasks),
+            "write_tasks" => Ok(UnverifiedAction::WriteTasks),
+            other => Err(format!("Unknown unverified action: {}", other)),
+        }
+    }
+}
+
+/// Política de bloqueo por logins fallidos.
+#[derive(Debug, Clone)]
+pub struct LockoutPolicy {
+    /// Fallos seguidos que bloquean una cuenta (`LOGIN_MAX_FAILURES`, 0 = sin límite).
+    pub max_account_failures: u32,
+    /// Fallos que bloquean una IP, sea cual sea la cuenta (`LOGIN_MAX_FAILURES_PER_IP`).
+    pub max_ip_failures: u32,
+    /// Primer bloqueo; cada fallo posterior lo duplica (`LOGIN_LOCKOUT_SECS`).
+    pub lockout_secs: i64,
+    /// Tope del bloqueo (`LOGIN_MAX_LOCKOUT_SECS`).
+    pub max_lockout_secs: i64,
+    /// Sin fallos durante este tiempo el contador vuelve a cero (`LOGIN_FAILURE_WINDOW_SECS`).
+    pub failure_window_secs: i64,
+}
+
+fn env_or<T: FromStr>(name: &str, default: T) -> T {
+    env::var(name)
+        .ok()
+        .map(|v| v.parse().unwrap_or_else(|_| panic!("Invalid {}", name)))
+        .unwrap_or(default)
+}
+
+impl LockoutPolicy {
+    pub fn from_env() -> Self {
+        Self {
+            max_account_failures: env_or("LOGIN_MAX_FAILURES", 5),
+            max_ip_failures: env_or("LOGIN_MAX_FAILURES_PER_IP", 20),
+            lockout_secs: env_or("LOGIN_LOCKOUT_SECS", 60),
+            max_lockout_secs: env_or("LOGIN_MAX_LOCKOUT_SECS", 60 * 60),
+            failure_window_secs: env_or("LOGIN_FAILURE_WINDOW_SECS", 15 * 60),
+        }
+    }
+}
+
+/// Política para contraseñas nuevas.
+#[derive(Debug, Clone)]
+pub struct PasswordPolicy {
+    /// `PASSWORD_MIN_LENGTH`.
+    pub min_length: usize,
+    /// Acota el coste de Argon2 ante contraseñas arbitrariamente largas (`PASSWORD_MAX_LENGTH`).
+    pub max_length: usize,
+    /// Clases distintas exigidas entre minúsculas, mayúsculas, dígitos y símbolos
+    /// (`PASSWORD_MIN_CHARACTER_CLASSES`).
+    pub min_character_classes: usize,
+    /// Directorio con el volcado de contraseñas filtradas por prefijo de SHA-1
+    /// (`PASSWORD_BREACHED_DIR`); sin él no se comprueba.
+    pub breached_passwords_dir: Option<PathBuf>,
+}
+
+impl PasswordPolicy {
+    pub fn from_env() -> Self {
+        Self {
+            min_length: env_or("PASSWORD_MIN_LENGTH", 8),
+            max_length: env_or("PASSWORD_MAX_LENGTH", 128),
+            min_character_classes: env_or("PASSWORD_MIN_CHARACTER_CLASSES", 2),
+            breached_passwords_dir: env::var("PASSWORD_BREACHED_DIR").ok().map(PathBuf::from),
+        }
+    }
+}
+
+/// Costes de Argon2id para los hashes nuevos. Los hashes con costes menores se
+/// rehacen en el siguiente login correcto.
+#[derive(Debug, Clone)]
+pub struct Argon2Config {
+    /// Memoria en KiB (`ARGON2_MEMORY_KIB`).
+    pub memory_kib: u32,
+    /// Iteraciones (`ARGON2_ITERATIONS`).
+    pub iterations: u32,
+    /// Paralelismo (`ARGON2_PARALLELISM`).
+    pub parallelism: u32,
+}
+
+impl Argon2Config {
+    pub fn from_env() -> Self {
+        let config = Self {
+            memory_kib: env_or("ARGON2_MEMORY_KIB", argon2::Params::DEFAULT_M_COST),
+            iterations: env_or("ARGON2_ITERATIONS", argon2::Params::DEFAULT_T_COST),
+            parallelism: env_or("ARGON2_PARALLELISM", argon2::Params::DEFAULT_P_COST),
+        };
+
+        config.params().expect("Invalid ARGON2_* parameters");
+        config
+    }
+
+    pub fn params(&self) -> Result<argon2::Params, argon2::Error> {
+        argon2::Params::new(self.memory_kib, self.iterations, self.parallelism, None)
+    }
+}
+
+/// Proveedor OpenID Connect externo (`OIDC_PROVIDERS=corp` + variables `OIDC_CORP_*`).
+#[derive(Debug, Clone)]
+pub struct OidcProviderConfig {
+    /// Nombre usado en las rutas `/auth/oidc/:provider/...`.
+    pub name: String,
+    /// Issuer; el documento de discovery se lee de `<issuer>/.well-known/openid-configuration`.
+    pub issuer: String,
+    pub client_id: String,
+    /// Secreto del cliente; los clientes públicos solo usan PKCE.
+    pub client_secret: Option<String>,
+    pub scopes: String,
+    /// Crear la cuenta en el primer login si no existe ninguna con ese email.
+    pub allow_signup: bool,
+}
+
+impl OidcProviderConfig {
+    fn from_env(name: &str) -> Self {
+        let var = |suffix: &str| format!("OIDC_{}_{}", name.to_uppercase().replace('-', "_"), suffix);
+        let required = |suffix: &str| {
+            let var = var(suffix);
+            env::var(&var).unwrap_or_else(|_| panic!("{} must be set", var))
+        };
+
+        Self {
+            name: name.to_string(),
+            issuer: required("ISSUER").trim_end_matches('/').to_string(),
+            client_id: required("CLIENT_ID"),
+            client_secret: env::var(var("CLIENT_SECRET")).ok(),
+            scopes: env::var(var("SCOPES")).unwrap_or_else(|_| "openid email profile".to_string()),
+            allow_signup: env_or(&var("ALLOW_SIGNUP"), true),
+        }
+    }
+}
+
+/// Configuración de la aplicación leída del entorno (`.env`).
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// URL pública del frontend/API, usada en los enlaces enviados por correo.
+    pub base_url: String,
+    /// Página del frontend donde se elige la contraseña nueva (`APP_PASSWORD_RESET_URL`);
+    /// el correo de recuperación enlaza a `URL?token=...`.
+    pub password_reset_url: Option<String>,
+    /// Acciones permitidas antes de verificar el email (`UNVERIFIED_ALLOWED_ACTIONS`).
+    pub unverified_actions: HashSet<UnverifiedAction>,
+    pub login_lockout: LockoutPolicy,
+    pub argon2: Argon2Config,
+    pub password_policy: PasswordPolicy,
+    /// Tomar la IP del cliente de `X-Forwarded-For` (solo detrás de un proxy de confianza).
+    pub trust_forwarded_for: bool,
+    /// Marcar las cookies de sesión como `Secure` (`COOKIE_SECURE`; desactivar solo en
+    /// desarrollo sin HTTPS).
+    pub cookie_secure: bool,
+    /// Proveedores de login externo por nombre.
+    pub oidc_providers: HashMap<String, OidcProviderConfig>,
+    /// Cuentas con más tareas que esto exportan sus datos en segundo plano
+    /// (`EXPORT_INLINE_MAX_TASKS`).
+    pub export_inline_max_tasks: i64,
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        let unverified_actions = env::var("UNVERIFIED_ALLOWED_ACTIONS")
+            .unwrap_or_else(|_| "login,read_tasks".to_string())
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(|s| s.parse().expect("Invalid UNVERIFIED_ALLOWED_ACTIONS"))
+            .collect();
+
+        Self {
+            base_url: env::var("APP_BASE_URL")
+                .unwrap_or_else(|_| "http://localhost:8000".to_string())
+                .trim_end_matches('/')
+                .to_string(),
+            password_reset_url: env::var("APP_PASSWORD_RESET_URL").ok().filter(|url| !url.is_empty()),
+            unverified_actions,
+            login_lockout: LockoutPolicy::from_env(),
+            argon2: Argon2Config::from_env(),
+            password_policy: PasswordPolicy::from_env(),
+            trust_forwarded_for: env_or("TRUST_FORWARDED_FOR", false),
+            cookie_secure: env_or("COOKIE_SECURE", true),
+            oidc_providers: env::var("OIDC_PROVIDERS")
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .map(|name| (name.to_string(), OidcProviderConfig::from_env(name)))
+                .collect(),
+            export_inline_max_tasks: env_or("EXPORT_INLINE_MAX_TASKS", 1000),
+        }
+    }
+}
@@ -0,0 +1,117 @@
+use axum::http::{header, HeaderMap, HeaderValue, Method};
+use subtle::ConstantTimeEq;
+
+use crate::{
+    config::Config,
+    error::AppError,
+    models::{Claims, Token},
+    oidc::AUTH_REQUEST_TTL_MINUTES,
+    tokens::{self, REFRESH_TOKEN_TTL_DAYS},
+};
+
+/// Cookie HttpOnly con el access token.
+pub const ACCESS_COOKIE: &str = "access_token";
+/// Cookie HttpOnly con el refresh token; solo se envía a `/token...`.
+pub const REFRESH_COOKIE: &str = "refresh_token";
+/// Cookie legible desde JS con el token CSRF, que el cliente repite en `X-CSRF-Token`.
+pub const CSRF_COOKIE: &str = "csrf_token";
+pub const CSRF_HEADER: &str = "x-csrf-token";
+/// Cookie HttpOnly con el hash del `state` OIDC: liga el callback al navegador que
+/// inició el flujo. Solo se envía a `/auth/oidc/...`.
+pub const OIDC_STATE_COOKIE: &str = "oidc_state";
+const OIDC_COOKIE_PATH: &str = "/auth/oidc";
+
+/// Valor de una cookie de la petición.
+pub fn get<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+fn cookie(config: &Config, name: &str, value: &str, path: &str, max_age: i64, extra: &str) -> HeaderValue {
+    let secure = if config.cookie_secure { "; Secure" } else { "" };
+    format!("{}={}; Path={}; Max-Age={}{}{}", name, value, path, max_age, extra, secure)
+        .parse()
+        .expect("cookie values are header-safe")
+}
+
+/// `Set-Cookie` de una sesión de navegador: los dos tokens y el token CSRF.
+pub fn session_cookies(config: &Config, token: &Token, csrf_token: &str) -> [(header::HeaderName, HeaderValue); 3] {
+    let refresh_max_age = REFRESH_TOKEN_TTL_DAYS * 24 * 60 * 60;
+
+    [
+        (
+            header::SET_COOKIE,
+            cookie(config, ACCESS_COOKIE, &token.access_token, "/", token.expires_in as i64, "; HttpOnly; SameSite=Lax"),
+        ),
+        (
+            header::SET_COOKIE,
+            cookie(config, REFRESH_COOKIE, &token.refresh_token, "/token", refresh_max_age, "; HttpOnly; SameSite=Strict"),
+        ),
+        (
+            header::SET_COOKIE,
+            cookie(config, CSRF_COOKIE, csrf_token, "/", refresh_max_age, "; SameSite=Lax"),
+        ),
+    ]
+}
+
+/// `Set-Cookie` que borran la sesión del navegador (logout).
+pub fn clear_session_cookies(config: &Config) -> [(header::HeaderName, HeaderValue); 3] {
+    [
+        (header::SET_COOKIE, cookie(config, ACCESS_COOKIE, "", "/", 0, "; HttpOnly; SameSite=Lax")),
+        (header::SET_COOKIE, cookie(config, REFRESH_COOKIE, "", "/token", 0, "; HttpOnly; SameSite=Strict")),
+        (header::SET_COOKIE, cookie(config, CSRF_COOKIE, "", "/", 0, "; SameSite=Lax")),
+    ]
+}
+
+/// `Set-Cookie` del `state` de un flujo OIDC recién iniciado. `SameSite=Lax` basta: la
+/// vuelta del proveedor al callback es una navegación GET de primer nivel.
+pub fn oidc_state_cookie(config: &Config, state: &str) -> (header::HeaderName, HeaderValue) {
+    let max_age = AUTH_REQUEST_TTL_MINUTES * 60;
+    (
+        header::SET_COOKIE,
+        cookie(config, OIDC_STATE_COOKIE, &tokens::hash_token(state), OIDC_COOKIE_PATH, max_age, "; HttpOnly; SameSite=Lax"),
+    )
+}
+
+/// `Set-Cookie` que borra el `state` OIDC una vez usado.
+pub fn clear_oidc_state_cookie(config: &Config) -> (header::HeaderName, HeaderValue) {
+    (
+        header::SET_COOKIE,
+        cookie(config, OIDC_STATE_COOKIE, "", OIDC_COOKIE_PATH, 0, "; HttpOnly; SameSite=Lax"),
+    )
+}
+
+/// El `state` del callback debe ser el del flujo que inició este navegador. Sin esto,
+/// quien abra la URL de autorización de otro (un enlace de vinculación o un login)
+/// acabaría con su identidad en la cuenta del atacante o en la sesión del atacante.
+pub fn verify_oidc_state(headers: &HeaderMap, state: &str) -> Result<(), AppError> {
+    let expected = get(headers, OIDC_STATE_COOKIE).unwrap_or_default();
+    let presented = tokens::hash_token(state);
+
+    if bool::from(presented.as_bytes().ct_eq(expected.as_bytes())) {
+        Ok(())
+    } else {
+        Err(AppError::AuthError("OIDC state does not belong to this browser".to_string()))
+    }
+}
+
+/// Las peticiones que modifican estado autenticadas por cookie deben repetir en
+/// `X-CSRF-Token` el token cuyo hash va firmado en el claim `csrf` del access token.
+pub fn verify_csrf(headers: &HeaderMap, method: &Method, claims: &Claims) -> Result<(), AppError> {
+    if matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS) {
+        return Ok(());
+    }
+
+    let presented = headers.get(CSRF_HEADER).and_then(|v| v.to_str().ok());
+
+    match (presented, &claims.csrf) {
+        (Some(presented), Some(expected)) if tokens::hash_token(presented) == *expected => Ok(()),
+        _ => Err(AppError::Forbidden("Missing or invalid CSRF token".to_string())),
+    }
+}
@@ -0,0 +1,93 @@
+use sqlx::SqlitePool;
+use std::collections::HashMap;
+
+use crate::error::AppError;
+
+/// Valida una dirección de email y devuelve su forma canónica, con la que se guarda y
+/// se busca: sin espacios alrededor, el dominio en ASCII (IDNA) y todo en minúsculas,
+/// de modo que `Bob@Ejemplo.com` y `bob@ejemplo.com` son la misma cuenta.
+pub fn normalize_email(email: &str) -> Result<String, AppError> {
+    let invalid = || AppError::ValidationError("Invalid email address".to_string());
+
+    let email = email.trim();
+    let (local, domain) = email.rsplit_once('@').ok_or_else(invalid)?;
+
+    if local.is_empty()
+        || local.contains('@')
+        || !domain.contains('.')
+        || domain.starts_with('.')
+        || domain.ends_with('.')
+        || email.chars().any(|c| c.is_whitespace() || c.is_control())
+    {
+        return Err(invalid());
+    }
+
+    // Solo nombres de dominio (no IPs); `Host::parse` aplica IDNA y pasa a minúsculas
+    let domain = match url::Host::parse(domain) {
+        Ok(url::Host::Domain(domain)) => domain,
+        _ => return Err(invalid()),
+    };
+
+    Ok(format!("{}@{}", local.to_lowercase(), domain))
+}
+
+/// Forma canónica de un email presentado para buscar una cuenta. Un email inválido no
+/// es un error: simplemente no coincidirá con ninguna cuenta.
+pub fn lookup_key(email: &str) -> String {
+    normalize_email(email).unwrap_or_else(|_| email.trim().to_lowercase())
+}
+
+/// Pasa a la forma canónica los emails guardados antes de normalizarlos en la aplicación.
+/// La migración solo pudo plegar ASCII (`lower()` de SQLite), así que las direcciones con
+/// mayúsculas no ASCII o dominios internacionales no coincidirían al buscarlas. Si dos
+/// cuentas quedarían con el mismo email no se cambia nada y falla: hay que fusionarlas antes.
+pub async fn normalize_stored_emails(pool: &SqlitePool) -> Result<u64, AppError> {
+    let users: Vec<(i64, String)> = sqlx::query_as("SELECT id, email FROM users ORDER BY id")
+        .fetch_all(pool)
+        .await?;
+
+    let mut owners: HashMap<String, Vec<i64>> = HashMap::new();
+    let mut changes = Vec::new();
+    for (id, stored) in users {
+        let canonical = match normalize_email(&stored) {
+            Ok(canonical) => canonical,
+            Err(_) => {
+                tracing::warn!("User {} has an invalid email, leaving it as is: {:?}", id, stored);
+                stored.clone()
+            }
+        };
+        if canonical != stored {
+            changes.push((id, canonical.clone()));
+        }
+        owners.entry(canonical).or_default().push(id);
+    }
+
+    let mut duplicates: Vec<String> = owners
+        .into_iter()
+        .filter(|(_, ids)| ids.len() > 1)
+        .map(|(email, ids)| format!("{} (users {:?})", email, ids))
+        .collect();
+    if !duplicates.is_empty() {
+        duplicates.sort();
+        return Err(AppError::InternalError(format!(
+            "Accounts share the same normalized email, merge them first: {}",
+            duplicates.join(", ")
+        )));
+    }
+
+    let mut tx = pool.begin().await?;
+    for (id, canonical) in &changes {
+        sqlx::query("UPDATE users SET email = ? WHERE id = ?")
+            .bind(canonical)
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+    }
+    tx.commit().await?;
+
+    if !changes.is_empty() {
+        tracing::info!("Normalized the email of {} users", changes.len());
+    }
+
+    Ok(changes.len() as u64)
+}
@@ -1,21 +1,35 @@
 use axum::{
-    http::StatusCode,
+    http::{header, StatusCode},
     response::{IntoResponse, Response},
     Json,
 };
 use serde_json::json;
 use thiserror::Error;
 
+use crate::password_policy::PasswordViolation;
+
 #[derive(Error, Debug)]
 pub enum AppError {
     #[error("Authentication failed: {0}")]
     AuthError(String),
     #[error("Database error: {0}")]
     SqlxError(#[from] sqlx::Error),
+    #[error("Account disabled: {0}")]
+    AccountDisabled(String),
+    #[error("Email not verified: {0}")]
+    EmailNotVerified(String),
+    #[error("Account locked, retry after {0}s")]
+    AccountLocked(u64),
+    #[error("Too many requests, retry after {0}s")]
+    TooManyRequests(u64),
+    #[error("Forbidden: {0}")]
+    Forbidden(String),
     #[error("Resource not found: {0}")]
     NotFound(String),
     #[error("Invalid input: {0}")]
     ValidationError(String),
+    #[error("Password rejected by policy")]
+    InvalidPassword(Vec<PasswordViolation>),
     #[allow(dead_code)]
     #[error("Internal server error: {0}")]
     InternalError(String),
@@ -23,24 +37,51 @@ pub enum AppError {
 
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
+        // Segundos tras los que reintentar, para el header `Retry-After`
+        let mut retry_after = None;
+        // Reglas incumplidas de la política de contraseñas
+        let mut violations = None;
+
         let (status, error_message) = match self {
             AppError::AuthError(msg) => (StatusCode::UNAUTHORIZED, msg),
             AppError::SqlxError(e) => {
                 tracing::error!("Database error: {}", e);
                 (StatusCode::INTERNAL_SERVER_ERROR, "Database error".to_string())
             }
+            AppError::AccountDisabled(msg) => (StatusCode::FORBIDDEN, msg),
+            AppError::EmailNotVerified(msg) => (StatusCode::FORBIDDEN, msg),
+            AppError::AccountLocked(secs) => {
+                retry_after = Some(secs);
+                (StatusCode::LOCKED, "Account temporarily locked".to_string())
+            }
+            AppError::TooManyRequests(secs) => {
+                retry_after = Some(secs);
+                (StatusCode::TOO_MANY_REQUESTS, "Too many failed login attempts".to_string())
+            }
+            AppError::Forbidden(msg) => (StatusCode::FORBIDDEN, msg),
             AppError::NotFound(msg) => (StatusCode::NOT_FOUND, msg),
             AppError::ValidationError(msg) => (StatusCode::BAD_REQUEST, msg),
+            AppError::InvalidPassword(list) => {
+                violations = Some(list);
+                (StatusCode::BAD_REQUEST, "Password does not meet the password policy".to_string())
+            }
             AppError::InternalError(msg) => {
                 tracing::error!("Internal error: {}", msg);
                 (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error".to_string())
             }
         };
 
-        let body = Json(json!({
+        let mut body = json!({
             "error": error_message,
-        }));
+        });
+        if let Some(violations) = violations {
+            body["violations"] = json!(violations);
+        }
+        let body = Json(body);
 
-        (status, body).into_response()
+        match retry_after {
+            Some(secs) => (status, [(header::RETRY_AFTER, secs.to_string())], body).into_response(),
+            None => (status, body).into_response(),
+        }
     }
 }
@@ -0,0 +1,230 @@
+use chrono::{Duration, NaiveDateTime, Utc};
+use sqlx::SqlitePool;
+
+use crate::{
+    audit::AUDIT_COLUMNS,
+    error::AppError,
+    handlers::api_keys::API_KEY_COLUMNS,
+    mailer::Email,
+    mfa,
+    models::{ApiKey, AuditEvent, DataExport, ExportStatus, LinkedIdentity, Session, Task, User, UserExport},
+    sessions::SESSION_COLUMNS,
+    state::AppState,
+    tokens,
+};
+
+/// Días que se conserva una exportación generada en segundo plano.
+pub const EXPORT_TTL_DAYS: i64 = 7;
+/// Minutos tras los que una exportación que sigue pendiente se da por fallida.
+pub const EXPORT_JOB_TIMEOUT_MINUTES: i64 = 30;
+
+const EXPORT_COLUMNS: &str = "id, status, created_at, completed_at, expires_at";
+
+/// Reúne todos los datos del usuario.
+pub async fn build(pool: &SqlitePool, user: &User) -> Result<UserExport, AppError> {
+    let tasks = sqlx::query_as::<_, Task>("SELECT * FROM tasks WHERE owner_id = ? ORDER BY id")
+        .bind(user.id)
+        .fetch_all(pool)
+        .await?;
+
+    // Todas las sesiones, también las revocadas: guardan IP y user agent
+    let sessions = sqlx::query_as::<_, Session>(&format!(
+        "SELECT {} FROM sessions WHERE user_id = ? ORDER BY id",
+        SESSION_COLUMNS
+    ))
+    .bind(user.id)
+    .fetch_all(pool)
+    .await?;
+
+    let api_keys = sqlx::query_as::<_, ApiKey>(&format!(
+        "SELECT {} FROM api_keys WHERE user_id = ? ORDER BY id",
+        API_KEY_COLUMNS
+    ))
+    .bind(user.id)
+    .fetch_all(pool)
+    .await?;
+
+    let identities = sqlx::query_as::<_, LinkedIdentity>(
+        "SELECT provider, subject, email, created_at FROM user_identities WHERE user_id = ? ORDER BY id",
+    )
+    .bind(user.id)
+    .fetch_all(pool)
+    .await?;
+
+    let activity = sqlx::query_as::<_, AuditEvent>(&format!(
+        "SELECT {} FROM audit_events WHERE user_id = ? ORDER BY id",
+        AUDIT_COLUMNS
+    ))
+    .bind(user.id)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(UserExport {
+        exported_at: Utc::now().naive_utc(),
+        user: user.clone(),
+        mfa_enabled: mfa::is_enabled(pool, user.id).await?,
+        tasks,
+        sessions,
+        api_keys,
+        identities,
+        activity,
+    })
+}
+
+/// Las cuentas grandes se exportan en segundo plano.
+pub async fn needs_background_job(state: &AppState, user_id: i64) -> Result<bool, AppError> {
+    let tasks: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM tasks WHERE owner_id = ?")
+        .bind(user_id)
+        .fetch_one(&state.pool)
+        .await?;
+
+    Ok(tasks > state.config.export_inline_max_tasks)
+}
+
+fn with_download_url(mut export: DataExport) -> DataExport {
+    export.download_url = format!("/users/me/exports/{}", export.id);
+    export
+}
+
+/// Exportación vigente (no expirada) del usuario.
+pub async fn find(pool: &SqlitePool, user_id: i64, id: i64) -> Result<DataExport, AppError> {
+    let export = sqlx::query_as::<_, DataExport>(&format!(
+        "SELECT {} FROM data_exports WHERE id = ? AND user_id = ? AND expires_at > ?",
+        EXPORT_COLUMNS
+    ))
+    .bind(id)
+    .bind(user_id)
+    .bind(Utc::now().naive_utc())
+    .fetch_optional(pool)
+    .await?
+    .ok_or(AppError::NotFound("Export not found".to_string()))?;
+
+    Ok(with_download_url(export))
+}
+
+/// Exportación vigente a la que da acceso el token del enlace enviado por correo, junto
+/// con su dueño.
+pub async fn find_by_token(pool: &SqlitePool, id: i64, token: &str) -> Result<(i64, DataExport), AppError> {
+    let user_id: i64 = sqlx::query_scalar("SELECT user_id FROM data_exports WHERE id = ? AND download_token_hash = ?")
+        .bind(id)
+        .bind(tokens::hash_token(token))
+        .fetch_optional(pool)
+        .await?
+        .ok_or(AppError::NotFound("Export not found".to_string()))?;
+
+    Ok((user_id, find(pool, user_id, id).await?))
+}
+
+/// Marca como fallidas las exportaciones pendientes creadas antes de `created_before`.
+async fn fail_pending(pool: &SqlitePool, created_before: NaiveDateTime) -> Result<(), AppError> {
+    let failed = sqlx::query("UPDATE data_exports SET status = ?, completed_at = ? WHERE status = ? AND created_at < ?")
+        .bind(ExportStatus::Failed)
+        .bind(Utc::now().naive_utc())
+        .bind(ExportStatus::Pending)
+        .bind(created_before)
+        .execute(pool)
+        .await?
+        .rows_affected();
+
+    if failed > 0 {
+        tracing::warn!("Marked {} unfinished data exports as failed", failed);
+    }
+
+    Ok(())
+}
+
+/// Al arrancar no hay ninguna exportación en marcha: las pendientes se quedaron a medias
+/// cuando se paró el proceso anterior y no terminarán nunca.
+pub async fn fail_interrupted(pool: &SqlitePool) -> Result<(), AppError> {
+    fail_pending(pool, Utc::now().naive_utc()).await
+}
+
+/// Documento JSON de una exportación lista.
+pub async fn archive(pool: &SqlitePool, export_id: i64) -> Result<String, AppError> {
+    sqlx::query_scalar::<_, Option<String>>("SELECT archive FROM data_exports WHERE id = ?")
+        .bind(export_id)
+        .fetch_one(pool)
+        .await?
+        .ok_or(AppError::NotFound("Export not found".to_string()))
+}
+
+/// Encola una exportación y la genera en segundo plano. Si el usuario ya tiene una en
+/// curso (y no ha superado `EXPORT_JOB_TIMEOUT_MINUTES`) se devuelve esa en lugar de
+/// lanzar otra.
+pub async fn start(state: &AppState, user: &User) -> Result<DataExport, AppError> {
+    let pool = &state.pool;
+    let now = Utc::now().naive_utc();
+
+    sqlx::query("DELETE FROM data_exports WHERE expires_at <= ?")
+        .bind(now)
+        .execute(pool)
+        .await?;
+    fail_pending(pool, now - Duration::minutes(EXPORT_JOB_TIMEOUT_MINUTES)).await?;
+
+    let pending: Option<i64> = sqlx::query_scalar(
+        "SELECT id FROM data_exports WHERE user_id = ? AND status = ? ORDER BY id DESC LIMIT 1",
+    )
+    .bind(user.id)
+    .bind(ExportStatus::Pending)
+    .fetch_optional(pool)
+    .await?;
+
+    if let Some(id) = pending {
+        return find(pool, user.id, id).await;
+    }
+
+    // El correo lleva un enlace que no necesita sesión: da acceso solo a esta exportación
+    let token = tokens::generate_opaque_token();
+    let id = sqlx::query("INSERT INTO data_exports (user_id, expires_at, download_token_hash) VALUES (?, ?, ?)")
+        .bind(user.id)
+        .bind(now + Duration::days(EXPORT_TTL_DAYS))
+        .bind(tokens::hash_token(&token))
+        .execute(pool)
+        .await?
+        .last_insert_rowid();
+
+    tokio::spawn(run(state.clone(), id, user.clone(), token));
+
+    find(pool, user.id, id).await
+}
+
+async fn run(state: AppState, export_id: i64, user: User, token: String) {
+    let result = async {
+        let archive = serde_json::to_string(&build(&state.pool, &user).await?)
+            .map_err(|e| AppError::InternalError(format!("Cannot serialize export: {}", e)))?;
+
+        sqlx::query("UPDATE data_exports SET status = ?, archive = ?, completed_at = ? WHERE id = ?")
+            .bind(ExportStatus::Ready)
+            .bind(archive)
+            .bind(Utc::now().naive_utc())
+            .bind(export_id)
+            .execute(&state.pool)
+            .await?;
+
+        Ok::<_, AppError>(())
+    }
+    .await;
+
+    if let Err(e) = result {
+        tracing::error!("Data export {} of user {} failed: {}", export_id, user.id, e);
+        let _ = sqlx::query("UPDATE data_exports SET status = ?, completed_at = ? WHERE id = ?")
+            .bind(ExportStatus::Failed)
+            .bind(Utc::now().naive_utc())
+            .bind(export_id)
+            .execute(&state.pool)
+            .await;
+        return;
+    }
+
+    let email = Email {
+        to: user.email.clone(),
+        subject: "Your data export is ready".to_string(),
+        body: format!(
+            "Your data export is ready. Download it from this link (available for {} days):\n\n{}/exports/{}?token={}",
+            EXPORT_TTL_DAYS, state.config.base_url, export_id, token
+        ),
+    };
+    if let Err(e) = state.mailer.send(email).await {
+        tracing::warn!("Cannot notify user {} about export {}: {}", user.id, export_id, e);
+    }
+}
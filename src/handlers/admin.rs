@@ -0,0 +1,472 @@
+use axum::{
+    extract::{Path, Query, State},
+    Json,
+};
+use sqlx::SqlitePool;
+
+use crate::{
+    audit,
+    error::AppError,
+    login_attempts,
+    handlers::tasks::{self, page_bounds, Pagination, TaskQuery},
+    middleware::{Admin, ClientInfo, RequireRole},
+    models::{Actor, AuditAction, ImpersonateRequest, ImpersonationToken, Role, Task, UpdateRole, UpdateTask, User},
+    revocation::RevocationStore,
+    sessions,
+    state::AppState,
+    tokens,
+};
+
+/// Activa o desactiva la cuenta `id` y lo registra en la auditoría a nombre de `admin_id`.
+async fn set_user_active(
+    pool: &SqlitePool,
+    client: &ClientInfo,
+    admin_id: i64,
+    id: i64,
+    active: bool,
+) -> Result<User, AppError> {
+    let mut tx = pool.begin().await?;
+    let result = sqlx::query("UPDATE users SET is_active = ? WHERE id = ?")
+        .bind(active)
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(AppError::NotFound("User not found".to_string()));
+    }
+
+    // Al desactivar se cierran también todas las sesiones abiertas
+    if !active {
+        RevocationStore::revoke_all_in(&mut tx, id).await?;
+    }
+
+    let action = if active { AuditAction::UserReactivated } else { AuditAction::UserDeactivated };
+    audit::record(&mut *tx, client, action, Some(id), Some(admin_id), serde_json::json!({})).await?;
+
+    let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = ?")
+        .bind(id)
+        .fetch_one(&mut *tx)
+        .await?;
+    tx.commit().await?;
+
+    Ok(user)
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/users/{id}/deactivate",
+    params(
+        ("id" = i64, Path, description = "User ID")
+    ),
+    responses(
+        (status = 200, description = "User deactivated", body = User),
+        (status = 404, description = "User not found"),
+        (status = 403, description = "Admin role required"),
+        (status = 401, description = "Unauthorized")
+    ),
+    security(
+        ("bearer" = [])
+    )
+)]
+pub async fn deactivate_user(
+    State(state): State<AppState>,
+    RequireRole(admin, _): RequireRole<Admin>,
+    client: ClientInfo,
+    Path(id): Path<i64>,
+) -> Result<Json<User>, AppError> {
+    if admin.id == id {
+        return Err(AppError::ValidationError(
+            "Admins cannot deactivate their own account here".to_string(),
+        ));
+    }
+
+    let user = set_user_active(&state.pool, &client, admin.id, id, false).await?;
+    tracing::info!("Admin {} deactivated user {}", admin.id, id);
+
+    Ok(Json(user))
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/users/{id}/reactivate",
+    params(
+        ("id" = i64, Path, description = "User ID")
+    ),
+    responses(
+        (status = 200, description = "User reactivated", body = User),
+        (status = 404, description = "User not found"),
+        (status = 403, description = "Admin role required"),
+        (status = 401, description = "Unauthorized")
+    ),
+    security(
+        ("bearer" = [])
+    )
+)]
+pub async fn reactivate_user(
+    State(state): State<AppState>,
+    RequireRole(admin, _): RequireRole<Admin>,
+    client: ClientInfo,
+    Path(id): Path<i64>,
+) -> Result<Json<User>, AppError> {
+    let user = set_user_active(&state.pool, &client, admin.id, id, true).await?;
+    tracing::info!("Admin {} reactivated user {}", admin.id, id);
+
+    Ok(Json(user))
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/users/{id}/unlock",
+    params(
+        ("id" = i64, Path, description = "User ID")
+    ),
+    responses(
+        (status = 200, description = "Login lockout cleared", body = User),
+        (status = 404, description = "User not found"),
+        (status = 403, description = "Admin role required"),
+        (status = 401, description = "Unauthorized")
+    ),
+    security(
+        ("bearer" = [])
+    )
+)]
+pub async fn unlock_user(
+    State(state): State<AppState>,
+    RequireRole(admin, _): RequireRole<Admin>,
+    client: ClientInfo,
+    Path(id): Path<i64>,
+) -> Result<Json<User>, AppError> {
+    let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = ?")
+        .bind(id)
+        .fetch_optional(&state.pool)
+        .await?
+        .ok_or(AppError::NotFound("User not found".to_string()))?;
+
+    // El almacén de intentos puede no estar en la DB: el evento se guarda antes,
+    // así no hay desbloqueo sin su registro de auditoría
+    audit::record(
+        &state.pool,
+        &client,
+        AuditAction::UserUnlocked,
+        Some(id),
+        Some(admin.id),
+        serde_json::json!({}),
+    )
+    .await?;
+    login_attempts::unlock(state.login_attempts.as_ref(), &user.email).await?;
+    tracing::info!("Admin {} unlocked user {}", admin.id, id);
+
+    Ok(Json(user))
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/users/{id}/impersonate",
+    params(
+        ("id" = i64, Path, description = "User ID")
+    ),
+    request_body = ImpersonateRequest,
+    responses(
+        (status = 200, description = "Short-lived token acting as the user", body = ImpersonationToken),
+        (status = 400, description = "Missing reason"),
+        (status = 404, description = "User not found"),
+        (status = 403, description = "Admin role required, or the target is an admin or deactivated"),
+        (status = 401, description = "Unauthorized")
+    ),
+    security(
+        ("bearer" = [])
+    )
+)]
+pub async fn impersonate_user(
+    State(state): State<AppState>,
+    RequireRole(admin, _): RequireRole<Admin>,
+    client: ClientInfo,
+    Path(id): Path<i64>,
+    Json(payload): Json<ImpersonateRequest>,
+) -> Result<Json<ImpersonationToken>, AppError> {
+    let reason = payload.reason.trim();
+    if reason.is_empty() {
+        return Err(AppError::ValidationError("A reason is required to impersonate a user".to_string()));
+    }
+
+    let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = ?")
+        .bind(id)
+        .fetch_optional(&state.pool)
+        .await?
+        .ok_or(AppError::NotFound("User not found".to_string()))?;
+
+    // Suplantar a otro admin daría acceso a sus permisos
+    if user.role == Role::Admin {
+        return Err(AppError::Forbidden("Administrators cannot be impersonated".to_string()));
+    }
+    if !user.is_active {
+        return Err(AppError::Forbidden("Cannot impersonate a deactivated account".to_string()));
+    }
+
+    // Sesión propia del usuario, visible en sus sesiones y revocable
+    let (session_id, _) = sessions::create(&state.pool, user.id, &client).await?;
+    let actor = Actor {
+        sub: admin.id.to_string(),
+        allow_destructive: payload.allow_destructive,
+    };
+    let access_token = tokens::create_impersonation_token(&state.keys, &user, session_id, actor)?;
+
+    tracing::warn!(
+        "Admin {} started impersonating user {} (session {}, destructive: {}): {}",
+        admin.id,
+        user.id,
+        session_id,
+        payload.allow_destructive,
+        reason
+    );
+    // Sin evento de auditoría no se entrega el token, y la sesión creada se cierra
+    if let Err(e) = audit::record(
+        &state.pool,
+        &client,
+        AuditAction::ImpersonationStarted,
+        Some(user.id),
+        Some(admin.id),
+        serde_json::json!({
+            "reason": reason,
+            "allow_destructive": payload.allow_destructive,
+            "session_id": session_id,
+        }),
+    )
+    .await
+    {
+        sessions::revoke(&state.pool, user.id, session_id).await?;
+        return Err(e);
+    }
+
+    Ok(Json(ImpersonationToken {
+        access_token,
+        token_type: "bearer".to_string(),
+        expires_in: tokens::IMPERSONATION_TOKEN_TTL_SECS,
+        user,
+    }))
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/users",
+    params(Pagination),
+    responses(
+        (status = 200, description = "List users", body = Vec<User>),
+        (status = 403, description = "Admin role required"),
+        (status = 401, description = "Unauthorized")
+    ),
+    security(
+        ("bearer" = [])
+    )
+)]
+pub async fn list_users(
+    State(pool): State<SqlitePool>,
+    RequireRole(_admin, _): RequireRole<Admin>,
+    Query(params): Query<Pagination>,
+) -> Result<Json<Vec<User>>, AppError> {
+    let (skip, limit) = page_bounds(params.skip, params.limit);
+
+    let users = sqlx::query_as::<_, User>("SELECT * FROM users ORDER BY id LIMIT ? OFFSET ?")
+        .bind(limit)
+        .bind(skip)
+        .fetch_all(&pool)
+        .await?;
+
+    Ok(Json(users))
+}
+
+#[utoipa::path(
+    put,
+    path = "/admin/users/{id}/role",
+    params(
+        ("id" = i64, Path, description = "User ID")
+    ),
+    request_body = UpdateRole,
+    responses(
+        (status = 200, description = "Role updated", body = User),
+        (status = 404, description = "User not found"),
+        (status = 403, description = "Admin role required"),
+        (status = 401, description = "Unauthorized")
+    ),
+    security(
+        ("bearer" = [])
+    )
+)]
+pub async fn set_user_role(
+    State(pool): State<SqlitePool>,
+    RequireRole(admin, _): RequireRole<Admin>,
+    client: ClientInfo,
+    Path(id): Path<i64>,
+    Json(payload): Json<UpdateRole>,
+) -> Result<Json<User>, AppError> {
+    if admin.id == id {
+        return Err(AppError::ValidationError(
+            "Admins cannot change their own role".to_string(),
+        ));
+    }
+
+    let mut tx = pool.begin().await?;
+    let result = sqlx::query("UPDATE users SET role = ? WHERE id = ?")
+        .bind(payload.role)
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(AppError::NotFound("User not found".to_string()));
+    }
+
+    audit::record(
+        &mut *tx,
+        &client,
+        AuditAction::RoleChanged,
+        Some(id),
+        Some(admin.id),
+        serde_json::json!({ "role": payload.role }),
+    )
+    .await?;
+
+    let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = ?")
+        .bind(id)
+        .fetch_one(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    tracing::info!("Admin {} set role of user {} to {:?}", admin.id, id, payload.role);
+
+    Ok(Json(user))
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/users/{id}/tasks",
+    params(
+        ("id" = i64, Path, description = "User ID"),
+        TaskQuery
+    ),
+    responses(
+        (status = 200, description = "List the user's tasks", body = Vec<Task>),
+        (status = 403, description = "Admin role required"),
+        (status = 401, description = "Unauthorized")
+    ),
+    security(
+        ("bearer" = [])
+    )
+)]
+pub async fn list_user_tasks(
+    State(pool): State<SqlitePool>,
+    RequireRole(_admin, _): RequireRole<Admin>,
+    Path(id): Path<i64>,
+    Query(params): Query<TaskQuery>,
+) -> Result<Json<Vec<Task>>, AppError> {
+    let tasks = tasks::list_tasks(&pool, id, &params).await?;
+
+    Ok(Json(tasks))
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/tasks/{id}",
+    params(
+        ("id" = i64, Path, description = "Task ID")
+    ),
+    responses(
+        (status = 200, description = "Get any task", body = Task),
+        (status = 404, description = "Task not found"),
+        (status = 403, description = "Admin role required"),
+        (status = 401, description = "Unauthorized")
+    ),
+    security(
+        ("bearer" = [])
+    )
+)]
+pub async fn get_task(
+    State(pool): State<SqlitePool>,
+    RequireRole(_admin, _): RequireRole<Admin>,
+    Path(id): Path<i64>,
+) -> Result<Json<Task>, AppError> {
+    let task = tasks::find_task(&pool, id, None).await?;
+
+    Ok(Json(task))
+}
+
+#[utoipa::path(
+    put,
+    path = "/admin/tasks/{id}",
+    params(
+        ("id" = i64, Path, description = "Task ID")
+    ),
+    request_body = UpdateTask,
+    responses(
+        (status = 200, description = "Task updated", body = Task),
+        (status = 404, description = "Task not found"),
+        (status = 403, description = "Admin role required"),
+        (status = 401, description = "Unauthorized")
+    ),
+    security(
+        ("bearer" = [])
+    )
+)]
+pub async fn update_task(
+    State(pool): State<SqlitePool>,
+    RequireRole(admin, _): RequireRole<Admin>,
+    client: ClientInfo,
+    Path(id): Path<i64>,
+    Json(payload): Json<UpdateTask>,
+) -> Result<Json<Task>, AppError> {
+    let mut tx = pool.begin().await?;
+    let task = tasks::apply_update(&mut tx, id, None, &payload).await?;
+    audit::record(
+        &mut *tx,
+        &client,
+        AuditAction::TaskUpdatedByAdmin,
+        Some(task.owner_id),
+        Some(admin.id),
+        serde_json::json!({ "task_id": id }),
+    )
+    .await?;
+    tx.commit().await?;
+    tracing::info!("Admin {} updated task {}", admin.id, id);
+
+    Ok(Json(task))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/admin/tasks/{id}",
+    params(
+        ("id" = i64, Path, description = "Task ID")
+    ),
+    responses(
+        (status = 200, description = "Task deleted"),
+        (status = 404, description = "Task not found"),
+        (status = 403, description = "Admin role required"),
+        (status = 401, description = "Unauthorized")
+    ),
+    security(
+        ("bearer" = [])
+    )
+)]
+pub async fn delete_task(
+    State(pool): State<SqlitePool>,
+    RequireRole(admin, _): RequireRole<Admin>,
+    client: ClientInfo,
+    Path(id): Path<i64>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    let mut tx = pool.begin().await?;
+    let task = tasks::find_task(&mut *tx, id, None).await?;
+    tasks::remove_task(&mut tx, id, None).await?;
+    audit::record(
+        &mut *tx,
+        &client,
+        AuditAction::TaskDeletedByAdmin,
+        Some(task.owner_id),
+        Some(admin.id),
+        serde_json::json!({ "task_id": id, "title": task.title }),
+    )
+    .await?;
+    tx.commit().await?;
+    tracing::info!("Admin {} deleted task {}", admin.id, id);
+
+    Ok(Json(serde_json::json!({ "ok": true })))
+}
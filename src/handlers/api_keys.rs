@@ -0,0 +1,221 @@
+use axum::{
+    extract::{Path, State},
+    Json,
+};
+use chrono::{Duration, Utc};
+use sqlx::SqlitePool;
+
+use crate::{
+    api_keys, audit,
+    error::AppError,
+    middleware::{ClientInfo, CurrentUser},
+    models::{ApiKey, AuditAction, CreateApiKey, CreatedApiKey, UpdateApiKey},
+    tokens,
+};
+
+pub const MAX_KEY_LIFETIME_DAYS: i64 = 365;
+
+pub(crate) const API_KEY_COLUMNS: &str =
+    "id, name, prefix, scope, created_at, expires_at, last_used_at, revoked_at";
+
+fn validate_name(name: &str) -> Result<&str, AppError> {
+    let name = name.trim();
+    if name.is_empty() || name.chars().count() > 100 {
+        return Err(AppError::ValidationError(
+            "API key name must be between 1 and 100 characters".to_string(),
+        ));
+    }
+    Ok(name)
+}
+
+async fn find_key(pool: &SqlitePool, id: i64, user_id: i64) -> Result<ApiKey, AppError> {
+    let key = sqlx::query_as::<_, ApiKey>(&format!(
+        "SELECT {} FROM api_keys WHERE id = ? AND user_id = ?",
+        API_KEY_COLUMNS
+    ))
+    .bind(id)
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await?
+    .ok_or(AppError::NotFound("API key not found".to_string()))?;
+
+    Ok(key)
+}
+
+#[utoipa::path(
+    post,
+    path = "/users/me/api-keys",
+    request_body = CreateApiKey,
+    responses(
+        (status = 200, description = "API key created; the key is only shown once", body = CreatedApiKey),
+        (status = 400, description = "Invalid name or expiration"),
+        (status = 401, description = "Unauthorized")
+    ),
+    security(
+        ("bearer" = [])
+    )
+)]
+pub async fn create_api_key(
+    State(pool): State<SqlitePool>,
+    CurrentUser(user): CurrentUser,
+    client: ClientInfo,
+    Json(payload): Json<CreateApiKey>,
+) -> Result<Json<CreatedApiKey>, AppError> {
+    let name = validate_name(&payload.name)?;
+
+    let expires_at = match payload.expires_in_days {
+        Some(days) if (1..=MAX_KEY_LIFETIME_DAYS).contains(&days) => {
+            Some((Utc::now() + Duration::days(days)).naive_utc())
+        }
+        Some(_) => {
+            return Err(AppError::ValidationError(format!(
+                "expires_in_days must be between 1 and {}",
+                MAX_KEY_LIFETIME_DAYS
+            )))
+        }
+        None => None,
+    };
+
+    let (key, prefix) = api_keys::generate_key();
+
+    let mut tx = pool.begin().await?;
+    let id = sqlx::query(
+        "INSERT INTO api_keys (user_id, name, prefix, key_hash, scope, expires_at) VALUES (?, ?, ?, ?, ?, ?)",
+    )
+    .bind(user.id)
+    .bind(name)
+    .bind(&prefix)
+    .bind(tokens::hash_token(&key))
+    .bind(payload.scope)
+    .bind(expires_at)
+    .execute(&mut *tx)
+    .await?
+    .last_insert_rowid();
+
+    audit::record(
+        &mut *tx,
+        &client,
+        AuditAction::ApiKeyCreated,
+        Some(user.id),
+        Some(user.id),
+        serde_json::json!({ "api_key_id": id, "prefix": prefix, "scope": payload.scope }),
+    )
+    .await?;
+    tx.commit().await?;
+
+    let api_key = find_key(&pool, id, user.id).await?;
+
+    Ok(Json(CreatedApiKey { key, api_key }))
+}
+
+#[utoipa::path(
+    get,
+    path = "/users/me/api-keys",
+    responses(
+        (status = 200, description = "List the user's API keys", body = Vec<ApiKey>),
+        (status = 401, description = "Unauthorized")
+    ),
+    security(
+        ("bearer" = [])
+    )
+)]
+pub async fn list_api_keys(
+    State(pool): State<SqlitePool>,
+    CurrentUser(user): CurrentUser,
+) -> Result<Json<Vec<ApiKey>>, AppError> {
+    let keys = sqlx::query_as::<_, ApiKey>(&format!(
+        "SELECT {} FROM api_keys WHERE user_id = ? ORDER BY id",
+        API_KEY_COLUMNS
+    ))
+    .bind(user.id)
+    .fetch_all(&pool)
+    .await?;
+
+    Ok(Json(keys))
+}
+
+#[utoipa::path(
+    patch,
+    path = "/users/me/api-keys/{id}",
+    params(
+        ("id" = i64, Path, description = "API key ID")
+    ),
+    request_body = UpdateApiKey,
+    responses(
+        (status = 200, description = "API key renamed", body = ApiKey),
+        (status = 404, description = "API key not found"),
+        (status = 401, description = "Unauthorized")
+    ),
+    security(
+        ("bearer" = [])
+    )
+)]
+pub async fn rename_api_key(
+    State(pool): State<SqlitePool>,
+    CurrentUser(user): CurrentUser,
+    Path(id): Path<i64>,
+    Json(payload): Json<UpdateApiKey>,
+) -> Result<Json<ApiKey>, AppError> {
+    let name = validate_name(&payload.name)?;
+
+    sqlx::query("UPDATE api_keys SET name = ? WHERE id = ? AND user_id = ?")
+        .bind(name)
+        .bind(id)
+        .bind(user.id)
+        .execute(&pool)
+        .await?;
+
+    let key = find_key(&pool, id, user.id).await?;
+
+    Ok(Json(key))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/users/me/api-keys/{id}",
+    params(
+        ("id" = i64, Path, description = "API key ID")
+    ),
+    responses(
+        (status = 200, description = "API key revoked", body = ApiKey),
+        (status = 404, description = "API key not found"),
+        (status = 401, description = "Unauthorized")
+    ),
+    security(
+        ("bearer" = [])
+    )
+)]
+pub async fn revoke_api_key(
+    State(pool): State<SqlitePool>,
+    CurrentUser(user): CurrentUser,
+    client: ClientInfo,
+    Path(id): Path<i64>,
+) -> Result<Json<ApiKey>, AppError> {
+    let mut tx = pool.begin().await?;
+    let result = sqlx::query(
+        "UPDATE api_keys SET revoked_at = ? WHERE id = ? AND user_id = ? AND revoked_at IS NULL",
+    )
+    .bind(Utc::now().naive_utc())
+    .bind(id)
+    .bind(user.id)
+    .execute(&mut *tx)
+    .await?;
+
+    // Revocar una clave ya revocada no es un evento nuevo
+    if result.rows_affected() > 0 {
+        audit::record(
+            &mut *tx,
+            &client,
+            AuditAction::ApiKeyRevoked,
+            Some(user.id),
+            Some(user.id),
+            serde_json::json!({ "api_key_id": id }),
+        )
+        .await?;
+    }
+    tx.commit().await?;
+
+    let key = find_key(&pool, id, user.id).await?;
+
+    Ok(Json(key))
+}
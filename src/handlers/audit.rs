@@ -0,0 +1,58 @@
+use axum::{
+    extract::{Query, State},
+    Json,
+};
+use sqlx::SqlitePool;
+
+use crate::{
+    audit,
+    error::AppError,
+    handlers::tasks::Pagination,
+    middleware::{Admin, CurrentUser, RequireRole},
+    models::{AuditEvent, AuditQuery},
+};
+
+#[utoipa::path(
+    get,
+    path = "/users/me/activity",
+    params(Pagination),
+    responses(
+        (status = 200, description = "Security events of the user's account, newest first", body = Vec<AuditEvent>),
+        (status = 401, description = "Unauthorized")
+    ),
+    security(
+        ("bearer" = [])
+    )
+)]
+pub async fn list_my_activity(
+    State(pool): State<SqlitePool>,
+    CurrentUser(user): CurrentUser,
+    Query(params): Query<Pagination>,
+) -> Result<Json<Vec<AuditEvent>>, AppError> {
+    let events = audit::for_user(&pool, user.id, params.skip, params.limit).await?;
+
+    Ok(Json(events))
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/audit",
+    params(AuditQuery),
+    responses(
+        (status = 200, description = "Audit events matching the filters, newest first", body = Vec<AuditEvent>),
+        (status = 403, description = "Admin role required"),
+        (status = 401, description = "Unauthorized")
+    ),
+    security(
+        ("bearer" = [])
+    )
+)]
+pub async fn list_audit_events(
+    State(pool): State<SqlitePool>,
+    RequireRole(_admin, _): RequireRole<Admin>,
+    Query(query): Query<AuditQuery>,
+) -> Result<Json<Vec<AuditEvent>>, AppError> {
+    let events = audit::search(&pool, &query).await?;
+
+    Ok(Json(events))
+}
@@ -1,16 +1,34 @@
-use axum::{extract::State, Json};
-use argon2::{
-    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
-    Argon2,
+use axum::{
+    extract::State,
+    http::{header, HeaderMap},
+    response::{AppendHeaders, IntoResponse, Response},
+    Json,
 };
-use rand_core::OsRng;use jsonwebtoken::{encode, EncodingKey, Header};
 use sqlx::SqlitePool;
-use std::env;
-use std::time::{SystemTime, UNIX_EPOCH}; // Add SystemTime and UNIX_EPOCH
+use std::sync::Arc;
 
 use crate::{
+    audit,
+    config::{Config, UnverifiedAction},
+    cookies,
+    email,
     error::AppError,
-    models::{Claims, CreateUser, LoginRequest, Token, User},
+    handlers::users,
+    keys::JwtKeys,
+    login_attempts,
+    middleware::{require_verified, AuthContext, ClientInfo, CurrentUser},
+    mfa,
+    models::{
+        AuditAction, CookieSession, CreateUser, LoginResponse, LogoutRequest, MfaChallenge, MfaLoginRequest,
+        RefreshRequest, Role, Token, TokenRequest, User,
+    },
+    oauth::{JsonOrForm, OAuthError},
+    password,
+    password_policy,
+    revocation::RevocationStore,
+    sessions,
+    state::AppState,
+    tokens,
 };
 
 #[utoipa::path(
@@ -19,17 +37,22 @@ use crate::{
     request_body = CreateUser,
     responses(
         (status = 201, description = "User created successfully", body = User),
-        (status = 400, description = "Email already registered")
+        (status = 400, description = "Email already registered, or password rejected by the policy (see `violations`)")
     )
 )]
 pub async fn register(
-    State(pool): State<SqlitePool>,
+    State(state): State<AppState>,
+    client: ClientInfo,
     Json(payload): Json<CreateUser>,
 ) -> Result<Json<User>, AppError> {
+    let pool = &state.pool;
+    let email = email::normalize_email(&payload.email)?;
+    password_policy::validate(&state.config.password_policy, &payload.password, &email).await?;
+
     // 1. Verificar si el usuario ya existe
     let user_exists = sqlx::query("SELECT 1 FROM users WHERE email = ?")
-        .bind(&payload.email)
-        .fetch_optional(&pool)
+        .bind(&email)
+        .fetch_optional(pool)
         .await?;
 
     if user_exists.is_some() {
@@ -37,86 +60,445 @@ pub async fn register(
     }
 
     // 2. Hash de contraseña
-    let salt = SaltString::generate(&mut OsRng);
-    let argon2 = Argon2::default();
-    let password_hash = argon2
-        .hash_password(payload.password.as_bytes(), &salt)
-        .map_err(|e| AppError::ValidationError(e.to_string()))?
-        .to_string();
-
-    // 3. Insertar usuario
+    let password_hash = password::hash_password(&state.config.argon2, &payload.password)?;
+
+    // 3. Insertar usuario (sin verificar) junto con su evento de auditoría
+    let mut tx = pool.begin().await?;
     let id = sqlx::query("INSERT INTO users (email, hashed_password) VALUES (?, ?)")
-        .bind(&payload.email)
+        .bind(&email)
         .bind(&password_hash)
-        .execute(&pool)
+        .execute(&mut *tx)
         .await?
         .last_insert_rowid();
+    audit::record(
+        &mut *tx,
+        &client,
+        AuditAction::UserRegistered,
+        Some(id),
+        Some(id),
+        serde_json::json!({ "email": email }),
+    )
+    .await?;
+    tx.commit().await?;
+
+    // 4. Enviar el enlace de verificación. La cuenta ya existe: si el correo falla se
+    // responde igualmente y el enlace puede pedirse de nuevo desde /users/me/verify/resend
+    if let Err(e) = users::send_verification_email(&state, id, &email).await {
+        tracing::warn!("Cannot send verification email to user {}: {}", id, e);
+    }
 
-    // 4. Retornar usuario creado
+    // 5. Retornar usuario creado
     Ok(Json(User {
         id,
-        email: payload.email,
+        email,
         hashed_password: "".to_string(), // No retornar hash
         is_active: true,
+        role: Role::User,
+        email_verified_at: None,
+        tokens_valid_after: None,
+        display_name: None,
+        timezone: None,
+        locale: None,
+        avatar_url: None,
     }))
 }
 
 #[utoipa::path(
     post,
     path = "/token",
-    request_body = LoginRequest,
+    request_body(
+        content = TokenRequest,
+        content_type = "application/x-www-form-urlencoded",
+        description = "OAuth2 password or refresh_token grant. A JSON body with the same fields is also accepted."
+    ),
     responses(
-        (status = 200, description = "Login successful", body = Token),
-        (status = 401, description = "Invalid credentials")
+        (status = 200, description = "Login successful, or an MFA challenge if the account has a second factor. With `cookie=true` the tokens are set as HttpOnly cookies and the body is a CookieSession", body = LoginResponse),
+        (status = 400, description = "OAuth2 error: invalid_grant, invalid_request or unsupported_grant_type"),
+        (status = 423, description = "Account temporarily locked after repeated failures (see Retry-After)"),
+        (status = 429, description = "Too many failed attempts from this IP (see Retry-After)")
     )
 )]
 pub async fn login(
-    State(pool): State<SqlitePool>,
-    // Usamos Json<LoginRequest> en lugar de FormUrlEncoded para simplificar, 
-    // aunque FastAPI usa FormUrlEncoded por defecto para OAuth2.
-    // El usuario pidió "lo mismo", pero LoginRequest es más común en APIs JSON modernas.
-    // Si falla la integración con frontend, cambiar a Form.
-    Json(payload): Json<LoginRequest>, 
-) -> Result<Json<Token>, AppError> {
-    
-    // 1. Buscar usuario
+    State(state): State<AppState>,
+    client: ClientInfo,
+    headers: HeaderMap,
+    // Negocia por Content-Type: form-urlencoded como los clientes OAuth2 (y el
+    // OAuth2PasswordRequestForm de FastAPI) o JSON como el resto de la API.
+    JsonOrForm(payload): JsonOrForm<TokenRequest>,
+) -> Result<impl IntoResponse, OAuthError> {
+    let mut use_cookie = payload.cookie;
+
+    let response = match payload.grant_type.as_deref().unwrap_or("password") {
+        "password" => {
+            let (Some(username), Some(password)) = (payload.username, payload.password) else {
+                return Err(OAuthError::invalid_request("Missing username or password"));
+            };
+            password_grant(&state, &client, &username, &password).await?
+        }
+        "refresh_token" => {
+            // Los navegadores renuevan con la cookie, que solo se envía a `/token`
+            let refresh_token = match payload.refresh_token {
+                Some(refresh_token) => refresh_token,
+                None => {
+                    use_cookie = true;
+                    cookies::get(&headers, cookies::REFRESH_COOKIE)
+                        .ok_or_else(|| OAuthError::invalid_request("Missing refresh_token"))?
+                        .to_string()
+                }
+            };
+            LoginResponse::Token(
+                tokens::rotate_refresh_token(&state.pool, &state.keys, &client, &refresh_token).await?,
+            )
+        }
+        other => return Err(OAuthError::unsupported_grant_type(other)),
+    };
+
+    let response = match response {
+        LoginResponse::Token(token) if use_cookie => cookie_response(&state.config, &state.keys, token)?,
+        response => Json(response).into_response(),
+    };
+
+    // RFC 6749 §5.1: las respuestas con tokens no deben cachearse
+    Ok((
+        [(header::CACHE_CONTROL, "no-store"), (header::PRAGMA, "no-cache")],
+        response,
+    ))
+}
+
+/// Entrega un par de tokens en cookies HttpOnly; el cuerpo solo lleva el token CSRF.
+pub(crate) fn cookie_response(config: &Config, keys: &JwtKeys, mut token: Token) -> Result<Response, AppError> {
+    let csrf_token = tokens::bind_csrf(keys, &mut token)?;
+
+    Ok((
+        AppendHeaders(cookies::session_cookies(config, &token, &csrf_token)),
+        Json(CookieSession {
+            token_type: "cookie".to_string(),
+            expires_in: token.expires_in,
+            csrf_token,
+        }),
+    )
+        .into_response())
+}
+
+async fn password_grant(
+    state: &AppState,
+    client: &ClientInfo,
+    username: &str,
+    password: &str,
+) -> Result<LoginResponse, AppError> {
+    let pool = &state.pool;
+    let attempts = state.login_attempts.as_ref();
+    let ip = client.ip;
+    let username = &email::lookup_key(username);
+
+    // 0. Rechazar cuentas o IPs bloqueadas por demasiados fallos
+    login_attempts::check(attempts, username, ip).await?;
+
+    // 1. Buscar usuario ('username' es el email)
     let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE email = ?")
-        .bind(&payload.username) // LoginRequest usa 'username' para el email
+        .bind(username)
+        .fetch_optional(pool)
+        .await?;
+
+    // 2. Verificar password (un email desconocido cuenta como fallo igual que un password erróneo)
+    let user = match user {
+        Some(user) if password::verify_password(password, &user.hashed_password).is_ok() => user,
+        user => {
+            login_attempts::record_failure(attempts, &state.config.login_lockout, username, ip).await?;
+            audit::record_applied(
+                pool,
+                client,
+                AuditAction::LoginFailed,
+                user.map(|u| u.id),
+                None,
+                serde_json::json!({ "method": "password", "email": username }),
+            )
+            .await;
+            return Err(AppError::AuthError("Invalid credentials".to_string()));
+        }
+    };
+    login_attempts::record_success(attempts, username).await?;
+
+    // Hashes con parámetros antiguos (o bcrypt heredado) se rehacen ahora que
+    // conocemos el password
+    if password::needs_rehash(&state.config.argon2, &user.hashed_password) {
+        upgrade_password_hash(state, &user, password).await;
+    }
+
+    complete_login(state, &user, client, "password").await
+}
+
+/// Un fallo al rehacer el hash no debe impedir el login: se reintenta en el siguiente.
+async fn upgrade_password_hash(state: &AppState, user: &User, password: &str) {
+    let result = async {
+        let new_hash = password::hash_password(&state.config.argon2, password)?;
+
+        // Solo si nadie cambió el password entretanto
+        sqlx::query("UPDATE users SET hashed_password = ? WHERE id = ? AND hashed_password = ?")
+            .bind(&new_hash)
+            .bind(user.id)
+            .bind(&user.hashed_password)
+            .execute(&state.pool)
+            .await?;
+
+        Ok::<_, AppError>(())
+    }
+    .await;
+
+    match result {
+        Ok(()) => tracing::info!("Upgraded password hash of user {}", user.id),
+        Err(e) => tracing::warn!("Cannot upgrade password hash of user {}: {}", user.id, e),
+    }
+}
+
+/// Resto del login una vez probado el primer factor (`method`: password, magic link u OIDC).
+pub(crate) async fn complete_login(
+    state: &AppState,
+    user: &User,
+    client: &ClientInfo,
+    method: &str,
+) -> Result<LoginResponse, AppError> {
+    let pool = &state.pool;
+
+    // Las cuentas desactivadas no pueden iniciar sesión (se comprueba tras el primer factor
+    // para no revelar el estado de cuentas ajenas)
+    if !user.is_active {
+        return Err(AppError::AccountDisabled("Account is deactivated".to_string()));
+    }
+
+    require_verified(&state.config, user, UnverifiedAction::Login)?;
+
+    // 3. Con segundo factor activo, devolver un challenge en lugar del token
+    if mfa::is_enabled(pool, user.id).await? {
+        return Ok(LoginResponse::MfaChallenge(MfaChallenge {
+            mfa_required: true,
+            mfa_token: tokens::create_mfa_token(&state.keys, user)?,
+            token_type: "mfa".to_string(),
+        }));
+    }
+
+    // 4. Abrir la sesión: access token (JWT) + refresh token
+    let token = tokens::issue_token_pair(pool, &state.keys, user, client).await?;
+    audit::record_applied(
+        pool,
+        client,
+        AuditAction::LoginSucceeded,
+        Some(user.id),
+        Some(user.id),
+        serde_json::json!({ "method": method }),
+    )
+    .await;
+
+    Ok(LoginResponse::Token(token))
+}
+
+#[utoipa::path(
+    post,
+    path = "/token/mfa",
+    request_body = MfaLoginRequest,
+    responses(
+        (status = 200, description = "Second factor accepted (a CookieSession with `cookie: true`)", body = Token),
+        (status = 401, description = "Invalid MFA token or code")
+    )
+)]
+pub async fn login_mfa(
+    State(pool): State<SqlitePool>,
+    State(revocations): State<RevocationStore>,
+    State(keys): State<Arc<JwtKeys>>,
+    State(config): State<Arc<Config>>,
+    client: ClientInfo,
+    Json(payload): Json<MfaLoginRequest>,
+) -> Result<Response, AppError> {
+    let claims = tokens::decode_mfa_token(&keys, &payload.mfa_token)?;
+
+    if revocations.is_revoked(&claims.jti) {
+        return Err(AppError::AuthError("MFA token already used".to_string()));
+    }
+
+    let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = ?")
+        .bind(tokens::subject_user_id(&claims.sub)?)
         .fetch_optional(&pool)
         .await?
-        .ok_or(AppError::AuthError("Invalid credentials".to_string()))?;
-
-    // 2. Verificar password
-    let parsed_hash = PasswordHash::new(&user.hashed_password)
-        .map_err(|_| AppError::AuthError("Invalid password hash in DB".to_string()))?;
-    
-    Argon2::default()
-        .verify_password(payload.password.as_bytes(), &parsed_hash)
-        .map_err(|_| AppError::AuthError("Invalid credentials".to_string()))?;
-
-    // 3. Generar JWT
-    let secret = env::var("SECRET_KEY").unwrap_or_else(|_| "secret".to_string());
-    
-    // Convertir SystemTime o chrono::DateTime a u64 para 'exp' (epoch seconds)
-    let expiration = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .expect("Time went backwards")
-        .as_secs() as usize + 60 * 30; // 30 minutos
-
-    let claims = Claims {
-        sub: user.email,
-        exp: expiration,
+        .ok_or(AppError::AuthError("User not found".to_string()))?;
+
+    // El challenge es de un solo intento: acertado o no, queda consumido.
+    // Así un password filtrado no permite probar códigos indefinidamente.
+    revocations.revoke(&claims.jti, user.id, claims.exp).await?;
+
+    if !user.is_active {
+        return Err(AppError::AccountDisabled("Account is deactivated".to_string()));
+    }
+
+    if !mfa::verify_second_factor(&pool, user.id, &payload.code).await? {
+        audit::record_applied(
+            &pool,
+            &client,
+            AuditAction::LoginFailed,
+            Some(user.id),
+            None,
+            serde_json::json!({ "method": "mfa" }),
+        )
+        .await;
+        return Err(AppError::AuthError("Invalid MFA code".to_string()));
+    }
+
+    let token = tokens::issue_token_pair(&pool, &keys, &user, &client).await?;
+    audit::record_applied(
+        &pool,
+        &client,
+        AuditAction::LoginSucceeded,
+        Some(user.id),
+        Some(user.id),
+        serde_json::json!({ "method": "mfa" }),
+    )
+    .await;
+
+    if payload.cookie {
+        return cookie_response(&config, &keys, token);
+    }
+
+    Ok(Json(token).into_response())
+}
+
+#[utoipa::path(
+    post,
+    path = "/token/refresh",
+    request_body = RefreshRequest,
+    responses(
+        (status = 200, description = "Token pair rotated (a CookieSession when renewing from the cookie or with `cookie: true`)", body = Token),
+        (status = 400, description = "No refresh token in the body or the cookie"),
+        (status = 401, description = "Invalid, expired or reused refresh token")
+    )
+)]
+pub async fn refresh(
+    State(pool): State<SqlitePool>,
+    State(keys): State<Arc<JwtKeys>>,
+    State(config): State<Arc<Config>>,
+    client: ClientInfo,
+    headers: HeaderMap,
+    Json(payload): Json<RefreshRequest>,
+) -> Result<Response, AppError> {
+    // Igual que `grant_type=refresh_token` en /token: sin token en el body se usa la cookie
+    let (refresh_token, use_cookie) = match payload.refresh_token {
+        Some(refresh_token) => (refresh_token, payload.cookie),
+        None => {
+            let refresh_token = cookies::get(&headers, cookies::REFRESH_COOKIE)
+                .ok_or(AppError::ValidationError("Missing refresh_token".to_string()))?;
+            (refresh_token.to_string(), true)
+        }
     };
 
-    let token = encode(
-        &Header::default(),
-        &claims,
-        &EncodingKey::from_secret(secret.as_bytes()),
+    let token = tokens::rotate_refresh_token(&pool, &keys, &client, &refresh_token).await?;
+
+    if use_cookie {
+        return cookie_response(&config, &keys, token);
+    }
+
+    Ok(Json(token).into_response())
+}
+
+#[utoipa::path(
+    post,
+    path = "/logout",
+    request_body = LogoutRequest,
+    responses(
+        (status = 200, description = "Current token and session revoked; session cookies cleared"),
+        (status = 401, description = "Unauthorized")
+    ),
+    security(
+        ("bearer" = [])
     )
-    .map_err(|e| AppError::AuthError(format!("Token creation failed: {}", e)))?;
+)]
+pub async fn logout(
+    State(pool): State<SqlitePool>,
+    State(revocations): State<RevocationStore>,
+    State(config): State<Arc<Config>>,
+    auth: AuthContext,
+    client: ClientInfo,
+    payload: Option<Json<LogoutRequest>>,
+) -> Result<impl IntoResponse, AppError> {
+    let claims = auth.claims()?;
+    revocations
+        .revoke(&claims.jti, auth.user.id, claims.exp)
+        .await?;
 
-    Ok(Json(Token {
-        access_token: token,
-        token_type: "bearer".to_string(),
-    }))
+    // Con un token de suplantación quien cierra la sesión es el admin
+    let actor_id = match &claims.act {
+        Some(actor) => tokens::subject_user_id(&actor.sub)?,
+        None => auth.user.id,
+    };
+    let mut tx = pool.begin().await?;
+    sessions::revoke_in(&mut tx, auth.user.id, claims.sid).await?;
+    audit::record(
+        &mut *tx,
+        &client,
+        AuditAction::LoggedOut,
+        Some(auth.user.id),
+        Some(actor_id),
+        serde_json::json!({ "session_id": claims.sid }),
+    )
+    .await?;
+    tx.commit().await?;
+
+    // Un refresh token de otra sesión del mismo usuario también se invalida
+    if let Some(refresh_token) = payload.and_then(|Json(p)| p.refresh_token) {
+        tokens::revoke_refresh_token(&pool, auth.user.id, &refresh_token).await?;
+    }
+
+    Ok((
+        AppendHeaders(cookies::clear_session_cookies(&config)),
+        Json(serde_json::json!({ "ok": true })),
+    ))
+}
+
+#[utoipa::path(
+    post,
+    path = "/logout/all",
+    responses(
+        (status = 200, description = "Every token of the user revoked; session cookies cleared"),
+        (status = 401, description = "Unauthorized")
+    ),
+    security(
+        ("bearer" = [])
+    )
+)]
+pub async fn logout_all(
+    State(pool): State<SqlitePool>,
+    State(config): State<Arc<Config>>,
+    CurrentUser(user): CurrentUser,
+    client: ClientInfo,
+) -> Result<impl IntoResponse, AppError> {
+    let mut tx = pool.begin().await?;
+    RevocationStore::revoke_all_in(&mut tx, user.id).await?;
+    audit::record(
+        &mut *tx,
+        &client,
+        AuditAction::LoggedOutEverywhere,
+        Some(user.id),
+        Some(user.id),
+        serde_json::json!({}),
+    )
+    .await?;
+    tx.commit().await?;
+
+    Ok((
+        AppendHeaders(cookies::clear_session_cookies(&config)),
+        Json(serde_json::json!({ "ok": true })),
+    ))
+}
+
+#[utoipa::path(
+    get,
+    path = "/.well-known/jwks.json",
+    responses(
+        (status = 200, description = "Public keys (JWKS, RFC 7517) that verify the access tokens")
+    )
+)]
+pub async fn jwks(State(keys): State<Arc<JwtKeys>>) -> impl IntoResponse {
+    // Los servicios que verifican tokens pueden cachearlo; tras una rotación la clave
+    // nueva debe publicarse (como adicional) antes de empezar a firmar con ella
+    (
+        [(header::CACHE_CONTROL, "public, max-age=300")],
+        Json(keys.jwks()),
+    )
 }
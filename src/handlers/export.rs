@@ -0,0 +1,119 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+
+use crate::{
+    error::AppError,
+    export,
+    middleware::CurrentUser,
+    models::{DataExport, ExportDownloadParams, ExportStatus},
+    state::AppState,
+};
+
+/// Respuesta de descarga del documento exportado.
+fn attachment(user_id: i64, archive: String) -> Response {
+    (
+        [
+            (header::CONTENT_TYPE, "application/json".to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"user-{}-export.json\"", user_id),
+            ),
+            (header::CACHE_CONTROL, "no-store".to_string()),
+        ],
+        archive,
+    )
+        .into_response()
+}
+
+#[utoipa::path(
+    get,
+    path = "/users/me/export",
+    responses(
+        (status = 200, description = "Everything stored about the user, as a JSON download", body = crate::models::UserExport),
+        (status = 202, description = "Large account: the export is generated in the background", body = crate::models::DataExport),
+        (status = 401, description = "Unauthorized")
+    ),
+    security(
+        ("bearer" = [])
+    )
+)]
+pub async fn export_me(
+    State(state): State<AppState>,
+    CurrentUser(user): CurrentUser,
+) -> Result<Response, AppError> {
+    if export::needs_background_job(&state, user.id).await? {
+        let job = export::start(&state, &user).await?;
+        return Ok((StatusCode::ACCEPTED, Json(job)).into_response());
+    }
+
+    let archive = serde_json::to_string(&export::build(&state.pool, &user).await?)
+        .map_err(|e| AppError::InternalError(format!("Cannot serialize export: {}", e)))?;
+
+    Ok(attachment(user.id, archive))
+}
+
+#[utoipa::path(
+    get,
+    path = "/users/me/exports/{id}",
+    params(
+        ("id" = i64, Path, description = "Export ID")
+    ),
+    responses(
+        (status = 200, description = "The export document, once ready", body = crate::models::UserExport),
+        (status = 202, description = "Still being generated", body = crate::models::DataExport),
+        (status = 404, description = "Export not found or expired"),
+        (status = 500, description = "The export failed; request a new one"),
+        (status = 401, description = "Unauthorized")
+    ),
+    security(
+        ("bearer" = [])
+    )
+)]
+pub async fn download_export(
+    State(state): State<AppState>,
+    CurrentUser(user): CurrentUser,
+    Path(id): Path<i64>,
+) -> Result<Response, AppError> {
+    let job = export::find(&state.pool, user.id, id).await?;
+
+    job_response(&state, user.id, job).await
+}
+
+#[utoipa::path(
+    get,
+    path = "/exports/{id}",
+    params(
+        ("id" = i64, Path, description = "Export ID"),
+        ExportDownloadParams
+    ),
+    responses(
+        (status = 200, description = "The export document, once ready", body = crate::models::UserExport),
+        (status = 202, description = "Still being generated", body = crate::models::DataExport),
+        (status = 404, description = "Export not found, expired or wrong token"),
+        (status = 500, description = "The export failed; request a new one")
+    )
+)]
+pub async fn download_export_link(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    Query(params): Query<ExportDownloadParams>,
+) -> Result<Response, AppError> {
+    // Enlace del correo: el token sustituye a la sesión
+    let (user_id, job) = export::find_by_token(&state.pool, id, &params.token).await?;
+
+    job_response(&state, user_id, job).await
+}
+
+async fn job_response(state: &AppState, user_id: i64, job: DataExport) -> Result<Response, AppError> {
+    match job.status {
+        ExportStatus::Pending => Ok((StatusCode::ACCEPTED, Json(job)).into_response()),
+        ExportStatus::Failed => Err(AppError::InternalError(
+            "Export failed, request a new one".to_string(),
+        )),
+        ExportStatus::Ready => Ok(attachment(user_id, export::archive(&state.pool, job.id).await?)),
+    }
+}
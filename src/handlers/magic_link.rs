@@ -0,0 +1,197 @@
+use axum::{
+    extract::{Query, State},
+    http::{header, StatusCode},
+    response::{Html, IntoResponse, Response},
+    Json,
+};
+use chrono::{Duration, Utc};
+
+use crate::{
+    email,
+    error::AppError,
+    handlers::auth,
+    login_attempts,
+    mailer::Email,
+    middleware::ClientInfo,
+    models::{LoginResponse, MagicLinkExchange, MagicLinkParams, MagicLinkRequest, User},
+    oauth::JsonOrForm,
+    state::AppState,
+    tokens,
+};
+
+pub const MAGIC_LINK_TTL_MINUTES: i64 = 15;
+// Enlaces que se pueden pedir para un mismo email en la ventana
+const MAGIC_LINK_MAX_REQUESTS: i64 = 3;
+const MAGIC_LINK_WINDOW_SECS: i64 = 15 * 60;
+
+#[utoipa::path(
+    post,
+    path = "/login/magic-link",
+    request_body = MagicLinkRequest,
+    responses(
+        (status = 202, description = "If the account exists, a login link has been sent"),
+        (status = 429, description = "Too many links requested for this email (see Retry-After)")
+    )
+)]
+pub async fn request_magic_link(
+    State(state): State<AppState>,
+    Json(payload): Json<MagicLinkRequest>,
+) -> Result<(StatusCode, Json<serde_json::Value>), AppError> {
+    // El límite se aplica por email exista o no la cuenta, para no permitir enumerarlas
+    let email = email::lookup_key(&payload.email);
+    let key = format!("magic-link:{}", email);
+    login_attempts::rate_limit(
+        state.login_attempts.as_ref(),
+        &key,
+        MAGIC_LINK_MAX_REQUESTS,
+        MAGIC_LINK_WINDOW_SECS,
+    )
+    .await?;
+
+    let accepted = (
+        StatusCode::ACCEPTED,
+        Json(serde_json::json!({ "ok": true })),
+    );
+
+    let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE email = ?")
+        .bind(&email)
+        .fetch_optional(&state.pool)
+        .await?;
+
+    let Some(user) = user.filter(|u| u.is_active) else {
+        return Ok(accepted);
+    };
+
+    let token = tokens::generate_opaque_token();
+    let expires_at = (Utc::now() + Duration::minutes(MAGIC_LINK_TTL_MINUTES)).naive_utc();
+
+    sqlx::query("INSERT INTO magic_link_tokens (user_id, token_hash, expires_at) VALUES (?, ?, ?)")
+        .bind(user.id)
+        .bind(tokens::hash_token(&token))
+        .bind(expires_at)
+        .execute(&state.pool)
+        .await?;
+
+    state
+        .mailer
+        .send(Email {
+            to: user.email,
+            subject: "Your login link".to_string(),
+            body: format!(
+                "Open this link to sign in (valid for {} minutes, single use):\n\n{}/login/magic-link/exchange?token={}",
+                MAGIC_LINK_TTL_MINUTES, state.config.base_url, token
+            ),
+        })
+        .await?;
+
+    Ok(accepted)
+}
+
+#[utoipa::path(
+    post,
+    path = "/login/magic-link/exchange",
+    request_body(
+        content = MagicLinkExchange,
+        description = "JSON, or a form like the one served by the GET of this route"
+    ),
+    responses(
+        (status = 200, description = "Login successful, or an MFA challenge if the account has a second factor. With `cookie=true` the tokens are set as HttpOnly cookies and the body is a CookieSession", body = LoginResponse),
+        (status = 401, description = "Invalid, expired or already used link")
+    )
+)]
+pub async fn exchange_magic_link(
+    State(state): State<AppState>,
+    client: ClientInfo,
+    JsonOrForm(payload): JsonOrForm<MagicLinkExchange>,
+) -> Result<Response, AppError> {
+    let response = match exchange(&state, &client, &payload.token).await? {
+        LoginResponse::Token(token) if payload.cookie => auth::cookie_response(&state.config, &state.keys, token)?,
+        response => Json(response).into_response(),
+    };
+
+    Ok(([(header::CACHE_CONTROL, "no-store"), (header::PRAGMA, "no-cache")], response).into_response())
+}
+
+#[utoipa::path(
+    get,
+    path = "/login/magic-link/exchange",
+    params(MagicLinkParams),
+    responses(
+        (status = 200, description = "HTML page whose button POSTs the token to this route; opening the link does not use it up", content_type = "text/html"),
+        (status = 401, description = "Invalid, expired or already used link")
+    )
+)]
+pub async fn open_magic_link(
+    State(state): State<AppState>,
+    Query(params): Query<MagicLinkParams>,
+) -> Result<Response, AppError> {
+    // Los escáneres de correo y las precargas siguen los enlaces con GET: aquí solo se
+    // comprueba el token, y se canjea cuando el usuario pulsa el botón de la página.
+    let now = Utc::now().naive_utc();
+    let valid: Option<i64> = sqlx::query_scalar(
+        "SELECT id FROM magic_link_tokens WHERE token_hash = ? AND used_at IS NULL AND expires_at > ?",
+    )
+    .bind(tokens::hash_token(&params.token))
+    .bind(now)
+    .fetch_optional(&state.pool)
+    .await?;
+
+    if valid.is_none() {
+        return Err(AppError::AuthError("Invalid or expired login link".to_string()));
+    }
+
+    // El token coincide con uno emitido por nosotros (base64 URL-safe): no hace falta escaparlo
+    let page = format!(
+        r#"<!DOCTYPE html>
+<html>
+<head><meta charset="utf-8"><title>Sign in</title></head>
+<body>
+<form method="post" action="/login/magic-link/exchange">
+<input type="hidden" name="token" value="{}">
+<input type="hidden" name="cookie" value="true">
+<button type="submit">Sign in</button>
+</form>
+</body>
+</html>"#,
+        params.token
+    );
+
+    Ok((
+        [
+            (header::CACHE_CONTROL, "no-store"),
+            (header::REFERRER_POLICY, "no-referrer"),
+        ],
+        Html(page),
+    )
+        .into_response())
+}
+
+/// Consume el token del enlace y completa el login de su usuario.
+async fn exchange(state: &AppState, client: &ClientInfo, token: &str) -> Result<LoginResponse, AppError> {
+    let now = Utc::now().naive_utc();
+
+    // Consumir el token de forma atómica: solo una petición puede usarlo
+    let user_id: i64 = sqlx::query_scalar(
+        "UPDATE magic_link_tokens SET used_at = ?
+        WHERE token_hash = ? AND used_at IS NULL AND expires_at > ?
+        RETURNING user_id",
+    )
+    .bind(now)
+    .bind(tokens::hash_token(token))
+    .bind(now)
+    .fetch_optional(&state.pool)
+    .await?
+    .ok_or(AppError::AuthError("Invalid or expired login link".to_string()))?;
+
+    // Abrir el enlace prueba que el usuario controla el email
+    let user = sqlx::query_as::<_, User>(
+        "UPDATE users SET email_verified_at = COALESCE(email_verified_at, ?)
+        WHERE id = ? RETURNING *",
+    )
+    .bind(now)
+    .bind(user_id)
+    .fetch_one(&state.pool)
+    .await?;
+
+    auth::complete_login(state, &user, client, "magic_link").await
+}
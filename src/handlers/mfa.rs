@@ -0,0 +1,154 @@
+use axum::{extract::State, Json};
+use sqlx::SqlitePool;
+
+use crate::{
+    audit,
+    error::AppError,
+    mfa,
+    middleware::{ClientInfo, CurrentUser},
+    models::{AuditAction, RecoveryCodes, TotpCode, TotpEnrollment},
+};
+
+#[utoipa::path(
+    post,
+    path = "/users/me/mfa/totp",
+    responses(
+        (status = 200, description = "Pending TOTP secret created; confirm it with a code", body = TotpEnrollment),
+        (status = 400, description = "TOTP already enabled"),
+        (status = 401, description = "Unauthorized")
+    ),
+    security(
+        ("bearer" = [])
+    )
+)]
+pub async fn enroll_totp(
+    State(pool): State<SqlitePool>,
+    CurrentUser(user): CurrentUser,
+) -> Result<Json<TotpEnrollment>, AppError> {
+    if mfa::is_enabled(&pool, user.id).await? {
+        return Err(AppError::ValidationError("TOTP is already enabled".to_string()));
+    }
+
+    let (secret, otpauth_uri) = mfa::generate_secret(&user.email)?;
+
+    // Un nuevo enrolamiento sustituye al pendiente, si lo hubiera
+    sqlx::query(
+        "INSERT INTO user_totp (user_id, secret) VALUES (?, ?)
+        ON CONFLICT(user_id) DO UPDATE SET secret = excluded.secret, last_used_step = NULL",
+    )
+    .bind(user.id)
+    .bind(&secret)
+    .execute(&pool)
+    .await?;
+
+    Ok(Json(TotpEnrollment { secret, otpauth_uri }))
+}
+
+#[utoipa::path(
+    post,
+    path = "/users/me/mfa/totp/confirm",
+    request_body = TotpCode,
+    responses(
+        (status = 200, description = "TOTP enabled; recovery codes are shown only once", body = RecoveryCodes),
+        (status = 400, description = "No pending enrollment or invalid code"),
+        (status = 401, description = "Unauthorized")
+    ),
+    security(
+        ("bearer" = [])
+    )
+)]
+pub async fn confirm_totp(
+    State(pool): State<SqlitePool>,
+    CurrentUser(user): CurrentUser,
+    client: ClientInfo,
+    Json(payload): Json<TotpCode>,
+) -> Result<Json<RecoveryCodes>, AppError> {
+    let secret: String = sqlx::query_scalar(
+        "SELECT secret FROM user_totp WHERE user_id = ? AND confirmed_at IS NULL",
+    )
+    .bind(user.id)
+    .fetch_optional(&pool)
+    .await?
+    .ok_or(AppError::ValidationError("No pending TOTP enrollment".to_string()))?;
+
+    let step = mfa::verify_code(&secret, &payload.code, None)?
+        .ok_or(AppError::ValidationError("Invalid TOTP code".to_string()))?;
+
+    // Activación, códigos de recuperación y auditoría van juntos: nunca TOTP activo sin códigos
+    let mut tx = pool.begin().await?;
+    sqlx::query(
+        "UPDATE user_totp SET confirmed_at = CURRENT_TIMESTAMP, last_used_step = ? WHERE user_id = ?",
+    )
+    .bind(step)
+    .bind(user.id)
+    .execute(&mut *tx)
+    .await?;
+
+    let recovery_codes = mfa::regenerate_recovery_codes(&mut tx, user.id).await?;
+    audit::record(
+        &mut *tx,
+        &client,
+        AuditAction::MfaEnabled,
+        Some(user.id),
+        Some(user.id),
+        serde_json::json!({ "method": "totp" }),
+    )
+    .await?;
+    tx.commit().await?;
+
+    Ok(Json(RecoveryCodes { recovery_codes }))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/users/me/mfa/totp",
+    request_body = TotpCode,
+    responses(
+        (status = 200, description = "TOTP disabled"),
+        (status = 400, description = "TOTP not enabled or invalid code"),
+        (status = 401, description = "Unauthorized")
+    ),
+    security(
+        ("bearer" = [])
+    )
+)]
+pub async fn disable_totp(
+    State(pool): State<SqlitePool>,
+    CurrentUser(user): CurrentUser,
+    client: ClientInfo,
+    Json(payload): Json<TotpCode>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    if !mfa::is_enabled(&pool, user.id).await? {
+        return Err(AppError::ValidationError("TOTP is not enabled".to_string()));
+    }
+
+    if !mfa::verify_second_factor(&pool, user.id, &payload.code).await? {
+        return Err(AppError::ValidationError("Invalid TOTP code".to_string()));
+    }
+
+    let mut tx = pool.begin().await?;
+
+    sqlx::query("DELETE FROM user_totp WHERE user_id = ?")
+        .bind(user.id)
+        .execute(&mut *tx)
+        .await?;
+
+    sqlx::query("DELETE FROM mfa_recovery_codes WHERE user_id = ?")
+        .bind(user.id)
+        .execute(&mut *tx)
+        .await?;
+
+    audit::record(
+        &mut *tx,
+        &client,
+        AuditAction::MfaDisabled,
+        Some(user.id),
+        Some(user.id),
+        serde_json::json!({ "method": "totp" }),
+    )
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(Json(serde_json::json!({ "ok": true })))
+}
@@ -1,2 +1,12 @@
+pub mod admin;
+pub mod api_keys;
+pub mod audit;
 pub mod auth;
+pub mod export;
+pub mod magic_link;
+pub mod mfa;
+pub mod oidc;
+pub mod password;
+pub mod sessions;
 pub mod tasks;
+pub mod users;
@@ -0,0 +1,147 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::HeaderMap,
+    response::{AppendHeaders, IntoResponse, Redirect, Response},
+    Json,
+};
+
+use crate::{
+    config::{Config, OidcProviderConfig},
+    cookies,
+    error::AppError,
+    handlers::auth,
+    middleware::{ClientInfo, CurrentUser},
+    models::{LoginResponse, OidcAuthorizeParams, OidcCallbackParams},
+    oidc,
+    state::AppState,
+};
+
+fn provider<'a>(config: &'a Config, name: &str) -> Result<&'a OidcProviderConfig, AppError> {
+    config
+        .oidc_providers
+        .get(name)
+        .ok_or(AppError::NotFound("Unknown OIDC provider".to_string()))
+}
+
+fn redirect_uri(config: &Config, provider: &OidcProviderConfig) -> String {
+    format!("{}/auth/oidc/{}/callback", config.base_url, provider.name)
+}
+
+#[utoipa::path(
+    get,
+    path = "/auth/oidc/{provider}/authorize",
+    params(
+        ("provider" = String, Path, description = "Configured OIDC provider name"),
+        OidcAuthorizeParams
+    ),
+    responses(
+        (status = 303, description = "Redirect to the provider's authorization endpoint; sets the `oidc_state` cookie the callback requires"),
+        (status = 404, description = "Unknown provider")
+    )
+)]
+pub async fn oidc_authorize(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    Query(params): Query<OidcAuthorizeParams>,
+) -> Result<impl IntoResponse, AppError> {
+    let provider = provider(&state.config, &name)?;
+
+    let (url, oidc_state) = state
+        .oidc
+        .authorization_url(&state.pool, provider, &redirect_uri(&state.config, provider), None, params.cookie)
+        .await?;
+
+    Ok((
+        AppendHeaders([cookies::oidc_state_cookie(&state.config, &oidc_state)]),
+        Redirect::to(&url),
+    ))
+}
+
+#[utoipa::path(
+    post,
+    path = "/auth/oidc/{provider}/link",
+    params(
+        ("provider" = String, Path, description = "Configured OIDC provider name")
+    ),
+    responses(
+        (status = 200, description = "Provider URL to open in this same browser (it sets the `oidc_state` cookie); its callback links the identity to the current account"),
+        (status = 404, description = "Unknown provider"),
+        (status = 401, description = "Unauthorized")
+    ),
+    security(
+        ("bearer" = [])
+    )
+)]
+pub async fn oidc_link(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    CurrentUser(user): CurrentUser,
+) -> Result<impl IntoResponse, AppError> {
+    let provider = provider(&state.config, &name)?;
+
+    let (url, oidc_state) = state
+        .oidc
+        .authorization_url(&state.pool, provider, &redirect_uri(&state.config, provider), Some(user.id), false)
+        .await?;
+
+    Ok((
+        AppendHeaders([cookies::oidc_state_cookie(&state.config, &oidc_state)]),
+        Json(serde_json::json!({ "authorization_url": url })),
+    ))
+}
+
+#[utoipa::path(
+    get,
+    path = "/auth/oidc/{provider}/callback",
+    params(
+        ("provider" = String, Path, description = "Configured OIDC provider name"),
+        OidcCallbackParams
+    ),
+    responses(
+        (status = 200, description = "Login successful, or an MFA challenge if the account has a second factor. A CookieSession if the flow started with `cookie=true`", body = LoginResponse),
+        (status = 401, description = "Invalid state, code or ID token, or missing `oidc_state` cookie"),
+        (status = 403, description = "No linked account and sign-up disabled, or account deactivated")
+    )
+)]
+pub async fn oidc_callback(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    Query(params): Query<OidcCallbackParams>,
+    client: ClientInfo,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let provider = provider(&state.config, &name)?;
+
+    if let Some(error) = params.error {
+        return Err(AppError::AuthError(format!("OIDC provider returned an error: {}", error)));
+    }
+
+    let (Some(code), Some(oidc_state)) = (params.code, params.state) else {
+        return Err(AppError::ValidationError("Missing code or state".to_string()));
+    };
+    // Antes de consumir el state: un intento de CSRF no debe gastar el flujo legítimo
+    cookies::verify_oidc_state(&headers, &oidc_state)?;
+
+    let completed = state
+        .oidc
+        .complete(
+            &state.pool,
+            provider,
+            &redirect_uri(&state.config, provider),
+            &code,
+            &oidc_state,
+        )
+        .await?;
+
+    let user = match completed.link_user_id {
+        Some(user_id) => oidc::link_identity(&state.pool, &client, provider, &completed.claims, user_id).await?,
+        None => oidc::find_or_provision_user(&state.pool, &client, provider, &completed.claims).await?,
+    };
+
+    let response = match auth::complete_login(&state, &user, &client, "oidc").await? {
+        LoginResponse::Token(token) if completed.cookie => auth::cookie_response(&state.config, &state.keys, token)?,
+        response => Json(response).into_response(),
+    };
+
+    Ok((AppendHeaders([cookies::clear_oidc_state_cookie(&state.config)]), response).into_response())
+}
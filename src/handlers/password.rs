@@ -0,0 +1,162 @@
+use axum::{extract::State, http::StatusCode, Json};
+use chrono::{Duration, Utc};
+
+use crate::{
+    audit,
+    email,
+    error::AppError,
+    mailer::Email,
+    middleware::ClientInfo,
+    models::{AuditAction, ForgotPasswordRequest, ResetPasswordRequest, User},
+    password,
+    password_policy,
+    revocation::RevocationStore,
+    state::AppState,
+    tokens,
+};
+
+pub const RESET_TOKEN_TTL_MINUTES: i64 = 15;
+
+#[utoipa::path(
+    post,
+    path = "/password/forgot",
+    request_body = ForgotPasswordRequest,
+    responses(
+        (status = 202, description = "If the account exists, a reset link has been sent")
+    )
+)]
+pub async fn forgot_password(
+    State(state): State<AppState>,
+    Json(payload): Json<ForgotPasswordRequest>,
+) -> Result<(StatusCode, Json<serde_json::Value>), AppError> {
+    // La respuesta es la misma exista o no la cuenta, para no permitir enumerar emails
+    let accepted = (
+        StatusCode::ACCEPTED,
+        Json(serde_json::json!({ "ok": true })),
+    );
+
+    let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE email = ?")
+        .bind(email::lookup_key(&payload.email))
+        .fetch_optional(&state.pool)
+        .await?;
+
+    let Some(user) = user.filter(|u| u.is_active) else {
+        return Ok(accepted);
+    };
+
+    let now = Utc::now().naive_utc();
+    let token = tokens::generate_opaque_token();
+    let expires_at = now + Duration::minutes(RESET_TOKEN_TTL_MINUTES);
+
+    // Solo el último enlace solicitado es válido
+    sqlx::query("UPDATE password_reset_tokens SET used_at = ? WHERE user_id = ? AND used_at IS NULL")
+        .bind(now)
+        .bind(user.id)
+        .execute(&state.pool)
+        .await?;
+
+    sqlx::query(
+        "INSERT INTO password_reset_tokens (user_id, token_hash, expires_at) VALUES (?, ?, ?)",
+    )
+    .bind(user.id)
+    .bind(tokens::hash_token(&token))
+    .bind(expires_at)
+    .execute(&state.pool)
+    .await?;
+
+    // Elegir la contraseña necesita una página: sin frontend configurado solo se envía
+    // el token para usarlo contra `POST /password/reset`
+    let instructions = match &state.config.password_reset_url {
+        Some(url) => format!("Open this link to choose a new password:\n\n{}?token={}", url, token),
+        None => format!(
+            "Send it with your new password to POST {}/password/reset:\n\n{{\"token\": \"{}\", \"new_password\": \"...\"}}",
+            state.config.base_url, token
+        ),
+    };
+
+    state
+        .mailer
+        .send(Email {
+            to: user.email,
+            subject: "Reset your password".to_string(),
+            body: format!(
+                "Use this token to reset your password (valid for {} minutes):\n\n{}\n\n{}",
+                RESET_TOKEN_TTL_MINUTES, token, instructions
+            ),
+        })
+        .await?;
+
+    Ok(accepted)
+}
+
+#[utoipa::path(
+    post,
+    path = "/password/reset",
+    request_body = ResetPasswordRequest,
+    responses(
+        (status = 200, description = "Password changed and existing sessions revoked"),
+        (status = 400, description = "Invalid or expired reset token, or password rejected by the policy")
+    )
+)]
+pub async fn reset_password(
+    State(state): State<AppState>,
+    client: ClientInfo,
+    Json(payload): Json<ResetPasswordRequest>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    let now = Utc::now().naive_utc();
+    let token_hash = tokens::hash_token(&payload.token);
+    let invalid_token = || AppError::ValidationError("Invalid or expired reset token".to_string());
+
+    // La política necesita el email de la cuenta; el token aún no se consume, así que
+    // un password rechazado puede corregirse con el mismo enlace
+    let email: String = sqlx::query_scalar(
+        "SELECT users.email FROM password_reset_tokens
+        JOIN users ON users.id = password_reset_tokens.user_id
+        WHERE token_hash = ? AND used_at IS NULL AND expires_at > ?",
+    )
+    .bind(&token_hash)
+    .bind(now)
+    .fetch_optional(&state.pool)
+    .await?
+    .ok_or_else(invalid_token)?;
+
+    password_policy::validate(&state.config.password_policy, &payload.new_password, &email).await?;
+    let password_hash = password::hash_password(&state.config.argon2, &payload.new_password)?;
+
+    let mut tx = state.pool.begin().await?;
+
+    // Consumir el token de forma atómica: solo una petición puede usarlo
+    let user_id: i64 = sqlx::query_scalar(
+        "UPDATE password_reset_tokens SET used_at = ?
+        WHERE token_hash = ? AND used_at IS NULL AND expires_at > ?
+        RETURNING user_id",
+    )
+    .bind(now)
+    .bind(&token_hash)
+    .bind(now)
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or_else(invalid_token)?;
+
+    sqlx::query("UPDATE users SET hashed_password = ? WHERE id = ?")
+        .bind(&password_hash)
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+
+    // Cerrar todas las sesiones abiertas con la contraseña anterior
+    RevocationStore::revoke_all_in(&mut tx, user_id).await?;
+    audit::record(
+        &mut *tx,
+        &client,
+        AuditAction::PasswordReset,
+        Some(user_id),
+        Some(user_id),
+        serde_json::json!({}),
+    )
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(Json(serde_json::json!({ "ok": true })))
+}
@@ -0,0 +1,80 @@
+use axum::{
+    extract::{Path, State},
+    Json,
+};
+use sqlx::SqlitePool;
+
+use crate::{
+    audit,
+    error::AppError,
+    middleware::{AuthContext, ClientInfo},
+    models::{AuditAction, Session},
+    sessions,
+};
+
+#[utoipa::path(
+    get,
+    path = "/users/me/sessions",
+    responses(
+        (status = 200, description = "Active sessions of the user, most recently used first", body = Vec<Session>),
+        (status = 401, description = "Unauthorized")
+    ),
+    security(
+        ("bearer" = [])
+    )
+)]
+pub async fn list_sessions(
+    State(pool): State<SqlitePool>,
+    auth: AuthContext,
+) -> Result<Json<Vec<Session>>, AppError> {
+    let current = auth.claims()?.sid;
+
+    let mut sessions = sessions::list(&pool, auth.user.id).await?;
+    for session in &mut sessions {
+        session.current = session.id == current;
+    }
+
+    Ok(Json(sessions))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/users/me/sessions/{id}",
+    params(
+        ("id" = i64, Path, description = "Session ID")
+    ),
+    responses(
+        (status = 200, description = "Session revoked; its tokens stop working immediately"),
+        (status = 404, description = "Session not found"),
+        (status = 401, description = "Unauthorized")
+    ),
+    security(
+        ("bearer" = [])
+    )
+)]
+pub async fn revoke_session(
+    State(pool): State<SqlitePool>,
+    auth: AuthContext,
+    client: ClientInfo,
+    Path(id): Path<i64>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    auth.claims()?;
+
+    let mut tx = pool.begin().await?;
+    if !sessions::revoke_in(&mut tx, auth.user.id, id).await? {
+        return Err(AppError::NotFound("Session not found".to_string()));
+    }
+
+    audit::record(
+        &mut *tx,
+        &client,
+        AuditAction::SessionRevoked,
+        Some(auth.user.id),
+        Some(auth.user.id),
+        serde_json::json!({ "session_id": id }),
+    )
+    .await?;
+    tx.commit().await?;
+
+    Ok(Json(serde_json::json!({ "ok": true })))
+}
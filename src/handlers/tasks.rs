@@ -2,13 +2,16 @@ use axum::{
     extract::{Path, Query, State},
     Json,
 };
+use chrono::NaiveDateTime;
 use serde::Deserialize;
-use sqlx::SqlitePool;
+use sqlx::{Executor, QueryBuilder, Sqlite, SqliteConnection, SqlitePool};
+use std::sync::Arc;
 use utoipa::{IntoParams, ToSchema};
 
 use crate::{
+    config::{Config, UnverifiedAction},
     error::AppError,
-    middleware::CurrentUser,
+    middleware::{require_verified, CurrentUser},
     models::{CreateTask, Task, UpdateTask},
 };
 
@@ -18,6 +21,65 @@ pub struct Pagination {
     pub limit: Option<i64>,
 }
 
+/// Elementos por página en los listados de admin y auditoría (`limit` por defecto y máximo).
+const DEFAULT_PAGE_SIZE: i64 = 100;
+const MAX_PAGE_SIZE: i64 = 500;
+
+/// `skip` y `limit` válidos: nada negativo y como mucho `MAX_PAGE_SIZE` elementos.
+pub(crate) fn page_bounds(skip: Option<i64>, limit: Option<i64>) -> (i64, i64) {
+    let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(0, MAX_PAGE_SIZE);
+    (skip.unwrap_or(0).max(0), limit)
+}
+
+/// Orden del listado de tareas; con `-` delante, descendente.
+#[derive(Debug, Clone, Copy, Default, Deserialize, ToSchema)]
+pub enum TaskSort {
+    #[default]
+    #[serde(rename = "id")]
+    Id,
+    #[serde(rename = "-id")]
+    IdDesc,
+    #[serde(rename = "created_at")]
+    CreatedAt,
+    #[serde(rename = "-created_at")]
+    CreatedAtDesc,
+    #[serde(rename = "title")]
+    Title,
+    #[serde(rename = "-title")]
+    TitleDesc,
+}
+
+impl TaskSort {
+    /// Cláusula `ORDER BY`; el id desempata para que la paginación sea estable.
+    fn order_by(self) -> &'static str {
+        match self {
+            TaskSort::Id => "id ASC",
+            TaskSort::IdDesc => "id DESC",
+            TaskSort::CreatedAt => "created_at ASC, id ASC",
+            TaskSort::CreatedAtDesc => "created_at DESC, id DESC",
+            TaskSort::Title => "title COLLATE NOCASE ASC, id ASC",
+            TaskSort::TitleDesc => "title COLLATE NOCASE DESC, id DESC",
+        }
+    }
+}
+
+/// Filtros, orden y paginación del listado de tareas.
+#[derive(Debug, Default, Deserialize, IntoParams)]
+pub struct TaskQuery {
+    /// Solo tareas completadas (`true`) o pendientes (`false`)
+    pub completed: Option<bool>,
+    /// Creadas en esta fecha o después (p. ej. `2024-01-31T00:00:00`)
+    pub created_after: Option<NaiveDateTime>,
+    /// Creadas antes de esta fecha
+    pub created_before: Option<NaiveDateTime>,
+    /// Texto contenido en el título o la descripción (sin distinguir mayúsculas)
+    pub search: Option<String>,
+    /// `id` (por defecto), `created_at`, `title`, o cualquiera con `-` para orden descendente
+    pub sort: Option<TaskSort>,
+    pub skip: Option<i64>,
+    pub limit: Option<i64>,
+}
+
 #[utoipa::path(
     post,
     path = "/tasks/",
@@ -27,14 +89,18 @@ pub struct Pagination {
         (status = 401, description = "Unauthorized")
     ),
     security(
-        ("bearer" = [])
+        ("bearer" = []),
+        ("api_key" = [])
     )
 )]
 pub async fn create_task(
     State(pool): State<SqlitePool>,
+    State(config): State<Arc<Config>>,
     CurrentUser(user): CurrentUser,
     Json(payload): Json<CreateTask>,
 ) -> Result<Json<Task>, AppError> {
+    require_verified(&config, &user, UnverifiedAction::WriteTasks)?;
+
     let id = sqlx::query(
         "INSERT INTO tasks (title, description, completed, owner_id) VALUES (?, ?, ?, ?)",
     )
@@ -57,22 +123,23 @@ pub async fn create_task(
 #[utoipa::path(
     get,
     path = "/tasks/",
-    params(Pagination),
+    params(TaskQuery),
     responses(
         (status = 200, description = "List tasks", body = Vec<Task>),
         (status = 401, description = "Unauthorized")
     ),
     security(
-        ("bearer" = [])
+        ("bearer" = []),
+        ("api_key" = [])
     )
 )]
 pub async fn get_tasks(
     State(pool): State<SqlitePool>,
+    State(config): State<Arc<Config>>,
     CurrentUser(user): CurrentUser,
-    Query(params): Query<Pagination>,
+    Query(params): Query<TaskQuery>,
 ) -> Result<Json<Vec<Task>>, AppError> {
-    let skip = params.skip.unwrap_or(0);
-    let limit = params.limit.unwrap_or(100);
+    require_verified(&config, &user, UnverifiedAction::ReadTasks)?;
 
     // En backend original, autenticación NO era obligatoria para leer tasks,
     // pero el usuario pidió "lo mismo que FastApi... autorización y creacion...".
@@ -91,14 +158,7 @@ pub async fn get_tasks(
     // Pero como estoy haciendo un backend "bien hecho" en Rust, lo filtraré por usuario.
     // Si el usuario quiere ver todas, puede cambiarlo.
     
-    let tasks = sqlx::query_as::<_, Task>(
-        "SELECT * FROM tasks WHERE owner_id = ? LIMIT ? OFFSET ?",
-    )
-    .bind(user.id)
-    .bind(limit)
-    .bind(skip)
-    .fetch_all(&pool)
-    .await?;
+    let tasks = list_tasks(&pool, user.id, &params).await?;
 
     Ok(Json(tasks))
 }
@@ -115,22 +175,19 @@ pub async fn get_tasks(
         (status = 401, description = "Unauthorized")
     ),
     security(
-        ("bearer" = [])
+        ("bearer" = []),
+        ("api_key" = [])
     )
 )]
 pub async fn get_task(
     State(pool): State<SqlitePool>,
+    State(config): State<Arc<Config>>,
     CurrentUser(user): CurrentUser,
     Path(id): Path<i64>,
 ) -> Result<Json<Task>, AppError> {
-    let task = sqlx::query_as::<_, Task>(
-        "SELECT * FROM tasks WHERE id = ? AND owner_id = ?",
-    )
-    .bind(id)
-    .bind(user.id)
-    .fetch_optional(&pool)
-    .await?
-    .ok_or(AppError::NotFound("Task not found".to_string()))?;
+    require_verified(&config, &user, UnverifiedAction::ReadTasks)?;
+
+    let task = find_task(&pool, id, Some(user.id)).await?;
 
     Ok(Json(task))
 }
@@ -148,53 +205,20 @@ pub async fn get_task(
         (status = 401, description = "Unauthorized")
     ),
     security(
-        ("bearer" = [])
+        ("bearer" = []),
+        ("api_key" = [])
     )
 )]
 pub async fn update_task(
     State(pool): State<SqlitePool>,
+    State(config): State<Arc<Config>>,
     CurrentUser(user): CurrentUser,
     Path(id): Path<i64>,
     Json(payload): Json<UpdateTask>,
 ) -> Result<Json<Task>, AppError> {
-    // Primero verificamos que exista y pertenezca al usuario
-    let _ = sqlx::query("SELECT 1 FROM tasks WHERE id = ? AND owner_id = ?")
-        .bind(id)
-        .bind(user.id)
-        .fetch_optional(&pool)
-        .await?
-        .ok_or(AppError::NotFound("Task not found".to_string()))?;
-
-    // Construcción dinámica de la query (SQLx no tiene query builder super flexible nativo sin macros,
-    // pero para 3 campos podemos usar COALESCE o lógica simple).
-    // Usaremos COALESCE en SQL para actualizar solo si no es NULL,
-    // PERO SQLite driver de SQLx binding con Option<T> funciona bien:
-    // "UPDATE tasks SET title = COALESCE(?, title) ..."
-    
-    // SQLx maneja Option::None como NULL.
-    // COALESCE(NULL, title) -> title (no changes)
-    // COALESCE('new', title) -> 'new'
-    
-    sqlx::query(
-        "UPDATE tasks SET 
-            title = COALESCE(?, title), 
-            description = COALESCE(?, description), 
-            completed = COALESCE(?, completed)
-        WHERE id = ? AND owner_id = ?",
-    )
-    .bind(&payload.title)
-    .bind(&payload.description)
-    .bind(payload.completed)
-    .bind(id)
-    .bind(user.id)
-    .execute(&pool)
-    .await?;
+    require_verified(&config, &user, UnverifiedAction::WriteTasks)?;
 
-    // Retornar tarea actualizada
-    let task = sqlx::query_as::<_, Task>("SELECT * FROM tasks WHERE id = ?")
-        .bind(id)
-        .fetch_one(&pool)
-        .await?;
+    let task = apply_update(&mut *pool.acquire().await?, id, Some(user.id), &payload).await?;
 
     Ok(Json(task))
 }
@@ -211,23 +235,147 @@ pub async fn update_task(
         (status = 401, description = "Unauthorized")
     ),
     security(
-        ("bearer" = [])
+        ("bearer" = []),
+        ("api_key" = [])
     )
 )]
 pub async fn delete_task(
     State(pool): State<SqlitePool>,
+    State(config): State<Arc<Config>>,
     CurrentUser(user): CurrentUser,
     Path(id): Path<i64>,
 ) -> Result<Json<serde_json::Value>, AppError> {
-    let result = sqlx::query("DELETE FROM tasks WHERE id = ? AND owner_id = ?")
+    require_verified(&config, &user, UnverifiedAction::WriteTasks)?;
+
+    remove_task(&mut *pool.acquire().await?, id, Some(user.id)).await?;
+
+    Ok(Json(serde_json::json!({ "ok": true })))
+}
+
+// --- Consultas compartidas con las rutas de administración ---
+// `owner_id = None` solo se usa desde `handlers::admin` (acceso a tareas de cualquier usuario).
+
+pub(crate) async fn list_tasks(
+    pool: &SqlitePool,
+    owner_id: i64,
+    params: &TaskQuery,
+) -> Result<Vec<Task>, AppError> {
+    let skip = params.skip.unwrap_or(0);
+    let limit = params.limit.unwrap_or(100);
+
+    let mut query = QueryBuilder::<Sqlite>::new("SELECT * FROM tasks WHERE owner_id = ");
+    query.push_bind(owner_id);
+
+    if let Some(completed) = params.completed {
+        query.push(" AND completed = ").push_bind(completed);
+    }
+    if let Some(created_after) = params.created_after {
+        query.push(" AND created_at >= ").push_bind(created_after);
+    }
+    if let Some(created_before) = params.created_before {
+        query.push(" AND created_at < ").push_bind(created_before);
+    }
+    if let Some(search) = params.search.as_deref().map(str::trim).filter(|s| !s.is_empty()) {
+        // `%` y `_` del texto buscado se tratan como caracteres normales
+        let pattern = format!(
+            "%{}%",
+            search.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_")
+        );
+        query
+            .push(" AND (title LIKE ")
+            .push_bind(pattern.clone())
+            .push(" ESCAPE '\\' OR description LIKE ")
+            .push_bind(pattern)
+            .push(" ESCAPE '\\')");
+    }
+
+    // El orden sale de una lista cerrada, nunca del texto de la petición
+    query
+        .push(" ORDER BY ")
+        .push(params.sort.unwrap_or_default().order_by())
+        .push(" LIMIT ")
+        .push_bind(limit)
+        .push(" OFFSET ")
+        .push_bind(skip);
+
+    let tasks = query.build_query_as::<Task>().fetch_all(pool).await?;
+
+    Ok(tasks)
+}
+
+pub(crate) async fn find_task<'e, E>(
+    executor: E,
+    id: i64,
+    owner_id: Option<i64>,
+) -> Result<Task, AppError>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
+    let task = sqlx::query_as::<_, Task>(
+        "SELECT * FROM tasks WHERE id = ? AND (? IS NULL OR owner_id = ?)",
+    )
+    .bind(id)
+    .bind(owner_id)
+    .bind(owner_id)
+    .fetch_optional(executor)
+    .await?
+    .ok_or(AppError::NotFound("Task not found".to_string()))?;
+
+    Ok(task)
+}
+
+/// Recibe una conexión para que el admin la haga dentro de la transacción de su auditoría.
+pub(crate) async fn apply_update(
+    conn: &mut SqliteConnection,
+    id: i64,
+    owner_id: Option<i64>,
+    payload: &UpdateTask,
+) -> Result<Task, AppError> {
+    // Primero verificamos que exista (y pertenezca al usuario, si aplica)
+    find_task(&mut *conn, id, owner_id).await?;
+
+    // Usaremos COALESCE en SQL para actualizar solo si no es NULL:
+    // SQLx maneja Option::None como NULL.
+    // COALESCE(NULL, title) -> title (no changes)
+    // COALESCE('new', title) -> 'new'
+    sqlx::query(
+        "UPDATE tasks SET 
+            title = COALESCE(?, title), 
+            description = COALESCE(?, description), 
+            completed = COALESCE(?, completed)
+        WHERE id = ?",
+    )
+    .bind(&payload.title)
+    .bind(&payload.description)
+    .bind(payload.completed)
+    .bind(id)
+    .execute(&mut *conn)
+    .await?;
+
+    // Retornar tarea actualizada
+    let task = sqlx::query_as::<_, Task>("SELECT * FROM tasks WHERE id = ?")
         .bind(id)
-        .bind(user.id)
-        .execute(&pool)
+        .fetch_one(&mut *conn)
+        .await?;
+
+    Ok(task)
+}
+
+pub(crate) async fn remove_task(
+    conn: &mut SqliteConnection,
+    id: i64,
+    owner_id: Option<i64>,
+) -> Result<(), AppError> {
+    let result = sqlx::query("DELETE FROM tasks WHERE id = ? AND (? IS NULL OR owner_id = ?)")
+        .bind(id)
+        .bind(owner_id)
+        .bind(owner_id)
+        .execute(conn)
         .await?;
 
     if result.rows_affected() == 0 {
         return Err(AppError::NotFound("Task not found".to_string()));
     }
 
-    Ok(Json(serde_json::json!({ "ok": true })))
+    Ok(())
 }
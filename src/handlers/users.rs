@@ -0,0 +1,583 @@
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    Json,
+};
+use chrono::{Duration, Utc};
+
+use crate::{
+    audit,
+    email,
+    error::AppError,
+    mailer::Email,
+    middleware::{ClientInfo, CurrentUser},
+    models::{
+        AuditAction, ChangeEmailRequest, ChangePasswordRequest, ConfirmEmailChangeRequest, DeleteAccountRequest, Token,
+        UpdateProfile, User, VerifyEmailParams,
+    },
+    password,
+    password_policy,
+    revocation::RevocationStore,
+    state::AppState,
+    tokens,
+};
+
+pub const VERIFICATION_TOKEN_TTL_HOURS: i64 = 24;
+pub const EMAIL_CHANGE_TOKEN_TTL_HOURS: i64 = 24;
+const MAX_DISPLAY_NAME_LEN: usize = 100;
+const MAX_AVATAR_URL_LEN: usize = 2048;
+
+/// Genera un token de verificación para `email` y lo envía por correo.
+pub(crate) async fn send_verification_email(
+    state: &AppState,
+    user_id: i64,
+    email: &str,
+) -> Result<(), AppError> {
+    let token = tokens::generate_opaque_token();
+    let expires_at = (Utc::now() + Duration::hours(VERIFICATION_TOKEN_TTL_HOURS)).naive_utc();
+
+    sqlx::query(
+        "INSERT INTO email_verification_tokens (user_id, token_hash, expires_at) VALUES (?, ?, ?)",
+    )
+    .bind(user_id)
+    .bind(tokens::hash_token(&token))
+    .bind(expires_at)
+    .execute(&state.pool)
+    .await?;
+
+    state
+        .mailer
+        .send(Email {
+            to: email.to_string(),
+            subject: "Verify your email address".to_string(),
+            body: format!(
+                "Confirm your email address by opening this link (valid for {} hours):\n\n{}/users/verify?token={}",
+                VERIFICATION_TOKEN_TTL_HOURS, state.config.base_url, token
+            ),
+        })
+        .await
+}
+
+#[utoipa::path(
+    get,
+    path = "/users/verify",
+    params(VerifyEmailParams),
+    responses(
+        (status = 200, description = "Email verified"),
+        (status = 400, description = "Invalid or expired verification token")
+    )
+)]
+pub async fn verify_email(
+    State(state): State<AppState>,
+    Query(params): Query<VerifyEmailParams>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    let now = Utc::now().naive_utc();
+    let mut tx = state.pool.begin().await?;
+
+    let user_id: i64 = sqlx::query_scalar(
+        "UPDATE email_verification_tokens SET used_at = ?
+        WHERE token_hash = ? AND used_at IS NULL AND expires_at > ?
+        RETURNING user_id",
+    )
+    .bind(now)
+    .bind(tokens::hash_token(&params.token))
+    .bind(now)
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or(AppError::ValidationError("Invalid or expired verification token".to_string()))?;
+
+    sqlx::query("UPDATE users SET email_verified_at = COALESCE(email_verified_at, ?) WHERE id = ?")
+        .bind(now)
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+
+    Ok(Json(serde_json::json!({ "ok": true })))
+}
+
+#[utoipa::path(
+    post,
+    path = "/users/me/verify/resend",
+    responses(
+        (status = 202, description = "Verification email sent"),
+        (status = 400, description = "Email already verified"),
+        (status = 401, description = "Unauthorized")
+    ),
+    security(
+        ("bearer" = [])
+    )
+)]
+pub async fn resend_verification(
+    State(state): State<AppState>,
+    CurrentUser(user): CurrentUser,
+) -> Result<(StatusCode, Json<serde_json::Value>), AppError> {
+    if user.email_verified_at.is_some() {
+        return Err(AppError::ValidationError("Email already verified".to_string()));
+    }
+
+    send_verification_email(&state, user.id, &user.email).await?;
+
+    Ok((StatusCode::ACCEPTED, Json(serde_json::json!({ "ok": true }))))
+}
+
+/// Normaliza y valida un campo de perfil; `""` se conserva para borrar el valor.
+fn profile_field(
+    value: &Option<String>,
+    valid: impl Fn(&str) -> bool,
+    message: &str,
+) -> Result<Option<String>, AppError> {
+    match value.as_deref().map(str::trim) {
+        Some(v) if !v.is_empty() && !valid(v) => Err(AppError::ValidationError(message.to_string())),
+        other => Ok(other.map(str::to_string)),
+    }
+}
+
+/// Etiqueta de idioma BCP 47 simplificada: `es`, `es-ES`, `zh-Hant-TW`...
+fn is_valid_locale(locale: &str) -> bool {
+    let mut parts = locale.split('-');
+    let language = parts.next().unwrap_or_default();
+
+    locale.len() <= 35
+        && (2..=3).contains(&language.len())
+        && language.chars().all(|c| c.is_ascii_alphabetic())
+        && parts.all(|p| (1..=8).contains(&p.len()) && p.chars().all(|c| c.is_ascii_alphanumeric()))
+}
+
+fn is_valid_avatar_url(avatar_url: &str) -> bool {
+    avatar_url.len() <= MAX_AVATAR_URL_LEN
+        && url::Url::parse(avatar_url).is_ok_and(|url| url.scheme() == "https" && url.host().is_some())
+}
+
+#[utoipa::path(
+    get,
+    path = "/users/me",
+    responses(
+        (status = 200, description = "Profile of the authenticated user", body = User),
+        (status = 401, description = "Unauthorized")
+    ),
+    security(
+        ("bearer" = [])
+    )
+)]
+pub async fn get_me(CurrentUser(user): CurrentUser) -> Json<User> {
+    Json(user)
+}
+
+#[utoipa::path(
+    patch,
+    path = "/users/me",
+    request_body = UpdateProfile,
+    responses(
+        (status = 200, description = "Profile updated", body = User),
+        (status = 400, description = "Invalid display name, timezone, locale or avatar URL"),
+        (status = 401, description = "Unauthorized")
+    ),
+    security(
+        ("bearer" = [])
+    )
+)]
+pub async fn update_me(
+    State(state): State<AppState>,
+    CurrentUser(user): CurrentUser,
+    Json(payload): Json<UpdateProfile>,
+) -> Result<Json<User>, AppError> {
+    let display_name = profile_field(
+        &payload.display_name,
+        |v| v.chars().count() <= MAX_DISPLAY_NAME_LEN && !v.chars().any(char::is_control),
+        "Display name must be at most 100 characters",
+    )?;
+    let timezone = profile_field(
+        &payload.timezone,
+        |v| v.parse::<chrono_tz::Tz>().is_ok(),
+        "Unknown timezone (expected an IANA name such as Europe/Madrid)",
+    )?;
+    let locale = profile_field(
+        &payload.locale,
+        is_valid_locale,
+        "Invalid locale (expected a language tag such as es-ES)",
+    )?;
+    let avatar_url = profile_field(
+        &payload.avatar_url,
+        is_valid_avatar_url,
+        "Avatar URL must be an https URL",
+    )?;
+
+    // COALESCE conserva los campos ausentes; NULLIF convierte "" en NULL
+    let user = sqlx::query_as::<_, User>(
+        "UPDATE users SET
+            display_name = NULLIF(COALESCE(?, display_name), ''),
+            timezone = NULLIF(COALESCE(?, timezone), ''),
+            locale = NULLIF(COALESCE(?, locale), ''),
+            avatar_url = NULLIF(COALESCE(?, avatar_url), '')
+        WHERE id = ? RETURNING *",
+    )
+    .bind(display_name)
+    .bind(timezone)
+    .bind(locale)
+    .bind(avatar_url)
+    .bind(user.id)
+    .fetch_one(&state.pool)
+    .await?;
+
+    Ok(Json(user))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/users/me",
+    request_body = DeleteAccountRequest,
+    responses(
+        (status = 200, description = "Account deleted together with its tasks, sessions and keys"),
+        (status = 400, description = "Password missing or incorrect"),
+        (status = 401, description = "Unauthorized")
+    ),
+    security(
+        ("bearer" = [])
+    )
+)]
+pub async fn delete_me(
+    State(state): State<AppState>,
+    CurrentUser(user): CurrentUser,
+    client: ClientInfo,
+    payload: Option<Json<DeleteAccountRequest>>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    // Las cuentas con password local deben confirmarlo (las creadas por OIDC no tienen)
+    if !user.hashed_password.is_empty() {
+        let password = payload
+            .and_then(|Json(p)| p.password)
+            .ok_or(AppError::ValidationError("Password is required to delete the account".to_string()))?;
+        password::verify_password(&password, &user.hashed_password)
+            .map_err(|_| AppError::ValidationError("Password is incorrect".to_string()))?;
+    }
+
+    // El resto de datos del usuario (tareas, sesiones, API keys...) se borra en cascada
+    let mut tx = state.pool.begin().await?;
+    sqlx::query("DELETE FROM users WHERE id = ?")
+        .bind(user.id)
+        .execute(&mut *tx)
+        .await?;
+    audit::record(
+        &mut *tx,
+        &client,
+        AuditAction::AccountDeleted,
+        Some(user.id),
+        Some(user.id),
+        serde_json::json!({ "email": user.email }),
+    )
+    .await?;
+    tx.commit().await?;
+
+    tracing::info!("User {} deleted their account", user.id);
+
+    Ok(Json(serde_json::json!({ "ok": true })))
+}
+
+#[utoipa::path(
+    post,
+    path = "/users/me/deactivate",
+    responses(
+        (status = 200, description = "Account deactivated and every token revoked"),
+        (status = 401, description = "Unauthorized")
+    ),
+    security(
+        ("bearer" = [])
+    )
+)]
+pub async fn deactivate_me(
+    State(state): State<AppState>,
+    CurrentUser(user): CurrentUser,
+    client: ClientInfo,
+) -> Result<Json<serde_json::Value>, AppError> {
+    let mut tx = state.pool.begin().await?;
+    sqlx::query("UPDATE users SET is_active = FALSE WHERE id = ?")
+        .bind(user.id)
+        .execute(&mut *tx)
+        .await?;
+
+    RevocationStore::revoke_all_in(&mut tx, user.id).await?;
+    audit::record(
+        &mut *tx,
+        &client,
+        AuditAction::AccountDeactivated,
+        Some(user.id),
+        Some(user.id),
+        serde_json::json!({}),
+    )
+    .await?;
+    tx.commit().await?;
+
+    Ok(Json(serde_json::json!({ "ok": true })))
+}
+
+#[utoipa::path(
+    put,
+    path = "/users/me/password",
+    request_body = ChangePasswordRequest,
+    responses(
+        (status = 200, description = "Password changed; previous tokens revoked and a new pair issued", body = Token),
+        (status = 400, description = "Current password incorrect or new password rejected by policy"),
+        (status = 401, description = "Unauthorized")
+    ),
+    security(
+        ("bearer" = [])
+    )
+)]
+pub async fn change_password(
+    State(state): State<AppState>,
+    CurrentUser(user): CurrentUser,
+    client: ClientInfo,
+    Json(payload): Json<ChangePasswordRequest>,
+) -> Result<Json<Token>, AppError> {
+    // 1. Verificar la contraseña actual (misma verificación Argon2 que el login)
+    password::verify_password(&payload.current_password, &user.hashed_password)
+        .map_err(|_| AppError::ValidationError("Current password is incorrect".to_string()))?;
+
+    // 2. Aplicar la política a la contraseña nueva
+    password_policy::validate(&state.config.password_policy, &payload.new_password, &user.email).await?;
+    if payload.new_password == payload.current_password {
+        return Err(AppError::ValidationError(
+            "New password must be different from the current one".to_string(),
+        ));
+    }
+
+    // 3. Guardar el hash nuevo
+    let password_hash = password::hash_password(&state.config.argon2, &payload.new_password)?;
+    let mut tx = state.pool.begin().await?;
+    sqlx::query("UPDATE users SET hashed_password = ? WHERE id = ?")
+        .bind(&password_hash)
+        .bind(user.id)
+        .execute(&mut *tx)
+        .await?;
+
+    // 4. Invalidar todos los tokens emitidos antes del cambio (incluido el actual)
+    RevocationStore::revoke_all_in(&mut tx, user.id).await?;
+    audit::record(
+        &mut *tx,
+        &client,
+        AuditAction::PasswordChanged,
+        Some(user.id),
+        Some(user.id),
+        serde_json::json!({}),
+    )
+    .await?;
+    tx.commit().await?;
+
+    // 5. Emitir un par nuevo para que este cliente siga con sesión
+    let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = ?")
+        .bind(user.id)
+        .fetch_one(&state.pool)
+        .await?;
+    let token = tokens::issue_token_pair(&state.pool, &state.keys, &user, &client).await?;
+
+    Ok(Json(token))
+}
+
+#[utoipa::path(
+    post,
+    path = "/users/me/email",
+    request_body = ChangeEmailRequest,
+    responses(
+        (status = 202, description = "Confirmation link sent to the new address"),
+        (status = 400, description = "Invalid or already registered email, or password missing or incorrect"),
+        (status = 401, description = "Unauthorized")
+    ),
+    security(
+        ("bearer" = [])
+    )
+)]
+pub async fn request_email_change(
+    State(state): State<AppState>,
+    CurrentUser(user): CurrentUser,
+    client: ClientInfo,
+    Json(payload): Json<ChangeEmailRequest>,
+) -> Result<(StatusCode, Json<serde_json::Value>), AppError> {
+    // Las cuentas con password local deben confirmarlo (las creadas por OIDC no tienen)
+    if !user.hashed_password.is_empty() {
+        let password = payload
+            .password
+            .ok_or(AppError::ValidationError("Password is required to change the email".to_string()))?;
+        password::verify_password(&password, &user.hashed_password)
+            .map_err(|_| AppError::ValidationError("Password is incorrect".to_string()))?;
+    }
+
+    let new_email = email::normalize_email(&payload.new_email)?;
+    if new_email == user.email {
+        return Err(AppError::ValidationError(
+            "New email must be different from the current one".to_string(),
+        ));
+    }
+
+    let taken: Option<i64> = sqlx::query_scalar("SELECT id FROM users WHERE email = ?")
+        .bind(&new_email)
+        .fetch_optional(&state.pool)
+        .await?;
+    if taken.is_some() {
+        return Err(AppError::ValidationError("Email already registered".to_string()));
+    }
+
+    let now = Utc::now().naive_utc();
+    let token = tokens::generate_opaque_token();
+
+    // Solo el último cambio solicitado es válido
+    let mut tx = state.pool.begin().await?;
+    sqlx::query("UPDATE email_change_tokens SET used_at = ? WHERE user_id = ? AND used_at IS NULL")
+        .bind(now)
+        .bind(user.id)
+        .execute(&mut *tx)
+        .await?;
+
+    sqlx::query(
+        "INSERT INTO email_change_tokens (user_id, new_email, token_hash, expires_at) VALUES (?, ?, ?, ?)",
+    )
+    .bind(user.id)
+    .bind(&new_email)
+    .bind(tokens::hash_token(&token))
+    .bind(now + Duration::hours(EMAIL_CHANGE_TOKEN_TTL_HOURS))
+    .execute(&mut *tx)
+    .await?;
+    audit::record(
+        &mut *tx,
+        &client,
+        AuditAction::EmailChangeRequested,
+        Some(user.id),
+        Some(user.id),
+        serde_json::json!({ "new_email": new_email }),
+    )
+    .await?;
+    tx.commit().await?;
+
+    state
+        .mailer
+        .send(Email {
+            to: new_email,
+            subject: "Confirm your new email address".to_string(),
+            body: format!(
+                "Confirm the change of your account email to this address (valid for {} hours):\n\n{}/users/email/confirm?token={}",
+                EMAIL_CHANGE_TOKEN_TTL_HOURS, state.config.base_url, token
+            ),
+        })
+        .await?;
+
+    Ok((StatusCode::ACCEPTED, Json(serde_json::json!({ "ok": true }))))
+}
+
+#[utoipa::path(
+    post,
+    path = "/users/email/confirm",
+    request_body = ConfirmEmailChangeRequest,
+    responses(
+        (status = 200, description = "Email changed; the previous address has been notified"),
+        (status = 400, description = "Invalid or expired token, or the email was registered meanwhile")
+    )
+)]
+pub async fn confirm_email_change(
+    State(state): State<AppState>,
+    client: ClientInfo,
+    Json(payload): Json<ConfirmEmailChangeRequest>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    apply_email_change(&state, &client, &payload.token).await?;
+
+    Ok(Json(serde_json::json!({ "ok": true })))
+}
+
+#[utoipa::path(
+    get,
+    path = "/users/email/confirm",
+    params(ConfirmEmailChangeRequest),
+    responses(
+        (status = 200, description = "Email changed; the previous address has been notified"),
+        (status = 400, description = "Invalid or expired token, or the email was registered meanwhile")
+    )
+)]
+pub async fn open_email_change_link(
+    State(state): State<AppState>,
+    client: ClientInfo,
+    Query(params): Query<ConfirmEmailChangeRequest>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    // El enlace del correo se abre directamente en el navegador, como el de verificación
+    apply_email_change(&state, &client, &params.token).await?;
+
+    Ok(Json(serde_json::json!({ "ok": true })))
+}
+
+/// Consume el token de cambio de email y aplica la dirección nueva.
+async fn apply_email_change(state: &AppState, client: &ClientInfo, token: &str) -> Result<(), AppError> {
+    let now = Utc::now().naive_utc();
+    let mut tx = state.pool.begin().await?;
+
+    // Consumir el token de forma atómica: solo una petición puede usarlo
+    let (user_id, new_email): (i64, String) = sqlx::query_as(
+        "UPDATE email_change_tokens SET used_at = ?
+        WHERE token_hash = ? AND used_at IS NULL AND expires_at > ?
+        RETURNING user_id, new_email",
+    )
+    .bind(now)
+    .bind(tokens::hash_token(token))
+    .bind(now)
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or(AppError::ValidationError("Invalid or expired confirmation token".to_string()))?;
+
+    // Otra cuenta pudo registrar la dirección mientras tanto
+    let taken: Option<i64> = sqlx::query_scalar("SELECT id FROM users WHERE email = ? AND id != ?")
+        .bind(&new_email)
+        .bind(user_id)
+        .fetch_optional(&mut *tx)
+        .await?;
+    if taken.is_some() {
+        return Err(AppError::ValidationError("Email already registered".to_string()));
+    }
+
+    let old_email: String = sqlx::query_scalar("SELECT email FROM users WHERE id = ?")
+        .bind(user_id)
+        .fetch_one(&mut *tx)
+        .await?;
+
+    // Abrir el enlace prueba que el usuario controla la dirección nueva
+    sqlx::query("UPDATE users SET email = ?, email_verified_at = ? WHERE id = ?")
+        .bind(&new_email)
+        .bind(now)
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+
+    // Los enlaces enviados a la dirección anterior dejan de valer
+    for table in ["password_reset_tokens", "magic_link_tokens", "email_verification_tokens"] {
+        sqlx::query(&format!(
+            "UPDATE {} SET used_at = ? WHERE user_id = ? AND used_at IS NULL",
+            table
+        ))
+        .bind(now)
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    audit::record(
+        &mut *tx,
+        client,
+        AuditAction::EmailChanged,
+        Some(user_id),
+        Some(user_id),
+        serde_json::json!({ "old_email": old_email, "new_email": new_email }),
+    )
+    .await?;
+    tx.commit().await?;
+
+    // Los tokens identifican al usuario por id, así que las sesiones abiertas siguen valiendo
+    let notice = Email {
+        to: old_email,
+        subject: "Your email address was changed".to_string(),
+        body: format!(
+            "The email address of your account was changed to {}. If you did not make this change, contact support immediately.",
+            new_email
+        ),
+    };
+    if let Err(e) = state.mailer.send(notice).await {
+        tracing::warn!("Cannot notify user {} about their email change: {}", user_id, e);
+    }
+
+    Ok(())
+}
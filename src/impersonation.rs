@@ -0,0 +1,46 @@
+use axum::http::Method;
+use sqlx::SqlitePool;
+
+use crate::{
+    error::AppError,
+    models::{Actor, Role, User},
+    tokens,
+};
+
+/// Con un token de suplantación solo se puede ver y manejar las tareas del usuario,
+/// leer su perfil y cerrar la sesión. Crear tareas siempre está permitido; cambiar o
+/// borrar las existentes (PUT, PATCH, DELETE) exige `allow_destructive`.
+pub fn permits(actor: &Actor, method: &Method, path: &str) -> Result<(), AppError> {
+    let allowed = path.starts_with("/tasks")
+        || (path == "/users/me" && *method == Method::GET)
+        || (path == "/logout" && *method == Method::POST);
+
+    if !allowed {
+        return Err(AppError::Forbidden(
+            "This operation is not allowed while impersonating".to_string(),
+        ));
+    }
+
+    let destructive = [Method::PUT, Method::PATCH, Method::DELETE].contains(method);
+    if path.starts_with("/tasks") && destructive && !actor.allow_destructive {
+        return Err(AppError::Forbidden(
+            "Destructive operations are not allowed while impersonating".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// El admin que suplanta debe seguir existiendo, activo y con rol admin: al quitarle
+/// el rol dejan de valer sus tokens de suplantación.
+pub async fn check_actor(pool: &SqlitePool, actor: &Actor) -> Result<User, AppError> {
+    let admin = sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = ?")
+        .bind(tokens::subject_user_id(&actor.sub)?)
+        .fetch_optional(pool)
+        .await?;
+
+    match admin {
+        Some(admin) if admin.is_active && admin.role == Role::Admin => Ok(admin),
+        _ => Err(AppError::AuthError("Impersonation is no longer authorized".to_string())),
+    }
+}
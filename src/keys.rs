@@ -0,0 +1,209 @@
+use anyhow::{anyhow, bail, Context};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::{
+    decode, decode_header, encode,
+    jwk::{
+        AlgorithmParameters, CommonParameters, EllipticCurve, Jwk, JwkSet, KeyAlgorithm,
+        OctetKeyPairParameters, OctetKeyPairType, PublicKeyUse, RSAKeyParameters, RSAKeyType,
+    },
+    Algorithm, DecodingKey, EncodingKey, Header, Validation,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use sha2::{Digest, Sha256};
+use simple_asn1::ASN1Block;
+use std::env;
+
+// OIDs del algoritmo en un SubjectPublicKeyInfo
+const OID_RSA: [u64; 7] = [1, 2, 840, 113549, 1, 1, 1];
+const OID_ED25519: [u64; 4] = [1, 3, 101, 112];
+
+/// Clave pública con la que se verifican tokens; se publica en el JWKS.
+struct VerificationKey {
+    algorithm: Algorithm,
+    decoding: DecodingKey,
+    jwk: Jwk,
+}
+
+/// Claves de firma de los JWT: una privada con la que se firman los tokens nuevos y
+/// varias públicas aceptadas al verificar, de modo que las claves puedan rotarse sin
+/// invalidar los tokens ya emitidos. Cada token lleva en `kid` la clave que lo firmó.
+pub struct JwtKeys {
+    kid: String,
+    algorithm: Algorithm,
+    encoding: EncodingKey,
+    verification: Vec<VerificationKey>,
+}
+
+/// Lee la clave pública (SPKI) de un PEM y la convierte en JWK. El `kid` es un
+/// fingerprint SHA-256 de la clave, estable entre reinicios e instancias.
+fn parse_public_key(pem: &[u8]) -> anyhow::Result<(Algorithm, Jwk)> {
+    let pem = pem::parse(pem).context("Invalid PEM")?;
+    if pem.tag() != "PUBLIC KEY" {
+        bail!("Expected a PUBLIC KEY PEM, found {}", pem.tag());
+    }
+
+    let blocks = simple_asn1::from_der(pem.contents()).context("Invalid public key DER")?;
+    let (oid, key_bytes) = match blocks.as_slice() {
+        [ASN1Block::Sequence(_, spki)] => match spki.as_slice() {
+            [ASN1Block::Sequence(_, alg), ASN1Block::BitString(_, _, key)] => match alg.first() {
+                Some(ASN1Block::ObjectIdentifier(_, oid)) => (
+                    oid.as_vec::<u64>().context("Invalid algorithm OID")?,
+                    key,
+                ),
+                _ => bail!("Invalid public key algorithm"),
+            },
+            _ => bail!("Invalid SubjectPublicKeyInfo"),
+        },
+        _ => bail!("Invalid SubjectPublicKeyInfo"),
+    };
+
+    let (algorithm, key_algorithm, params) = if oid == OID_RSA {
+        let (n, e) = match simple_asn1::from_der(key_bytes)
+            .context("Invalid RSA public key")?
+            .as_slice()
+        {
+            [ASN1Block::Sequence(_, ints)] => match ints.as_slice() {
+                [ASN1Block::Integer(_, n), ASN1Block::Integer(_, e)] => {
+                    (n.to_bytes_be().1, e.to_bytes_be().1)
+                }
+                _ => bail!("Invalid RSA public key"),
+            },
+            _ => bail!("Invalid RSA public key"),
+        };
+
+        (
+            Algorithm::RS256,
+            KeyAlgorithm::RS256,
+            AlgorithmParameters::RSA(RSAKeyParameters {
+                key_type: RSAKeyType::RSA,
+                n: URL_SAFE_NO_PAD.encode(n),
+                e: URL_SAFE_NO_PAD.encode(e),
+            }),
+        )
+    } else if oid == OID_ED25519 {
+        (
+            Algorithm::EdDSA,
+            KeyAlgorithm::EdDSA,
+            AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+                key_type: OctetKeyPairType::OctetKeyPair,
+                curve: EllipticCurve::Ed25519,
+                x: URL_SAFE_NO_PAD.encode(key_bytes),
+            }),
+        )
+    } else {
+        bail!("Unsupported key type (expected RSA or Ed25519)");
+    };
+
+    let kid = URL_SAFE_NO_PAD.encode(&Sha256::digest(pem.contents())[..12]);
+    let jwk = Jwk {
+        common: CommonParameters {
+            public_key_use: Some(PublicKeyUse::Signature),
+            key_algorithm: Some(key_algorithm),
+            key_id: Some(kid),
+            ..Default::default()
+        },
+        algorithm: params,
+    };
+
+    Ok((algorithm, jwk))
+}
+
+fn read_pem(path: &str) -> anyhow::Result<Vec<u8>> {
+    std::fs::read(path).with_context(|| format!("Cannot read key file {}", path))
+}
+
+impl JwtKeys {
+    /// Carga las claves de `JWT_PRIVATE_KEY_FILE` y `JWT_PUBLIC_KEY_FILE` (obligatorias) y
+    /// de `JWT_ADDITIONAL_PUBLIC_KEY_FILES` (lista separada por comas de claves públicas
+    /// que se siguen aceptando, p. ej. la anterior tras una rotación).
+    pub fn from_env() -> anyhow::Result<Self> {
+        let private_path = env::var("JWT_PRIVATE_KEY_FILE")
+            .map_err(|_| anyhow!("JWT_PRIVATE_KEY_FILE must be set"))?;
+        let public_path = env::var("JWT_PUBLIC_KEY_FILE")
+            .map_err(|_| anyhow!("JWT_PUBLIC_KEY_FILE must be set"))?;
+
+        let additional = env::var("JWT_ADDITIONAL_PUBLIC_KEY_FILES")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(read_pem)
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        Self::from_pem(&read_pem(&private_path)?, &read_pem(&public_path)?, &additional)
+    }
+
+    pub fn from_pem(
+        private_pem: &[u8],
+        public_pem: &[u8],
+        additional_public_pems: &[Vec<u8>],
+    ) -> anyhow::Result<Self> {
+        let mut verification = Vec::new();
+        for pem in std::iter::once(public_pem).chain(additional_public_pems.iter().map(Vec::as_slice)) {
+            let (algorithm, jwk) = parse_public_key(pem)?;
+            let decoding = DecodingKey::from_jwk(&jwk).context("Invalid public key")?;
+            verification.push(VerificationKey { algorithm, decoding, jwk });
+        }
+
+        let signing = &verification[0];
+        let encoding = match signing.algorithm {
+            Algorithm::EdDSA => EncodingKey::from_ed_pem(private_pem),
+            _ => EncodingKey::from_rsa_pem(private_pem),
+        }
+        .context("Invalid private key")?;
+
+        let keys = Self {
+            kid: signing.jwk.common.key_id.clone().unwrap_or_default(),
+            algorithm: signing.algorithm,
+            encoding,
+            verification,
+        };
+
+        // Comprobar que la clave privada y la pública forman un par
+        let probe = keys
+            .encode(&serde_json::json!({ "exp": usize::MAX }))
+            .map_err(|e| anyhow!("Cannot sign with private key: {}", e))?;
+        keys.decode::<serde_json::Value>(&probe, None)
+            .map_err(|_| anyhow!("JWT private key does not match JWT_PUBLIC_KEY_FILE"))?;
+
+        Ok(keys)
+    }
+
+    /// Firma `claims` con la clave activa, indicando su `kid` en la cabecera.
+    pub fn encode<T: Serialize>(&self, claims: &T) -> Result<String, jsonwebtoken::errors::Error> {
+        let mut header = Header::new(self.algorithm);
+        header.kid = Some(self.kid.clone());
+
+        encode(&header, claims, &self.encoding)
+    }
+
+    /// Verifica un token con la clave indicada en su `kid`, que debe ser una de las
+    /// configuradas y del mismo algoritmo. Con `audience` se exige ese `aud`; sin él se
+    /// rechazan los tokens que lo lleven.
+    pub fn decode<T: DeserializeOwned>(&self, token: &str, audience: Option<&str>) -> Result<T, String> {
+        let header = decode_header(token).map_err(|e| e.to_string())?;
+        let kid = header.kid.ok_or("Missing kid")?;
+
+        let key = self
+            .verification
+            .iter()
+            .find(|k| k.jwk.common.key_id.as_deref() == Some(kid.as_str()))
+            .ok_or("Unknown kid")?;
+
+        let mut validation = Validation::new(key.algorithm);
+        if let Some(audience) = audience {
+            validation.set_audience(&[audience]);
+        }
+
+        decode::<T>(token, &key.decoding, &validation)
+            .map(|data| data.claims)
+            .map_err(|e| e.to_string())
+    }
+
+    /// Claves públicas en formato JWKS, para que otros servicios verifiquen los tokens.
+    pub fn jwks(&self) -> JwkSet {
+        JwkSet {
+            keys: self.verification.iter().map(|k| k.jwk.clone()).collect(),
+        }
+    }
+}
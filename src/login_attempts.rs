@@ -0,0 +1,313 @@
+use axum::async_trait;
+use sqlx::{FromRow, SqlitePool};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+use crate::{config::LockoutPolicy, error::AppError, tokens};
+
+/// Fallos acumulados de una clave (cuenta o IP).
+#[derive(Debug, Clone, Copy, FromRow)]
+pub struct AttemptRecord {
+    pub failures: i64,
+    pub last_failure_at: i64,
+    pub locked_until: Option<i64>,
+}
+
+impl AttemptRecord {
+    /// Un registro caduca cuando pasa `window_secs` sin fallos ni bloqueo activo.
+    fn is_stale(&self, now: i64, window_secs: i64) -> bool {
+        self.last_failure_at.max(self.locked_until.unwrap_or(0)) <= now - window_secs
+    }
+}
+
+/// Almacenamiento de los intentos fallidos. La política (umbral, backoff) se aplica
+/// en este módulo, de modo que las implementaciones son intercambiables.
+#[async_trait]
+pub trait LoginAttemptStore: Send + Sync {
+    async fn get(&self, key: &str) -> Result<Option<AttemptRecord>, AppError>;
+
+    /// Suma un fallo de forma atómica, empezando de cero si el registro caducó,
+    /// y devuelve el registro resultante.
+    async fn record_failure(
+        &self,
+        key: &str,
+        now: i64,
+        window_secs: i64,
+    ) -> Result<AttemptRecord, AppError>;
+
+    async fn lock(&self, key: &str, until: i64) -> Result<(), AppError>;
+
+    async fn clear(&self, key: &str) -> Result<(), AppError>;
+}
+
+/// Intentos en memoria: se pierden al reiniciar y no se comparten entre instancias.
+#[derive(Default)]
+pub struct MemoryLoginAttemptStore {
+    records: Mutex<HashMap<String, AttemptRecord>>,
+}
+
+#[async_trait]
+impl LoginAttemptStore for MemoryLoginAttemptStore {
+    async fn get(&self, key: &str) -> Result<Option<AttemptRecord>, AppError> {
+        Ok(self
+            .records
+            .lock()
+            .expect("login attempts poisoned")
+            .get(key)
+            .copied())
+    }
+
+    async fn record_failure(
+        &self,
+        key: &str,
+        now: i64,
+        window_secs: i64,
+    ) -> Result<AttemptRecord, AppError> {
+        let mut records = self.records.lock().expect("login attempts poisoned");
+        // Aprovechamos para descartar registros caducados
+        records.retain(|_, record| !record.is_stale(now, window_secs));
+
+        let record = records.entry(key.to_string()).or_insert(AttemptRecord {
+            failures: 0,
+            last_failure_at: now,
+            locked_until: None,
+        });
+        record.failures += 1;
+        record.last_failure_at = now;
+
+        Ok(*record)
+    }
+
+    async fn lock(&self, key: &str, until: i64) -> Result<(), AppError> {
+        if let Some(record) = self
+            .records
+            .lock()
+            .expect("login attempts poisoned")
+            .get_mut(key)
+        {
+            record.locked_until = Some(until);
+        }
+        Ok(())
+    }
+
+    async fn clear(&self, key: &str) -> Result<(), AppError> {
+        self.records
+            .lock()
+            .expect("login attempts poisoned")
+            .remove(key);
+        Ok(())
+    }
+}
+
+/// Intentos en la tabla `login_attempts`: sobreviven a reinicios y el CLI puede
+/// desbloquear cuentas.
+pub struct SqliteLoginAttemptStore {
+    pool: SqlitePool,
+}
+
+impl SqliteLoginAttemptStore {
+    pub fn new(pool: SqlitePool) -> Self {
+        Self { pool }
+    }
+}
+
+#[async_trait]
+impl LoginAttemptStore for SqliteLoginAttemptStore {
+    async fn get(&self, key: &str) -> Result<Option<AttemptRecord>, AppError> {
+        let record = sqlx::query_as::<_, AttemptRecord>(
+            "SELECT failures, last_failure_at, locked_until FROM login_attempts WHERE key = ?",
+        )
+        .bind(key)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(record)
+    }
+
+    async fn record_failure(
+        &self,
+        key: &str,
+        now: i64,
+        window_secs: i64,
+    ) -> Result<AttemptRecord, AppError> {
+        let record = sqlx::query_as::<_, AttemptRecord>(
+            "INSERT INTO login_attempts (key, failures, last_failure_at) VALUES (?, 1, ?)
+            ON CONFLICT(key) DO UPDATE SET
+                failures = CASE
+                    WHEN MAX(last_failure_at, COALESCE(locked_until, 0)) <= ? THEN 1
+                    ELSE failures + 1
+                END,
+                locked_until = CASE
+                    WHEN MAX(last_failure_at, COALESCE(locked_until, 0)) <= ? THEN NULL
+                    ELSE locked_until
+                END,
+                last_failure_at = excluded.last_failure_at
+            RETURNING failures, last_failure_at, locked_until",
+        )
+        .bind(key)
+        .bind(now)
+        .bind(now - window_secs)
+        .bind(now - window_secs)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(record)
+    }
+
+    async fn lock(&self, key: &str, until: i64) -> Result<(), AppError> {
+        sqlx::query("UPDATE login_attempts SET locked_until = ? WHERE key = ?")
+            .bind(until)
+            .bind(key)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn clear(&self, key: &str) -> Result<(), AppError> {
+        sqlx::query("DELETE FROM login_attempts WHERE key = ?")
+            .bind(key)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+}
+
+/// Selecciona el almacén según `LOGIN_ATTEMPT_STORE` (`sqlite` por defecto, o `memory`).
+pub fn from_env(pool: SqlitePool) -> Arc<dyn LoginAttemptStore> {
+    match std::env::var("LOGIN_ATTEMPT_STORE").as_deref() {
+        Ok("memory") => Arc::new(MemoryLoginAttemptStore::default()),
+        _ => Arc::new(SqliteLoginAttemptStore::new(pool)),
+    }
+}
+
+fn account_key(email: &str) -> String {
+    format!("account:{}", email.trim().to_lowercase())
+}
+
+fn ip_key(ip: IpAddr) -> String {
+    format!("ip:{}", ip)
+}
+
+/// Segundos restantes de bloqueo, si lo hay.
+async fn locked_for(store: &dyn LoginAttemptStore, key: &str, now: i64) -> Result<Option<u64>, AppError> {
+    let locked_until = store.get(key).await?.and_then(|r| r.locked_until);
+
+    Ok(locked_until
+        .filter(|until| *until > now)
+        .map(|until| (until - now) as u64))
+}
+
+/// Rechaza el intento si la cuenta (`423`) o la IP (`429`) están bloqueadas.
+/// Se comprueba antes de verificar el password, para no gastar Argon2 en ataques.
+pub async fn check(
+    store: &dyn LoginAttemptStore,
+    email: &str,
+    ip: Option<IpAddr>,
+) -> Result<(), AppError> {
+    let now = tokens::now_secs() as i64;
+
+    if let Some(ip) = ip {
+        if let Some(retry_after) = locked_for(store, &ip_key(ip), now).await? {
+            return Err(AppError::TooManyRequests(retry_after));
+        }
+    }
+
+    if let Some(retry_after) = locked_for(store, &account_key(email), now).await? {
+        return Err(AppError::AccountLocked(retry_after));
+    }
+
+    Ok(())
+}
+
+/// Duración del bloqueo tras `failures` fallos: `lockout_secs` al alcanzar el umbral,
+/// duplicándose con cada fallo adicional hasta `max_lockout_secs`.
+fn lockout_duration(policy: &LockoutPolicy, failures: i64, threshold: u32) -> Option<i64> {
+    let over = failures - threshold as i64;
+    if threshold == 0 || over < 0 {
+        return None;
+    }
+
+    let factor = 1i64.checked_shl(over.min(32) as u32).unwrap_or(i64::MAX);
+    Some(
+        policy
+            .lockout_secs
+            .saturating_mul(factor)
+            .min(policy.max_lockout_secs),
+    )
+}
+
+async fn register_failure(
+    store: &dyn LoginAttemptStore,
+    policy: &LockoutPolicy,
+    key: &str,
+    threshold: u32,
+    now: i64,
+) -> Result<(), AppError> {
+    let record = store
+        .record_failure(key, now, policy.failure_window_secs)
+        .await?;
+
+    if let Some(duration) = lockout_duration(policy, record.failures, threshold) {
+        tracing::warn!("Locking {} for {}s after {} failed logins", key, duration, record.failures);
+        store.lock(key, now + duration).await?;
+    }
+
+    Ok(())
+}
+
+/// Registra un login fallido para la cuenta y la IP.
+pub async fn record_failure(
+    store: &dyn LoginAttemptStore,
+    policy: &LockoutPolicy,
+    email: &str,
+    ip: Option<IpAddr>,
+) -> Result<(), AppError> {
+    let now = tokens::now_secs() as i64;
+
+    register_failure(store, policy, &account_key(email), policy.max_account_failures, now).await?;
+
+    if let Some(ip) = ip {
+        register_failure(store, policy, &ip_key(ip), policy.max_ip_failures, now).await?;
+    }
+
+    Ok(())
+}
+
+/// Un login correcto reinicia el contador de la cuenta (no el de la IP: un atacante
+/// con una cuenta propia no debe poder limpiar su historial).
+pub async fn record_success(store: &dyn LoginAttemptStore, email: &str) -> Result<(), AppError> {
+    store.clear(&account_key(email)).await
+}
+
+/// Desbloquea una cuenta y olvida sus fallos.
+pub async fn unlock(store: &dyn LoginAttemptStore, email: &str) -> Result<(), AppError> {
+    store.clear(&account_key(email)).await
+}
+
+/// Limita las peticiones asociadas a `key` a `max` por ventana de `window_secs`
+/// (p. ej. envíos de magic links a un mismo email). Al superarlo responde `429`.
+pub async fn rate_limit(
+    store: &dyn LoginAttemptStore,
+    key: &str,
+    max: i64,
+    window_secs: i64,
+) -> Result<(), AppError> {
+    let now = tokens::now_secs() as i64;
+
+    match store.get(key).await? {
+        Some(AttemptRecord { locked_until: Some(until), .. }) if until > now => {
+            return Err(AppError::TooManyRequests((until - now) as u64));
+        }
+        // Cumplido el bloqueo se empieza una ventana nueva
+        Some(AttemptRecord { locked_until: Some(_), .. }) => store.clear(key).await?,
+        _ => {}
+    }
+
+    let record = store.record_failure(key, now, window_secs).await?;
+    if record.failures >= max {
+        store.lock(key, now + window_secs).await?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,100 @@
+use axum::async_trait;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::error::AppError;
+
+#[derive(Debug, Clone)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+/// Envío de correos transaccionales (reset de contraseña, verificación...).
+#[async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, email: Email) -> Result<(), AppError>;
+}
+
+/// Escribe los correos en el log. Útil en desarrollo.
+pub struct LogMailer;
+
+#[async_trait]
+impl Mailer for LogMailer {
+    async fn send(&self, email: Email) -> Result<(), AppError> {
+        tracing::info!(
+            "Email to {}: {}\n{}",
+            email.to,
+            email.subject,
+            email.body
+        );
+        Ok(())
+    }
+}
+
+/// Guarda cada correo como un fichero `.eml` en un directorio "outbox".
+pub struct FileMailer {
+    dir: PathBuf,
+}
+
+impl FileMailer {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+}
+
+#[async_trait]
+impl Mailer for FileMailer {
+    async fn send(&self, email: Email) -> Result<(), AppError> {
+        tokio::fs::create_dir_all(&self.dir)
+            .await
+            .map_err(|e| AppError::InternalError(format!("Cannot create outbox: {}", e)))?;
+
+        let file_name = format!(
+            "{}-{}.eml",
+            chrono::Utc::now().format("%Y%m%dT%H%M%S%.6f"),
+            email.to.replace(['/', '\\'], "_")
+        );
+        let contents = format!(
+            "To: {}\nSubject: {}\n\n{}\n",
+            email.to, email.subject, email.body
+        );
+
+        tokio::fs::write(self.dir.join(file_name), contents)
+            .await
+            .map_err(|e| AppError::InternalError(format!("Cannot write email: {}", e)))
+    }
+}
+
+/// Selecciona el mailer según el entorno: `MAIL_OUTBOX_DIR` activa el `FileMailer`,
+/// si no se usa el `LogMailer`.
+pub fn from_env() -> Arc<dyn Mailer> {
+    match std::env::var("MAIL_OUTBOX_DIR") {
+        Ok(dir) => Arc::new(FileMailer::new(dir)),
+        Err(_) => Arc::new(LogMailer),
+    }
+}
+
+/// Mailer en memoria para inspeccionar los correos enviados en los tests.
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub struct MemoryMailer {
+    sent: Arc<std::sync::Mutex<Vec<Email>>>,
+}
+
+#[cfg(test)]
+impl MemoryMailer {
+    pub fn sent(&self) -> Vec<Email> {
+        self.sent.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+#[async_trait]
+impl Mailer for MemoryMailer {
+    async fn send(&self, email: Email) -> Result<(), AppError> {
+        self.sent.lock().unwrap().push(email);
+        Ok(())
+    }
+}
@@ -1,5 +1,5 @@
 use axum::{
-    routing::{get, post, put, delete},
+    routing::{get, patch, post, put, delete},
     Router,
 };
 use dotenvy::dotenv;
@@ -11,10 +11,30 @@ use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 use utoipa::{OpenApi, Modify};
 use utoipa_swagger_ui::SwaggerUi;
 
+mod api_keys;
+mod audit;
+mod cli;
+mod config;
+mod cookies;
 mod db;
+mod email;
 mod error;
+mod export;
 mod handlers;
+mod impersonation;
+mod keys;
+mod login_attempts;
+mod mailer;
+mod mfa;
 mod middleware;
+mod oauth;
+mod oidc;
+mod password;
+mod password_policy;
+mod revocation;
+mod sessions;
+mod state;
+mod tokens;
 
 mod models;
 
@@ -26,6 +46,53 @@ mod tests;
     paths(
         handlers::auth::register,
         handlers::auth::login,
+        handlers::auth::login_mfa,
+        handlers::auth::refresh,
+        handlers::auth::logout,
+        handlers::auth::logout_all,
+        handlers::auth::jwks,
+        handlers::magic_link::request_magic_link,
+        handlers::magic_link::exchange_magic_link,
+        handlers::magic_link::open_magic_link,
+        handlers::oidc::oidc_authorize,
+        handlers::oidc::oidc_callback,
+        handlers::oidc::oidc_link,
+        handlers::password::forgot_password,
+        handlers::password::reset_password,
+        handlers::users::verify_email,
+        handlers::users::resend_verification,
+        handlers::users::get_me,
+        handlers::users::update_me,
+        handlers::users::delete_me,
+        handlers::users::deactivate_me,
+        handlers::export::export_me,
+        handlers::export::download_export,
+        handlers::export::download_export_link,
+        handlers::mfa::enroll_totp,
+        handlers::mfa::confirm_totp,
+        handlers::mfa::disable_totp,
+        handlers::api_keys::create_api_key,
+        handlers::api_keys::list_api_keys,
+        handlers::api_keys::rename_api_key,
+        handlers::api_keys::revoke_api_key,
+        handlers::sessions::list_sessions,
+        handlers::sessions::revoke_session,
+        handlers::users::change_password,
+        handlers::users::request_email_change,
+        handlers::users::confirm_email_change,
+        handlers::users::open_email_change_link,
+        handlers::admin::deactivate_user,
+        handlers::admin::reactivate_user,
+        handlers::admin::unlock_user,
+        handlers::admin::impersonate_user,
+        handlers::admin::list_users,
+        handlers::admin::set_user_role,
+        handlers::admin::list_user_tasks,
+        handlers::admin::get_task,
+        handlers::admin::update_task,
+        handlers::admin::delete_task,
+        handlers::audit::list_my_activity,
+        handlers::audit::list_audit_events,
         handlers::tasks::create_task,
         handlers::tasks::get_tasks,
         handlers::tasks::get_task,
@@ -35,18 +102,56 @@ mod tests;
     components(
         schemas(
             models::User, 
+            models::Role,
+            models::UpdateRole,
+            models::ImpersonateRequest,
+            models::ImpersonationToken,
             models::CreateUser, 
-            models::LoginRequest, 
+            models::TokenRequest,
             models::Token, 
+            models::LoginResponse,
+            models::MfaChallenge,
+            models::MfaLoginRequest,
+            models::CookieSession,
+            models::TotpEnrollment,
+            models::TotpCode,
+            models::RecoveryCodes,
+            models::ApiKey,
+            models::ApiKeyScope,
+            models::CreateApiKey,
+            models::CreatedApiKey,
+            models::UpdateApiKey,
+            models::Session,
+            models::LinkedIdentity,
+            models::ExportStatus,
+            models::DataExport,
+            models::UserExport,
+            models::AuditAction,
+            models::AuditEvent,
+            models::RefreshRequest,
+            models::LogoutRequest,
+            models::MagicLinkRequest,
+            models::MagicLinkExchange,
+            models::ForgotPasswordRequest,
+            models::ResetPasswordRequest,
+            models::ChangePasswordRequest,
+            models::ChangeEmailRequest,
+            models::ConfirmEmailChangeRequest,
+            models::UpdateProfile,
+            models::DeleteAccountRequest,
+            password_policy::PasswordViolation,
             models::Task, 
             models::CreateTask, 
             models::UpdateTask, 
-            handlers::tasks::Pagination
+            handlers::tasks::Pagination,
+            handlers::tasks::TaskSort
         )
     ),
     modifiers(&SecurityAddon),
     tags(
         (name = "auth", description = "Authentication endpoints"),
+        (name = "users", description = "Account management endpoints"),
+        (name = "admin", description = "Administration endpoints"),
         (name = "tasks", description = "Task management endpoints")
     )
 )]
@@ -65,6 +170,51 @@ impl Modify for SecurityAddon {
                     ),
                 ),
             );
+            components.add_security_scheme(
+                "api_key",
+                utoipa::openapi::security::SecurityScheme::ApiKey(
+                    utoipa::openapi::security::ApiKey::Header(
+                        utoipa::openapi::security::ApiKeyValue::new("X-API-Key"),
+                    ),
+                ),
+            );
+            components.add_security_scheme(
+                "session_cookie",
+                utoipa::openapi::security::SecurityScheme::ApiKey(
+                    utoipa::openapi::security::ApiKey::Cookie(
+                        utoipa::openapi::security::ApiKeyValue::new("access_token"),
+                    ),
+                ),
+            );
+            // Flujo password de OAuth2 contra `/token`, para el botón "Authorize" de Swagger UI
+            components.add_security_scheme(
+                "oauth2",
+                utoipa::openapi::security::SecurityScheme::OAuth2(
+                    utoipa::openapi::security::OAuth2::new([
+                        utoipa::openapi::security::Flow::Password(
+                            utoipa::openapi::security::Password::new(
+                                "/token",
+                                utoipa::openapi::security::Scopes::new(),
+                            ),
+                        ),
+                    ]),
+                ),
+            );
+        }
+
+        // Todas las rutas que aceptan un Bearer aceptan también el token de ese flujo y la
+        // cookie de sesión
+        for path in openapi.paths.paths.values_mut() {
+            for operation in path.operations.values_mut() {
+                if let Some(security) = operation.security.as_mut() {
+                    for scheme in ["oauth2", "session_cookie"] {
+                        security.push(utoipa::openapi::security::SecurityRequirement::new(
+                            scheme,
+                            Vec::<String>::new(),
+                        ));
+                    }
+                }
+            }
         }
     }
 }
@@ -86,20 +236,34 @@ async fn main() -> anyhow::Result<()> {
     let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
     let pool = db::establish_connection(&database_url).await?;
 
+    // Emails guardados antes de normalizarlos en la aplicación (falla si dos cuentas coinciden)
+    email::normalize_stored_emails(&pool).await?;
+
+    // Comandos de administración (p. ej. `set-role`) en lugar de levantar el servidor
+    let args: Vec<String> = env::args().skip(1).collect();
+    if !args.is_empty() {
+        return cli::run(&pool, &args).await;
+    }
+
+    // Las exportaciones que quedaron pendientes al parar el proceso anterior no terminarán
+    export::fail_interrupted(&pool).await?;
+
     // Crear app
-    let app = create_app(pool);
+    let state = state::AppState::new(pool).await?;
+    let app = create_app(state);
 
     // Iniciar servidor
     let addr = SocketAddr::from(([0, 0, 0, 0], 8000));
     tracing::info!("listening on {}", addr);
     
     let listener = tokio::net::TcpListener::bind(addr).await?;
-    axum::serve(listener, app).await?;
+    // `ConnectInfo` da la IP del cliente al control de logins fallidos
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await?;
 
     Ok(())
 }
 
-pub fn create_app(pool: sqlx::SqlitePool) -> Router {
+pub fn create_app(state: state::AppState) -> Router {
     // Configurar CORS
     let cors = CorsLayer::new()
         .allow_origin(Any)
@@ -112,8 +276,59 @@ pub fn create_app(pool: sqlx::SqlitePool) -> Router {
         // Rutas públicas
         .route("/", get(|| async { "Axum Backend is running!" }))
         .route("/users/", post(handlers::auth::register))
+        .route("/users/verify", get(handlers::users::verify_email))
+        .route(
+            "/users/email/confirm",
+            get(handlers::users::open_email_change_link).post(handlers::users::confirm_email_change),
+        )
         .route("/token", post(handlers::auth::login))
+        .route("/token/mfa", post(handlers::auth::login_mfa))
+        .route("/token/refresh", post(handlers::auth::refresh))
+        .route("/login/magic-link", post(handlers::magic_link::request_magic_link))
+        .route(
+            "/login/magic-link/exchange",
+            get(handlers::magic_link::open_magic_link).post(handlers::magic_link::exchange_magic_link),
+        )
+        .route("/auth/oidc/:provider/authorize", get(handlers::oidc::oidc_authorize))
+        .route("/auth/oidc/:provider/callback", get(handlers::oidc::oidc_callback))
+        .route("/.well-known/jwks.json", get(handlers::auth::jwks))
+        .route("/logout", post(handlers::auth::logout))
+        .route("/logout/all", post(handlers::auth::logout_all))
+        .route("/password/forgot", post(handlers::password::forgot_password))
+        .route("/password/reset", post(handlers::password::reset_password))
+        .route("/exports/:id", get(handlers::export::download_export_link))
         // Rutas protegidas
+        .route("/users/me", get(handlers::users::get_me))
+        .route("/users/me", patch(handlers::users::update_me))
+        .route("/users/me", delete(handlers::users::delete_me))
+        .route("/users/me/export", get(handlers::export::export_me))
+        .route("/users/me/exports/:id", get(handlers::export::download_export))
+        .route("/users/me/verify/resend", post(handlers::users::resend_verification))
+        .route("/users/me/deactivate", post(handlers::users::deactivate_me))
+        .route("/users/me/password", put(handlers::users::change_password))
+        .route("/users/me/email", post(handlers::users::request_email_change))
+        .route("/users/me/mfa/totp", post(handlers::mfa::enroll_totp))
+        .route("/users/me/mfa/totp", delete(handlers::mfa::disable_totp))
+        .route("/users/me/mfa/totp/confirm", post(handlers::mfa::confirm_totp))
+        .route("/users/me/api-keys", post(handlers::api_keys::create_api_key))
+        .route("/users/me/api-keys", get(handlers::api_keys::list_api_keys))
+        .route("/users/me/api-keys/:id", patch(handlers::api_keys::rename_api_key))
+        .route("/users/me/api-keys/:id", delete(handlers::api_keys::revoke_api_key))
+        .route("/users/me/sessions", get(handlers::sessions::list_sessions))
+        .route("/users/me/sessions/:id", delete(handlers::sessions::revoke_session))
+        .route("/users/me/activity", get(handlers::audit::list_my_activity))
+        .route("/auth/oidc/:provider/link", post(handlers::oidc::oidc_link))
+        .route("/admin/users/:id/deactivate", post(handlers::admin::deactivate_user))
+        .route("/admin/users/:id/reactivate", post(handlers::admin::reactivate_user))
+        .route("/admin/users/:id/unlock", post(handlers::admin::unlock_user))
+        .route("/admin/users/:id/impersonate", post(handlers::admin::impersonate_user))
+        .route("/admin/users", get(handlers::admin::list_users))
+        .route("/admin/users/:id/role", put(handlers::admin::set_user_role))
+        .route("/admin/users/:id/tasks", get(handlers::admin::list_user_tasks))
+        .route("/admin/audit", get(handlers::audit::list_audit_events))
+        .route("/admin/tasks/:id", get(handlers::admin::get_task))
+        .route("/admin/tasks/:id", put(handlers::admin::update_task))
+        .route("/admin/tasks/:id", delete(handlers::admin::delete_task))
         .route("/tasks/", post(handlers::tasks::create_task))
         .route("/tasks/", get(handlers::tasks::get_tasks))
         .route("/tasks/:id", get(handlers::tasks::get_task))
@@ -122,5 +337,5 @@ pub fn create_app(pool: sqlx::SqlitePool) -> Router {
         // Middleware
         .layer(TraceLayer::new_for_http())
         .layer(cors)
-        .with_state(pool)
+        .with_state(state)
 }
@@ -0,0 +1,165 @@
+use chrono::Utc;
+use rand_core::{OsRng, RngCore};
+use sqlx::{SqliteConnection, SqlitePool};
+use subtle::ConstantTimeEq;
+use totp_rs::{Algorithm, Secret, TOTP};
+
+use crate::{error::AppError, tokens};
+
+pub const TOTP_DIGITS: usize = 6;
+pub const TOTP_STEP_SECS: u64 = 30;
+// Pasos de tolerancia a cada lado por desfase de reloj
+pub const TOTP_SKEW: u64 = 1;
+pub const RECOVERY_CODE_COUNT: usize = 10;
+
+pub const ISSUER: &str = "AxumBackend";
+
+fn build_totp(secret: Vec<u8>, account_name: &str) -> Result<TOTP, AppError> {
+    TOTP::new(
+        Algorithm::SHA1,
+        TOTP_DIGITS,
+        TOTP_SKEW as u8,
+        TOTP_STEP_SECS,
+        secret,
+        Some(ISSUER.to_string()),
+        account_name.to_string(),
+    )
+    .map_err(|e| AppError::InternalError(format!("Cannot build TOTP: {}", e)))
+}
+
+/// Genera un secreto TOTP nuevo (160 bits, base32) y su URI `otpauth://`.
+pub fn generate_secret(account_name: &str) -> Result<(String, String), AppError> {
+    let mut bytes = [0u8; 20];
+    OsRng.fill_bytes(&mut bytes);
+
+    let totp = build_totp(bytes.to_vec(), account_name)?;
+
+    Ok((totp.get_secret_base32(), totp.get_url()))
+}
+
+/// Devuelve el paso temporal en el que `code` es válido, si lo es. Se prueban los pasos
+/// dentro de la tolerancia; los pasos <= `last_used_step` se descartan para impedir replays.
+pub fn verify_code(
+    secret_base32: &str,
+    code: &str,
+    last_used_step: Option<i64>,
+) -> Result<Option<i64>, AppError> {
+    let secret = Secret::Encoded(secret_base32.to_string())
+        .to_bytes()
+        .map_err(|_| AppError::InternalError("Invalid TOTP secret in DB".to_string()))?;
+    let totp = build_totp(secret, "")?;
+    let current_step = (tokens::now_secs() as u64 / TOTP_STEP_SECS) as i64;
+
+    for step in (current_step - TOTP_SKEW as i64)..=(current_step + TOTP_SKEW as i64) {
+        if last_used_step.is_some_and(|last| step <= last) {
+            continue;
+        }
+
+        let expected = totp.generate(step as u64 * TOTP_STEP_SECS);
+        if bool::from(expected.as_bytes().ct_eq(code.trim().as_bytes())) {
+            return Ok(Some(step));
+        }
+    }
+
+    Ok(None)
+}
+
+const RECOVERY_CODE_ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+/// Código de recuperación legible de 50 bits, p. ej. `k3j7x-q2m7d`.
+fn generate_recovery_code() -> String {
+    let mut bytes = [0u8; 10];
+    OsRng.fill_bytes(&mut bytes);
+
+    let raw: String = bytes
+        .iter()
+        .map(|b| RECOVERY_CODE_ALPHABET[(b & 31) as usize] as char)
+        .collect();
+
+    format!("{}-{}", &raw[..5], &raw[5..])
+}
+
+/// Sustituye los códigos de recuperación del usuario y devuelve los nuevos en claro.
+/// Va en la transacción del llamador, junto a la activación del TOTP.
+pub async fn regenerate_recovery_codes(
+    conn: &mut SqliteConnection,
+    user_id: i64,
+) -> Result<Vec<String>, AppError> {
+    let codes: Vec<String> = (0..RECOVERY_CODE_COUNT)
+        .map(|_| generate_recovery_code())
+        .collect();
+
+    sqlx::query("DELETE FROM mfa_recovery_codes WHERE user_id = ?")
+        .bind(user_id)
+        .execute(&mut *conn)
+        .await?;
+
+    for code in &codes {
+        sqlx::query("INSERT INTO mfa_recovery_codes (user_id, code_hash) VALUES (?, ?)")
+            .bind(user_id)
+            .bind(tokens::hash_token(code))
+            .execute(&mut *conn)
+            .await?;
+    }
+
+    Ok(codes)
+}
+
+/// Indica si el usuario tiene un segundo factor TOTP confirmado.
+pub async fn is_enabled(pool: &SqlitePool, user_id: i64) -> Result<bool, AppError> {
+    let enabled = sqlx::query(
+        "SELECT 1 FROM user_totp WHERE user_id = ? AND confirmed_at IS NOT NULL",
+    )
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await?
+    .is_some();
+
+    Ok(enabled)
+}
+
+/// Valida un segundo factor de un usuario con TOTP confirmado: un código TOTP
+/// (que no haya sido usado antes) o un código de recuperación sin usar.
+pub async fn verify_second_factor(
+    pool: &SqlitePool,
+    user_id: i64,
+    code: &str,
+) -> Result<bool, AppError> {
+    let row: Option<(String, Option<i64>)> = sqlx::query_as(
+        "SELECT secret, last_used_step FROM user_totp WHERE user_id = ? AND confirmed_at IS NOT NULL",
+    )
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await?;
+
+    let Some((secret, last_used_step)) = row else {
+        return Ok(false);
+    };
+
+    if let Some(step) = verify_code(&secret, code, last_used_step)? {
+        // Registrar el paso solo si nadie lo ha hecho antes (condición de carrera)
+        let result = sqlx::query(
+            "UPDATE user_totp SET last_used_step = ?
+            WHERE user_id = ? AND (last_used_step IS NULL OR last_used_step < ?)",
+        )
+        .bind(step)
+        .bind(user_id)
+        .bind(step)
+        .execute(pool)
+        .await?;
+
+        return Ok(result.rows_affected() == 1);
+    }
+
+    let result = sqlx::query(
+        "UPDATE mfa_recovery_codes SET used_at = ?
+        WHERE user_id = ? AND code_hash = ? AND used_at IS NULL",
+    )
+    .bind(Utc::now().naive_utc())
+    .bind(user_id)
+    .bind(tokens::hash_token(code.trim()))
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected() == 1)
+}
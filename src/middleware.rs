@@ -1,65 +1,292 @@
 use axum::{
     async_trait,
-    extract::{FromRequestParts, FromRef},
+    extract::{ConnectInfo, FromRequestParts, FromRef},
     http::{request::Parts, header},
 };
-use jsonwebtoken::{decode, DecodingKey, Validation};
-use sqlx::SqlitePool;
+use std::marker::PhantomData;
+use std::net::{IpAddr, SocketAddr};
 
 use crate::{
+    api_keys,
+    audit,
+    config::{Config, UnverifiedAction},
+    cookies,
     error::AppError,
-    models::{Claims, User},
+    impersonation,
+    models::{AuditAction, Claims, Role, User},
+    sessions,
+    state::AppState,
+    tokens,
 };
 
+pub const API_KEY_HEADER: &str = "x-api-key";
+
+/// Credencial con la que se autenticó la petición.
+pub enum Credential {
+    Jwt(Claims),
+    // El scope de la clave ya se aplica al autenticar (ver `api_keys::permits`)
+    ApiKey,
+}
+
+/// Usuario autenticado junto con la credencial presentada.
+pub struct AuthContext {
+    pub user: User,
+    pub credential: Credential,
+}
+
+impl AuthContext {
+    /// Claims del JWT; las operaciones de sesión (logout...) no aceptan API keys.
+    pub fn claims(&self) -> Result<&Claims, AppError> {
+        match &self.credential {
+            Credential::Jwt(claims) => Ok(claims),
+            Credential::ApiKey => Err(AppError::Forbidden(
+                "This operation requires a session token".to_string(),
+            )),
+        }
+    }
+}
+
 pub struct CurrentUser(pub User);
 
+/// Datos del cliente que hace la petición. La IP es la del socket, o la primera de
+/// `X-Forwarded-For` si `TRUST_FORWARDED_FOR` está activo; `None` si no se puede determinar.
+#[derive(Debug, Clone, Default)]
+pub struct ClientInfo {
+    pub ip: Option<IpAddr>,
+    pub user_agent: Option<String>,
+}
+
+/// Comprueba que el usuario pueda realizar `action`: siempre si ya verificó su email,
+/// y si no solo cuando la acción está en `UNVERIFIED_ALLOWED_ACTIONS`.
+pub fn require_verified(config: &Config, user: &User, action: UnverifiedAction) -> Result<(), AppError> {
+    if user.email_verified_at.is_some() || config.unverified_actions.contains(&action) {
+        Ok(())
+    } else {
+        Err(AppError::EmailNotVerified("Email address not verified".to_string()))
+    }
+}
+
+/// Marca de tipo para exigir un rol concreto con `RequireRole<R>`.
+pub trait RoleRequirement {
+    const ROLE: Role;
+}
+
+pub struct Admin;
+
+impl RoleRequirement for Admin {
+    const ROLE: Role = Role::Admin;
+}
+
+/// Usuario autenticado cuyo rol satisface `R` (p. ej. `RequireRole<Admin>`).
+/// Se exige tanto el rol firmado en el token como el actual de la DB: quitar un rol
+/// aplica de inmediato y uno nuevo requiere volver a iniciar sesión.
+pub struct RequireRole<R: RoleRequirement>(pub User, pub PhantomData<R>);
+
 #[async_trait]
-impl<S> FromRequestParts<S> for CurrentUser
+impl<S> FromRequestParts<S> for AuthContext
 where
-    SqlitePool: FromRef<S>,
+    AppState: FromRef<S>,
     S: Send + Sync,
 {
     type Rejection = AppError;
 
     async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
-        // 1. Extraer el token del header Authorization
-        let auth_header = parts
-            .headers
-            .get(header::AUTHORIZATION)
-            .ok_or(AppError::AuthError("Missing Authorization header".to_string()))?
-            .to_str()
-            .map_err(|_| AppError::AuthError("Invalid Authorization header".to_string()))?;
+        let state = AppState::from_ref(state);
 
-        if !auth_header.starts_with("Bearer ") {
-            return Err(AppError::AuthError("Invalid token format".to_string()));
+        // 1. API key en `X-API-Key`, si viene
+        if let Some(key) = parts.headers.get(API_KEY_HEADER) {
+            let key = key
+                .to_str()
+                .map_err(|_| AppError::AuthError("Invalid X-API-Key header".to_string()))?;
+            return authenticate_api_key(parts, &state, key).await;
         }
 
-        let token = &auth_header[7..];
+        // 2. Extraer el token del header Authorization o, en navegadores, de la cookie
+        let (token, from_cookie) = match parts.headers.get(header::AUTHORIZATION) {
+            Some(auth_header) => {
+                let auth_header = auth_header
+                    .to_str()
+                    .map_err(|_| AppError::AuthError("Invalid Authorization header".to_string()))?;
+
+                let token = auth_header
+                    .strip_prefix("Bearer ")
+                    .ok_or(AppError::AuthError("Invalid token format".to_string()))?;
 
-        // 2. Obtener el SECRET_KEY del entorno
-        let secret = std::env::var("SECRET_KEY").unwrap_or_else(|_| "secret".to_string());
+                // Las API keys también pueden enviarse como Bearer
+                if token.starts_with(api_keys::KEY_PREFIX) {
+                    return authenticate_api_key(parts, &state, token).await;
+                }
 
-        // 3. Decodificar el token
-        let token_data = decode::<Claims>(
-            token,
-            &DecodingKey::from_secret(secret.as_bytes()),
-            &Validation::default(),
-        )
-        .map_err(|e| AppError::AuthError(format!("Invalid token: {}", e)))?;
+                (token, false)
+            }
+            None => match cookies::get(&parts.headers, cookies::ACCESS_COOKIE) {
+                Some(token) => (token, true),
+                None => return Err(AppError::AuthError("Missing Authorization header".to_string())),
+            },
+        };
 
-        // 4. Obtener el usuario de la DB usando el state
-        let pool = SqlitePool::from_ref(state);
+        // 3. Decodificar el token; con cookie, exigir el token CSRF si se modifica estado
+        let claims = tokens::decode_access_token(&state.keys, token)?;
+        if from_cookie {
+            cookies::verify_csrf(&parts.headers, &parts.method, &claims)?;
+        }
+
+        // 4. Rechazar tokens revocados explícitamente (logout)
+        if state.revocations.is_revoked(&claims.jti) {
+            return Err(AppError::AuthError("Token has been revoked".to_string()));
+        }
 
-        let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE email = ?")
-            .bind(token_data.claims.sub)
-            .fetch_optional(&pool)
+        // 5. Obtener el usuario de la DB (`sub` es su id, que no cambia)
+        let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = ?")
+            .bind(tokens::subject_user_id(&claims.sub)?)
+            .fetch_optional(&state.pool)
             .await
-            .map_err(AppError::SqlxError)?;
+            .map_err(AppError::SqlxError)?
+            .ok_or(AppError::AuthError("User not found".to_string()))?;
 
-        if let Some(user) = user {
-            Ok(CurrentUser(user))
-        } else {
-            Err(AppError::AuthError("User not found".to_string()))
+        // 6. Rechazar cuentas desactivadas
+        if !user.is_active {
+            return Err(AppError::AccountDisabled("Account is deactivated".to_string()));
+        }
+
+        // 7. Rechazar tokens emitidos antes del último "logout all"
+        if let Some(valid_after) = user.tokens_valid_after {
+            if (claims.iat as i64) < valid_after {
+                return Err(AppError::AuthError("Token has been revoked".to_string()));
+            }
+        }
+
+        // 8. Rechazar tokens de sesiones revocadas (y registrar la actividad)
+        sessions::touch(&state.pool, user.id, claims.sid).await?;
+
+        // 9. Suplantación: limitar lo que puede hacer el admin y dejar rastro de cada petición
+        if let Some(actor) = &claims.act {
+            let admin = impersonation::check_actor(&state.pool, actor).await?;
+            impersonation::permits(actor, &parts.method, parts.uri.path())?;
+
+            tracing::warn!(
+                impersonator = %admin.email,
+                user = %user.email,
+                method = %parts.method,
+                path = %parts.uri.path(),
+                "Impersonated request"
+            );
+            // Sin rastro en la auditoría no se atiende la petición
+            let client = <ClientInfo as FromRequestParts<AppState>>::from_request_parts(parts, &state).await?;
+            audit::record(
+                &state.pool,
+                &client,
+                AuditAction::ImpersonatedRequest,
+                Some(user.id),
+                Some(admin.id),
+                serde_json::json!({ "method": parts.method.as_str(), "path": parts.uri.path() }),
+            )
+            .await?;
+        }
+
+        Ok(AuthContext {
+            user,
+            credential: Credential::Jwt(claims),
+        })
+    }
+}
+
+async fn authenticate_api_key(
+    parts: &Parts,
+    state: &AppState,
+    key: &str,
+) -> Result<AuthContext, AppError> {
+    let (user, scope) = api_keys::authenticate(&state.pool, key).await?;
+
+    if !user.is_active {
+        return Err(AppError::AccountDisabled("Account is deactivated".to_string()));
+    }
+
+    api_keys::permits(scope, &parts.method, parts.uri.path())?;
+
+    Ok(AuthContext {
+        user,
+        credential: Credential::ApiKey,
+    })
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for CurrentUser
+where
+    AppState: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let auth = AuthContext::from_request_parts(parts, state).await?;
+
+        Ok(CurrentUser(auth.user))
+    }
+}
+
+#[async_trait]
+impl<S, R> FromRequestParts<S> for RequireRole<R>
+where
+    AppState: FromRef<S>,
+    S: Send + Sync,
+    R: RoleRequirement,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let auth = AuthContext::from_request_parts(parts, state).await?;
+        let user = auth.user;
+
+        // Las API keys no llevan rol propio: solo cuenta el de la DB
+        let token_role = match &auth.credential {
+            Credential::Jwt(claims) => claims.role,
+            Credential::ApiKey => user.role,
+        };
+
+        if !user.role.satisfies(R::ROLE) || !token_role.satisfies(R::ROLE) {
+            return Err(AppError::Forbidden("Insufficient role".to_string()));
         }
+
+        Ok(RequireRole(user, PhantomData))
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for ClientInfo
+where
+    AppState: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let state = AppState::from_ref(state);
+
+        let user_agent = parts
+            .headers
+            .get(header::USER_AGENT)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string);
+
+        let forwarded = if state.config.trust_forwarded_for {
+            parts
+                .headers
+                .get("x-forwarded-for")
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.split(',').next())
+                .and_then(|ip| ip.trim().parse().ok())
+        } else {
+            None
+        };
+
+        let ip = forwarded.or_else(|| {
+            parts
+                .extensions
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|ConnectInfo(addr)| addr.ip())
+        });
+
+        Ok(ClientInfo { ip, user_agent })
     }
 }
@@ -1,10 +1,40 @@
 use serde::{Deserialize, Serialize};
 use sqlx::FromRow;
 use chrono::NaiveDateTime;
-use utoipa::ToSchema;
+use utoipa::{IntoParams, ToSchema};
 
 // --- Domain Models (Mapped to DB) ---
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type, ToSchema)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
+pub enum Role {
+    User,
+    Admin,
+}
+
+impl Role {
+    /// Un rol satisface a otro si es igual o superior (admin incluye los permisos de user).
+    pub fn satisfies(self, required: Role) -> bool {
+        match required {
+            Role::User => true,
+            Role::Admin => self == Role::Admin,
+        }
+    }
+}
+
+impl std::str::FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "user" => Ok(Role::User),
+            "admin" => Ok(Role::Admin),
+            other => Err(format!("Unknown role: {}", other)),
+        }
+    }
+}
+
 #[derive(Debug, Serialize, FromRow, Clone, ToSchema)]
 pub struct User {
     pub id: i64, // SQLite uses INTEGER which maps to i64
@@ -12,6 +42,14 @@ pub struct User {
     #[serde(skip)] // No serializar el hash en la respuesta JSON
     pub hashed_password: String,
     pub is_active: bool,
+    pub role: Role,
+    pub email_verified_at: Option<NaiveDateTime>,
+    #[serde(skip)]
+    pub tokens_valid_after: Option<i64>,
+    pub display_name: Option<String>,
+    pub timezone: Option<String>, // Zona horaria IANA, p. ej. "Europe/Madrid"
+    pub locale: Option<String>,   // Etiqueta BCP 47, p. ej. "es-ES"
+    pub avatar_url: Option<String>,
 }
 
 #[derive(Debug, Serialize, FromRow, ToSchema)]
@@ -24,6 +62,141 @@ pub struct Task {
     pub owner_id: i64,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type, ToSchema)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(rename_all = "snake_case")]
+pub enum ApiKeyScope {
+    Read,
+    ReadWrite,
+}
+
+#[derive(Debug, Serialize, FromRow, ToSchema)]
+pub struct ApiKey {
+    pub id: i64,
+    pub name: String,
+    pub prefix: String,
+    pub scope: ApiKeyScope,
+    pub created_at: NaiveDateTime,
+    pub expires_at: Option<NaiveDateTime>,
+    pub last_used_at: Option<NaiveDateTime>,
+    pub revoked_at: Option<NaiveDateTime>,
+}
+
+/// Sesión abierta por un login, una por dispositivo.
+#[derive(Debug, Serialize, FromRow, ToSchema)]
+pub struct Session {
+    pub id: i64,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub last_seen_at: NaiveDateTime,
+    pub revoked_at: Option<NaiveDateTime>,
+    /// La sesión del token con el que se hace la petición
+    #[sqlx(skip)]
+    pub current: bool,
+}
+
+/// Cuenta externa (OIDC) vinculada al usuario.
+#[derive(Debug, Serialize, FromRow, ToSchema)]
+pub struct LinkedIdentity {
+    pub provider: String,
+    pub subject: String,
+    pub email: Option<String>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type, ToSchema)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(rename_all = "snake_case")]
+pub enum ExportStatus {
+    Pending,
+    Ready,
+    Failed,
+}
+
+/// Exportación de datos generada en segundo plano.
+#[derive(Debug, Serialize, FromRow, ToSchema)]
+pub struct DataExport {
+    pub id: i64,
+    pub status: ExportStatus,
+    pub created_at: NaiveDateTime,
+    pub completed_at: Option<NaiveDateTime>,
+    pub expires_at: NaiveDateTime,
+    /// Ruta autenticada desde la que se descarga (cuando `status` es `ready`)
+    #[sqlx(skip)]
+    pub download_url: String,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct ExportDownloadParams {
+    /// Token del enlace recibido en el correo
+    pub token: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type, ToSchema)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(rename_all = "snake_case")]
+pub enum AuditAction {
+    UserRegistered,
+    IdentityLinked,
+    LoginSucceeded,
+    LoginFailed,
+    LoggedOut,
+    LoggedOutEverywhere,
+    SessionRevoked,
+    TokenRefreshed,
+    RefreshTokenReused,
+    MfaEnabled,
+    MfaDisabled,
+    ApiKeyCreated,
+    ApiKeyRevoked,
+    PasswordChanged,
+    PasswordReset,
+    EmailChangeRequested,
+    EmailChanged,
+    AccountDeactivated,
+    AccountDeleted,
+    RoleChanged,
+    UserDeactivated,
+    UserReactivated,
+    UserUnlocked,
+    ImpersonationStarted,
+    ImpersonatedRequest,
+    TaskUpdatedByAdmin,
+    TaskDeletedByAdmin,
+}
+
+/// Entrada del registro de auditoría.
+#[derive(Debug, Serialize, FromRow, ToSchema)]
+pub struct AuditEvent {
+    pub id: i64,
+    pub action: AuditAction,
+    /// Cuenta a la que se refiere el evento
+    pub user_id: Option<i64>,
+    /// Quién lo hizo: el propio usuario, un admin, o nadie autenticado
+    pub actor_id: Option<i64>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    /// Detalles del evento (email, motivo, rol...)
+    #[sqlx(json)]
+    #[schema(value_type = Object)]
+    pub payload: serde_json::Value,
+    pub created_at: NaiveDateTime,
+}
+
+/// Todo lo que guardamos de un usuario (exportación de datos personales).
+#[derive(Debug, Serialize, ToSchema)]
+pub struct UserExport {
+    pub exported_at: NaiveDateTime,
+    pub user: User,
+    pub mfa_enabled: bool,
+    pub tasks: Vec<Task>,
+    pub sessions: Vec<Session>,
+    pub api_keys: Vec<ApiKey>,
+    pub identities: Vec<LinkedIdentity>,
+    pub activity: Vec<AuditEvent>,
+}
+
 // --- Request/Response DTOs ---
 
 #[derive(Debug, Deserialize, ToSchema)]
@@ -32,16 +205,237 @@ pub struct CreateUser {
     pub password: String,
 }
 
+/// Petición a `/token` (RFC 6749): `grant_type=password` con `username` y `password`,
+/// o `grant_type=refresh_token` con `refresh_token`. Sin `grant_type` se asume `password`.
 #[derive(Debug, Deserialize, ToSchema)]
-pub struct LoginRequest {
-    pub username: String, // FastAPI OAuth2PasswordRequestForm usa 'username' para el email
-    pub password: String,
+pub struct TokenRequest {
+    pub grant_type: Option<String>,
+    pub username: Option<String>, // FastAPI OAuth2PasswordRequestForm usa 'username' para el email
+    pub password: Option<String>,
+    pub refresh_token: Option<String>,
+    /// Entregar los tokens en cookies HttpOnly (clientes de navegador)
+    #[serde(default)]
+    pub cookie: bool,
 }
 
 #[derive(Debug, Serialize, ToSchema)]
 pub struct Token {
     pub access_token: String,
     pub token_type: String,
+    /// Segundos de validez del access token.
+    pub expires_in: usize,
+    pub refresh_token: String,
+}
+
+/// Respuesta de login cuando la cuenta tiene segundo factor: el `mfa_token` se canjea
+/// en `/token/mfa` junto con un código TOTP o de recuperación.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct MfaChallenge {
+    pub mfa_required: bool,
+    pub mfa_token: String,
+    pub token_type: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(untagged)]
+pub enum LoginResponse {
+    Token(Token),
+    MfaChallenge(MfaChallenge),
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct MfaLoginRequest {
+    pub mfa_token: String,
+    pub code: String,
+    /// Entregar los tokens en cookies HttpOnly, como en `/token`
+    #[serde(default)]
+    pub cookie: bool,
+}
+
+/// Login con cookies: los tokens van en cookies HttpOnly y el cliente solo recibe el
+/// token CSRF, que debe enviar en `X-CSRF-Token` en las peticiones que modifican estado.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct CookieSession {
+    pub token_type: String,
+    pub expires_in: usize,
+    pub csrf_token: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct TotpEnrollment {
+    pub secret: String, // base32, para introducirlo a mano en la app
+    pub otpauth_uri: String,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct TotpCode {
+    pub code: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct RecoveryCodes {
+    pub recovery_codes: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct RefreshRequest {
+    /// Sin él se usa la cookie del refresh token y la respuesta renueva las cookies
+    pub refresh_token: Option<String>,
+    /// Entregar los tokens en cookies HttpOnly, como en `/token`
+    #[serde(default)]
+    pub cookie: bool,
+}
+
+#[derive(Debug, Default, Deserialize, ToSchema)]
+pub struct LogoutRequest {
+    /// Refresh token de la sesión a cerrar; si se envía se revoca también su familia
+    pub refresh_token: Option<String>,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct VerifyEmailParams {
+    /// Token recibido en el correo de verificación
+    pub token: String,
+}
+
+#[derive(Debug, Default, Deserialize, IntoParams)]
+pub struct OidcAuthorizeParams {
+    /// Terminar el login en una sesión de cookies HttpOnly, como `/token` con `cookie=true`
+    #[serde(default)]
+    pub cookie: bool,
+}
+
+/// Parámetros con los que el proveedor OIDC vuelve al callback.
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct OidcCallbackParams {
+    pub code: Option<String>,
+    pub state: Option<String>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct MagicLinkRequest {
+    pub email: String,
+}
+
+#[derive(Debug, Deserialize, ToSchema, IntoParams)]
+pub struct MagicLinkExchange {
+    /// Token recibido en el enlace del correo
+    pub token: String,
+    /// Entregar los tokens en cookies HttpOnly, como en `/token`
+    #[serde(default)]
+    pub cookie: bool,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct MagicLinkParams {
+    /// Token recibido en el enlace del correo
+    pub token: String,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct ForgotPasswordRequest {
+    pub email: String,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct ResetPasswordRequest {
+    pub token: String,
+    pub new_password: String,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct ChangePasswordRequest {
+    pub current_password: String,
+    pub new_password: String,
+}
+
+/// Filtros de `GET /admin/audit`; todos opcionales y combinables.
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct AuditQuery {
+    pub user_id: Option<i64>,
+    pub actor_id: Option<i64>,
+    pub action: Option<AuditAction>,
+    pub ip: Option<String>,
+    /// Solo eventos desde esta fecha (incluida)
+    pub since: Option<NaiveDateTime>,
+    /// Solo eventos anteriores a esta fecha
+    pub until: Option<NaiveDateTime>,
+    pub skip: Option<i64>,
+    /// Por defecto 100, como mucho 500
+    pub limit: Option<i64>,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct ChangeEmailRequest {
+    pub new_email: String,
+    /// Obligatorio si la cuenta tiene password local
+    pub password: Option<String>,
+}
+
+#[derive(Debug, Deserialize, ToSchema, IntoParams)]
+pub struct ConfirmEmailChangeRequest {
+    /// Token recibido en el correo enviado a la dirección nueva
+    pub token: String,
+}
+
+/// Campos ausentes no se modifican; un string vacío borra el valor.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct UpdateProfile {
+    pub display_name: Option<String>,
+    pub timezone: Option<String>,
+    pub locale: Option<String>,
+    pub avatar_url: Option<String>,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct DeleteAccountRequest {
+    /// Obligatorio si la cuenta tiene password local
+    pub password: Option<String>,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct ImpersonateRequest {
+    /// Motivo (p. ej. el ticket de soporte); queda en el log de auditoría
+    pub reason: String,
+    /// Permitir editar y borrar tareas (`PUT`, `PATCH`, `DELETE`) con el token (por defecto no)
+    #[serde(default)]
+    pub allow_destructive: bool,
+}
+
+/// Access token de corta duración que actúa como otro usuario; no tiene refresh token.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ImpersonationToken {
+    pub access_token: String,
+    pub token_type: String,
+    pub expires_in: usize,
+    pub user: User,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct UpdateRole {
+    pub role: Role,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct CreateApiKey {
+    pub name: String,
+    pub scope: ApiKeyScope,
+    /// Días de validez; sin valor la clave no expira
+    pub expires_in_days: Option<i64>,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct UpdateApiKey {
+    pub name: String,
+}
+
+/// La clave en claro solo se devuelve al crearla.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct CreatedApiKey {
+    pub key: String,
+    #[serde(flatten)]
+    pub api_key: ApiKey,
 }
 
 #[derive(Debug, Deserialize, ToSchema)]
@@ -61,6 +455,34 @@ pub struct UpdateTask {
 // Claims para JWT
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Claims {
-    pub sub: String, // Email del usuario
+    pub sub: String, // Id del usuario (el email puede cambiar)
+    pub exp: usize,
+    pub iat: usize,
+    pub jti: String, // Identificador único, usado para revocar el token
+    pub role: Role,
+    pub sid: i64, // Sesión (dispositivo) a la que pertenece el token
+    /// Admin que actúa en nombre del usuario (suplantación, RFC 8693 `act`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub act: Option<Actor>,
+    /// Hash del token CSRF, en los tokens entregados como cookie
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub csrf: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Actor {
+    pub sub: String, // Id del admin
+    /// Permite editar y borrar tareas durante la suplantación
+    #[serde(default)]
+    pub allow_destructive: bool,
+}
+
+// Claims del token intermedio de login con segundo factor (aud = "mfa")
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MfaClaims {
+    pub sub: String, // Id del usuario
     pub exp: usize,
+    pub iat: usize,
+    pub jti: String,
+    pub aud: String,
 }
@@ -0,0 +1,136 @@
+use axum::{
+    async_trait,
+    extract::{FromRequest, Request},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Form, Json,
+};
+use serde::de::DeserializeOwned;
+use serde_json::json;
+
+use crate::error::AppError;
+
+/// Error del endpoint de tokens con la forma de RFC 6749 §5.2:
+/// `{"error": "invalid_grant", "error_description": "..."}`.
+#[derive(Debug)]
+pub struct OAuthError {
+    status: StatusCode,
+    error: &'static str,
+    description: String,
+    retry_after: Option<u64>,
+}
+
+impl OAuthError {
+    fn new(status: StatusCode, error: &'static str, description: impl Into<String>) -> Self {
+        Self {
+            status,
+            error,
+            description: description.into(),
+            retry_after: None,
+        }
+    }
+
+    pub fn invalid_request(description: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, "invalid_request", description)
+    }
+
+    pub fn unsupported_grant_type(grant_type: &str) -> Self {
+        Self::new(
+            StatusCode::BAD_REQUEST,
+            "unsupported_grant_type",
+            format!("Unsupported grant_type: {}", grant_type),
+        )
+    }
+}
+
+impl From<AppError> for OAuthError {
+    fn from(err: AppError) -> Self {
+        match err {
+            AppError::AuthError(msg) => Self::new(StatusCode::BAD_REQUEST, "invalid_grant", msg),
+            // Se conservan los códigos propios (403, 423, 429) para que el cliente pueda
+            // distinguir estos casos de un password erróneo
+            AppError::AccountDisabled(msg) | AppError::EmailNotVerified(msg) => {
+                Self::new(StatusCode::FORBIDDEN, "invalid_grant", msg)
+            }
+            AppError::AccountLocked(secs) => Self {
+                retry_after: Some(secs),
+                ..Self::new(StatusCode::LOCKED, "invalid_grant", "Account temporarily locked")
+            },
+            AppError::TooManyRequests(secs) => Self {
+                retry_after: Some(secs),
+                ..Self::new(
+                    StatusCode::TOO_MANY_REQUESTS,
+                    "invalid_request",
+                    "Too many failed login attempts",
+                )
+            },
+            AppError::InvalidPassword(_) => Self::invalid_request(err.to_string()),
+            AppError::ValidationError(msg)
+            | AppError::Forbidden(msg)
+            | AppError::NotFound(msg) => Self::invalid_request(msg),
+            AppError::SqlxError(e) => {
+                tracing::error!("Database error: {}", e);
+                Self::new(StatusCode::INTERNAL_SERVER_ERROR, "server_error", "Database error")
+            }
+            AppError::InternalError(msg) => {
+                tracing::error!("Internal error: {}", msg);
+                Self::new(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "server_error",
+                    "Internal server error",
+                )
+            }
+        }
+    }
+}
+
+impl IntoResponse for OAuthError {
+    fn into_response(self) -> Response {
+        let body = Json(json!({
+            "error": self.error,
+            "error_description": self.description,
+        }));
+
+        let mut response = (self.status, [(header::CACHE_CONTROL, "no-store")], body).into_response();
+        if let Some(secs) = self.retry_after {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, secs.to_string().parse().unwrap());
+        }
+
+        response
+    }
+}
+
+/// Cuerpo aceptado como `application/x-www-form-urlencoded` (lo que envían los clientes
+/// OAuth2) o como JSON, según el `Content-Type`.
+pub struct JsonOrForm<T>(pub T);
+
+#[async_trait]
+impl<S, T> FromRequest<S> for JsonOrForm<T>
+where
+    S: Send + Sync,
+    T: DeserializeOwned,
+{
+    type Rejection = OAuthError;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let is_form = req
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.starts_with("application/x-www-form-urlencoded"));
+
+        if is_form {
+            let Form(value) = Form::<T>::from_request(req, state)
+                .await
+                .map_err(|e| OAuthError::invalid_request(e.body_text()))?;
+            Ok(JsonOrForm(value))
+        } else {
+            let Json(value) = Json::<T>::from_request(req, state)
+                .await
+                .map_err(|e| OAuthError::invalid_request(e.body_text()))?;
+            Ok(JsonOrForm(value))
+        }
+    }
+}
@@ -0,0 +1,513 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{Duration, Utc};
+use jsonwebtoken::{decode, decode_header, jwk::JwkSet, Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use sqlx::SqlitePool;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration as StdDuration, Instant};
+
+use crate::{
+    audit,
+    config::OidcProviderConfig,
+    email,
+    error::AppError,
+    middleware::ClientInfo,
+    models::{AuditAction, Role, User},
+    revocation::RevocationStore,
+    tokens,
+};
+
+pub const AUTH_REQUEST_TTL_MINUTES: i64 = 10;
+
+// Límite de cada petición al proveedor (discovery, token, JWKS)
+const HTTP_CONNECT_TIMEOUT: StdDuration = StdDuration::from_secs(5);
+const HTTP_TIMEOUT: StdDuration = StdDuration::from_secs(10);
+// Un `kid` desconocido vuelve a pedir el JWKS como mucho una vez por este intervalo,
+// para que tokens con `kid` inventados no sirvan para saturar al proveedor
+const JWKS_MIN_REFRESH_INTERVAL: StdDuration = StdDuration::from_secs(60);
+
+// Algoritmos aceptados en los ID tokens: solo asimétricos
+const ID_TOKEN_ALGORITHMS: [Algorithm; 7] = [
+    Algorithm::RS256,
+    Algorithm::RS384,
+    Algorithm::RS512,
+    Algorithm::PS256,
+    Algorithm::ES256,
+    Algorithm::ES384,
+    Algorithm::EdDSA,
+];
+
+/// Campos del documento de discovery que usamos.
+#[derive(Debug, Clone, Deserialize)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+/// Claims del ID token que usamos para identificar al usuario.
+#[derive(Debug, Deserialize)]
+pub struct IdTokenClaims {
+    pub sub: String,
+    pub email: Option<String>,
+    // Algunos proveedores lo envían como string
+    email_verified: Option<serde_json::Value>,
+    nonce: Option<String>,
+}
+
+impl IdTokenClaims {
+    /// Email del usuario si el proveedor afirma haberlo verificado.
+    pub fn verified_email(&self) -> Option<&str> {
+        let verified = matches!(
+            &self.email_verified,
+            Some(serde_json::Value::Bool(true))
+        ) || matches!(&self.email_verified, Some(serde_json::Value::String(s)) if s == "true");
+
+        self.email.as_deref().filter(|_| verified)
+    }
+}
+
+/// Resultado del callback: el ID token validado y las opciones con las que se inició el flujo.
+pub struct CompletedAuthorization {
+    pub claims: IdTokenClaims,
+    /// Cuenta a la que vincular la identidad (flujo iniciado desde una sesión)
+    pub link_user_id: Option<i64>,
+    /// Entregar la sesión en cookies
+    pub cookie: bool,
+}
+
+/// Discovery y JWKS de un issuer, con el momento en que se pidieron las claves.
+#[derive(Clone)]
+struct CachedProvider {
+    metadata: ProviderMetadata,
+    jwks: JwkSet,
+    keys_fetched_at: Instant,
+}
+
+/// Cliente OIDC compartido: cachea el discovery y el JWKS de cada issuer.
+#[derive(Clone)]
+pub struct OidcClient {
+    http: reqwest::Client,
+    // issuer -> discovery y claves
+    cache: Arc<RwLock<HashMap<String, CachedProvider>>>,
+}
+
+impl Default for OidcClient {
+    fn default() -> Self {
+        let http = reqwest::Client::builder()
+            .connect_timeout(HTTP_CONNECT_TIMEOUT)
+            .timeout(HTTP_TIMEOUT)
+            .build()
+            .expect("Cannot build the OIDC HTTP client");
+
+        Self { http, cache: Default::default() }
+    }
+}
+
+fn upstream_error(what: &str, e: impl std::fmt::Display) -> AppError {
+    AppError::InternalError(format!("OIDC {} failed: {}", what, e))
+}
+
+/// `code_challenge` S256 de PKCE (RFC 7636).
+pub fn pkce_challenge(verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()))
+}
+
+impl OidcClient {
+    async fn fetch_json<T: serde::de::DeserializeOwned>(&self, what: &str, url: &str) -> Result<T, AppError> {
+        self.http
+            .get(url)
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(|e| upstream_error(what, e))?
+            .json()
+            .await
+            .map_err(|e| upstream_error(what, e))
+    }
+
+    /// Discovery + JWKS del proveedor; con `refresh_keys` se vuelve a pedir el JWKS
+    /// (el proveedor puede haber rotado sus claves), salvo que se pidiera hace menos de
+    /// `JWKS_MIN_REFRESH_INTERVAL`.
+    async fn provider(
+        &self,
+        provider: &OidcProviderConfig,
+        refresh_keys: bool,
+    ) -> Result<(ProviderMetadata, JwkSet), AppError> {
+        let cached = self
+            .cache
+            .read()
+            .expect("oidc cache poisoned")
+            .get(&provider.issuer)
+            .cloned();
+
+        let metadata = match cached {
+            Some(entry) if !refresh_keys || entry.keys_fetched_at.elapsed() < JWKS_MIN_REFRESH_INTERVAL => {
+                return Ok((entry.metadata, entry.jwks))
+            }
+            Some(entry) => entry.metadata,
+            None => {
+                let url = format!("{}/.well-known/openid-configuration", provider.issuer);
+                let metadata: ProviderMetadata = self.fetch_json("discovery", &url).await?;
+
+                if metadata.issuer.trim_end_matches('/') != provider.issuer {
+                    return Err(upstream_error("discovery", "issuer mismatch"));
+                }
+                metadata
+            }
+        };
+
+        let jwks: JwkSet = self.fetch_json("JWKS", &metadata.jwks_uri).await?;
+        let entry = CachedProvider { metadata, jwks, keys_fetched_at: Instant::now() };
+        self.cache
+            .write()
+            .expect("oidc cache poisoned")
+            .insert(provider.issuer.clone(), entry.clone());
+
+        Ok((entry.metadata, entry.jwks))
+    }
+
+    /// Inicia el flujo authorization code + PKCE: guarda `state`, `nonce` y el verificador
+    /// y devuelve la URL del proveedor a la que redirigir al usuario junto con el `state`,
+    /// que el navegador debe guardar en su cookie. Con `link_user_id` el flujo vincula la
+    /// identidad a esa cuenta en lugar de buscarla por email; con `cookie` el callback
+    /// entrega una sesión de cookies.
+    pub async fn authorization_url(
+        &self,
+        pool: &SqlitePool,
+        provider: &OidcProviderConfig,
+        redirect_uri: &str,
+        link_user_id: Option<i64>,
+        cookie: bool,
+    ) -> Result<(String, String), AppError> {
+        let (metadata, _) = self.provider(provider, false).await?;
+
+        let state = tokens::generate_opaque_token();
+        let nonce = tokens::generate_opaque_token();
+        let code_verifier = tokens::generate_opaque_token();
+        let expires_at = (Utc::now() + Duration::minutes(AUTH_REQUEST_TTL_MINUTES)).naive_utc();
+
+        sqlx::query(
+            "INSERT INTO oidc_auth_requests (state_hash, provider, nonce, code_verifier, expires_at, link_user_id, cookie)
+            VALUES (?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(tokens::hash_token(&state))
+        .bind(&provider.name)
+        .bind(&nonce)
+        .bind(&code_verifier)
+        .bind(expires_at)
+        .bind(link_user_id)
+        .bind(cookie)
+        .execute(pool)
+        .await?;
+
+        let mut url = url::Url::parse(&metadata.authorization_endpoint)
+            .map_err(|e| upstream_error("discovery", e))?;
+        url.query_pairs_mut()
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &provider.client_id)
+            .append_pair("redirect_uri", redirect_uri)
+            .append_pair("scope", &provider.scopes)
+            .append_pair("state", &state)
+            .append_pair("nonce", &nonce)
+            .append_pair("code_challenge", &pkce_challenge(&code_verifier))
+            .append_pair("code_challenge_method", "S256");
+
+        Ok((url.into(), state))
+    }
+
+    /// Completa el flujo: consume el `state`, canjea el código y valida el ID token.
+    pub async fn complete(
+        &self,
+        pool: &SqlitePool,
+        provider: &OidcProviderConfig,
+        redirect_uri: &str,
+        code: &str,
+        state: &str,
+    ) -> Result<CompletedAuthorization, AppError> {
+        // El state es de un solo uso y ligado al proveedor que lo emitió
+        let (nonce, code_verifier, link_user_id, cookie): (String, String, Option<i64>, bool) = sqlx::query_as(
+            "DELETE FROM oidc_auth_requests
+            WHERE state_hash = ? AND provider = ? AND expires_at > ?
+            RETURNING nonce, code_verifier, link_user_id, cookie",
+        )
+        .bind(tokens::hash_token(state))
+        .bind(&provider.name)
+        .bind(Utc::now().naive_utc())
+        .fetch_optional(pool)
+        .await?
+        .ok_or(AppError::AuthError("Invalid or expired OIDC state".to_string()))?;
+
+        let (metadata, _) = self.provider(provider, false).await?;
+
+        let mut form = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", redirect_uri),
+            ("client_id", provider.client_id.as_str()),
+            ("code_verifier", code_verifier.as_str()),
+        ];
+        if let Some(secret) = &provider.client_secret {
+            form.push(("client_secret", secret));
+        }
+
+        let response = self
+            .http
+            .post(&metadata.token_endpoint)
+            .form(&form)
+            .send()
+            .await
+            .map_err(|e| upstream_error("token request", e))?;
+
+        if !response.status().is_success() {
+            return Err(AppError::AuthError(format!(
+                "OIDC provider rejected the authorization code ({})",
+                response.status()
+            )));
+        }
+
+        let token: TokenResponse = response
+            .json()
+            .await
+            .map_err(|e| upstream_error("token request", e))?;
+
+        let claims = self.validate_id_token(provider, &token.id_token).await?;
+
+        if claims.nonce.as_deref() != Some(nonce.as_str()) {
+            return Err(AppError::AuthError("Invalid ID token nonce".to_string()));
+        }
+
+        Ok(CompletedAuthorization { claims, link_user_id, cookie })
+    }
+
+    async fn validate_id_token(
+        &self,
+        provider: &OidcProviderConfig,
+        id_token: &str,
+    ) -> Result<IdTokenClaims, AppError> {
+        let invalid = |e: &dyn std::fmt::Display| AppError::AuthError(format!("Invalid ID token: {}", e));
+
+        let header = decode_header(id_token).map_err(|e| invalid(&e))?;
+        if !ID_TOKEN_ALGORITHMS.contains(&header.alg) {
+            return Err(invalid(&"unsupported algorithm"));
+        }
+        let kid = header.kid.ok_or_else(|| invalid(&"missing kid"))?;
+
+        let (metadata, mut jwks) = self.provider(provider, false).await?;
+        if jwks.find(&kid).is_none() {
+            (_, jwks) = self.provider(provider, true).await?;
+        }
+        let jwk = jwks.find(&kid).ok_or_else(|| invalid(&"unknown kid"))?;
+        let key = DecodingKey::from_jwk(jwk).map_err(|e| invalid(&e))?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&metadata.issuer]);
+        validation.set_audience(&[&provider.client_id]);
+
+        decode::<IdTokenClaims>(id_token, &key, &validation)
+            .map(|data| data.claims)
+            .map_err(|e| invalid(&e))
+    }
+}
+
+/// Usuario local de una identidad externa: la ya vinculada, o se vincula por email
+/// (solo si el proveedor lo verificó) o, si se permite, se crea una cuenta nueva.
+pub async fn find_or_provision_user(
+    pool: &SqlitePool,
+    client: &ClientInfo,
+    provider: &OidcProviderConfig,
+    claims: &IdTokenClaims,
+) -> Result<User, AppError> {
+    let linked = sqlx::query_as::<_, User>(
+        "SELECT users.* FROM users
+        JOIN user_identities ON user_identities.user_id = users.id
+        WHERE user_identities.provider = ? AND user_identities.subject = ?",
+    )
+    .bind(&provider.name)
+    .bind(&claims.sub)
+    .fetch_optional(pool)
+    .await?;
+
+    if let Some(user) = linked {
+        return Ok(user);
+    }
+
+    // Sin email verificado no se puede vincular ni crear la cuenta con seguridad
+    let email = claims.verified_email().ok_or(AppError::AuthError(
+        "OIDC provider did not return a verified email".to_string(),
+    ))?;
+    let email = &email::normalize_email(email)?;
+
+    let now = Utc::now().naive_utc();
+    let mut tx = pool.begin().await?;
+
+    let existing = sqlx::query_as::<_, User>("SELECT * FROM users WHERE email = ?")
+        .bind(email)
+        .fetch_optional(&mut *tx)
+        .await?;
+
+    let mut credentials_cleared = false;
+    let user_id = match existing {
+        // Un admin solo vincula identidades explícitamente, desde una sesión ya iniciada
+        Some(user) if user.role.satisfies(Role::Admin) => {
+            return Err(AppError::Forbidden(format!(
+                "Admin accounts must link this identity from a signed-in session (POST /auth/oidc/{}/link)",
+                provider.name
+            )))
+        }
+        Some(user) if user.email_verified_at.is_none() => {
+            // Nadie ha demostrado controlar el email: quien registró la cuenta puede ser un
+            // atacante esperando a que el dueño real entre por SSO. Se le retira todo acceso.
+            sqlx::query("UPDATE users SET hashed_password = '' WHERE id = ?")
+                .bind(user.id)
+                .execute(&mut *tx)
+                .await?;
+            for table in ["user_totp", "mfa_recovery_codes"] {
+                sqlx::query(&format!("DELETE FROM {} WHERE user_id = ?", table))
+                    .bind(user.id)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            sqlx::query("UPDATE email_change_tokens SET used_at = ? WHERE user_id = ? AND used_at IS NULL")
+                .bind(now)
+                .bind(user.id)
+                .execute(&mut *tx)
+                .await?;
+            RevocationStore::revoke_all_in(&mut tx, user.id).await?;
+
+            tracing::warn!(
+                "Cleared credentials of unverified user {} before linking {} identity {}",
+                user.id,
+                provider.name,
+                claims.sub
+            );
+            credentials_cleared = true;
+            user.id
+        }
+        Some(user) => user.id,
+        None if provider.allow_signup => {
+            // Cuenta sin password local (el hash vacío nunca verifica)
+            let id = sqlx::query("INSERT INTO users (email, hashed_password) VALUES (?, '')")
+                .bind(email)
+                .execute(&mut *tx)
+                .await?
+                .last_insert_rowid();
+            audit::record(
+                &mut *tx,
+                client,
+                AuditAction::UserRegistered,
+                Some(id),
+                Some(id),
+                serde_json::json!({ "email": email, "provider": provider.name }),
+            )
+            .await?;
+            id
+        }
+        None => {
+            return Err(AppError::Forbidden(
+                "No account for this email and sign-up via this provider is disabled".to_string(),
+            ))
+        }
+    };
+
+    sqlx::query("INSERT INTO user_identities (user_id, provider, subject, email) VALUES (?, ?, ?, ?)")
+        .bind(user_id)
+        .bind(&provider.name)
+        .bind(&claims.sub)
+        .bind(email)
+        .execute(&mut *tx)
+        .await?;
+    audit::record(
+        &mut *tx,
+        client,
+        AuditAction::IdentityLinked,
+        Some(user_id),
+        Some(user_id),
+        serde_json::json!({
+            "provider": provider.name,
+            "subject": claims.sub,
+            "credentials_cleared": credentials_cleared,
+        }),
+    )
+    .await?;
+
+    // El proveedor ya verificó el email
+    let user = sqlx::query_as::<_, User>(
+        "UPDATE users SET email_verified_at = COALESCE(email_verified_at, ?)
+        WHERE id = ? RETURNING *",
+    )
+    .bind(now)
+    .bind(user_id)
+    .fetch_one(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    tracing::info!("Linked {} identity {} to user {}", provider.name, claims.sub, user.id);
+
+    Ok(user)
+}
+
+/// Vincula una identidad externa a `user_id`, que inició el flujo desde su sesión. No
+/// hace falta que coincida el email: el usuario ya demostró controlar las dos cuentas.
+pub async fn link_identity(
+    pool: &SqlitePool,
+    client: &ClientInfo,
+    provider: &OidcProviderConfig,
+    claims: &IdTokenClaims,
+    user_id: i64,
+) -> Result<User, AppError> {
+    let linked_to: Option<i64> =
+        sqlx::query_scalar("SELECT user_id FROM user_identities WHERE provider = ? AND subject = ?")
+            .bind(&provider.name)
+            .bind(&claims.sub)
+            .fetch_optional(pool)
+            .await?;
+
+    match linked_to {
+        Some(id) if id != user_id => {
+            return Err(AppError::Forbidden(
+                "This identity is already linked to another account".to_string(),
+            ))
+        }
+        Some(_) => {}
+        None => {
+            let email = claims.email.as_deref().and_then(|e| email::normalize_email(e).ok());
+            let mut tx = pool.begin().await?;
+            sqlx::query("INSERT INTO user_identities (user_id, provider, subject, email) VALUES (?, ?, ?, ?)")
+                .bind(user_id)
+                .bind(&provider.name)
+                .bind(&claims.sub)
+                .bind(email)
+                .execute(&mut *tx)
+                .await?;
+            audit::record(
+                &mut *tx,
+                client,
+                AuditAction::IdentityLinked,
+                Some(user_id),
+                Some(user_id),
+                serde_json::json!({ "provider": provider.name, "subject": claims.sub }),
+            )
+            .await?;
+            tx.commit().await?;
+
+            tracing::info!("Linked {} identity {} to user {}", provider.name, claims.sub, user_id);
+        }
+    }
+
+    let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = ?")
+        .bind(user_id)
+        .fetch_one(pool)
+        .await?;
+
+    Ok(user)
+}
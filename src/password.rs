@@ -0,0 +1,71 @@
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Algorithm, Argon2, Params, Version,
+};
+use rand_core::OsRng;
+
+use crate::{config::Argon2Config, error::AppError};
+
+fn argon2(config: &Argon2Config) -> Result<Argon2<'static>, AppError> {
+    let params = config
+        .params()
+        .map_err(|e| AppError::InternalError(format!("Invalid Argon2 parameters: {}", e)))?;
+
+    Ok(Argon2::new(Algorithm::Argon2id, Version::V0x13, params))
+}
+
+// Hashes bcrypt importados de la base de datos de FastAPI (passlib)
+fn is_bcrypt(hashed_password: &str) -> bool {
+    ["$2a$", "$2b$", "$2y$"]
+        .iter()
+        .any(|prefix| hashed_password.starts_with(prefix))
+}
+
+/// Hash Argon2id (formato PHC) de una contraseña con salt aleatorio.
+pub fn hash_password(config: &Argon2Config, password: &str) -> Result<String, AppError> {
+    let salt = SaltString::generate(&mut OsRng);
+
+    argon2(config)?
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| AppError::ValidationError(e.to_string()))
+}
+
+/// Verifica una contraseña contra el hash guardado en la DB (Argon2 con los parámetros
+/// que lleve el hash, o bcrypt heredado).
+pub fn verify_password(password: &str, hashed_password: &str) -> Result<(), AppError> {
+    if is_bcrypt(hashed_password) {
+        return match bcrypt::verify(password, hashed_password) {
+            Ok(true) => Ok(()),
+            _ => Err(AppError::AuthError("Invalid credentials".to_string())),
+        };
+    }
+
+    let parsed_hash = PasswordHash::new(hashed_password)
+        .map_err(|_| AppError::AuthError("Invalid password hash in DB".to_string()))?;
+
+    Argon2::default()
+        .verify_password(password.as_bytes(), &parsed_hash)
+        .map_err(|_| AppError::AuthError("Invalid credentials".to_string()))
+}
+
+/// Indica si un hash (ya verificado) debe rehacerse con la configuración actual:
+/// otro algoritmo o costes de Argon2 menores que los configurados.
+pub fn needs_rehash(config: &Argon2Config, hashed_password: &str) -> bool {
+    let Ok(parsed_hash) = PasswordHash::new(hashed_password) else {
+        return true;
+    };
+
+    if parsed_hash.algorithm != Algorithm::Argon2id.ident() {
+        return true;
+    }
+
+    match Params::try_from(&parsed_hash) {
+        Ok(params) => {
+            params.m_cost() < config.memory_kib
+                || params.t_cost() < config.iterations
+                || params.p_cost() < config.parallelism
+        }
+        Err(_) => true,
+    }
+}
@@ -0,0 +1,128 @@
+use serde::Serialize;
+use sha1::{Digest, Sha1};
+use utoipa::ToSchema;
+
+use crate::{config::PasswordPolicy, error::AppError};
+
+/// Regla de la política que incumple una contraseña.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct PasswordViolation {
+    /// `too_short`, `too_long`, `missing_character_classes`, `contains_email` o `breached`.
+    pub code: &'static str,
+    pub message: String,
+}
+
+impl PasswordViolation {
+    fn new(code: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+/// Clases presentes entre minúsculas, mayúsculas, dígitos y el resto de símbolos.
+fn character_classes(password: &str) -> usize {
+    let checks: [fn(char) -> bool; 4] = [
+        char::is_lowercase,
+        char::is_uppercase,
+        |c| c.is_ascii_digit(),
+        |c| !c.is_alphanumeric(),
+    ];
+
+    checks
+        .iter()
+        .filter(|check| password.chars().any(**check))
+        .count()
+}
+
+/// Busca la contraseña en un volcado offline de contraseñas filtradas con el formato
+/// por rangos de Have I Been Pwned: un fichero por prefijo de 5 caracteres del SHA-1
+/// (`<dir>/ABCDE` o `<dir>/ABCDE.txt`) con líneas `SUFIJO:apariciones`.
+async fn is_breached(dir: &std::path::Path, password: &str) -> Result<bool, AppError> {
+    let hash: String = Sha1::digest(password.as_bytes())
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect();
+    let (prefix, suffix) = hash.split_at(5);
+
+    for name in [prefix.to_string(), format!("{}.txt", prefix)] {
+        match tokio::fs::read_to_string(dir.join(name)).await {
+            Ok(contents) => {
+                return Ok(contents.lines().any(|line| {
+                    line.split(':')
+                        .next()
+                        .is_some_and(|s| s.trim().eq_ignore_ascii_case(suffix))
+                }))
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => {
+                return Err(AppError::InternalError(format!(
+                    "Cannot read breached password file: {}",
+                    e
+                )))
+            }
+        }
+    }
+
+    Ok(false)
+}
+
+/// Aplica la política a una contraseña nueva (registro, cambio y reset) y devuelve
+/// todas las reglas incumplidas a la vez.
+pub async fn validate(policy: &PasswordPolicy, password: &str, email: &str) -> Result<(), AppError> {
+    let mut violations = Vec::new();
+    let length = password.chars().count();
+
+    if length < policy.min_length {
+        violations.push(PasswordViolation::new(
+            "too_short",
+            format!("Password must be at least {} characters long", policy.min_length),
+        ));
+    }
+
+    if length > policy.max_length {
+        violations.push(PasswordViolation::new(
+            "too_long",
+            format!("Password must be at most {} characters long", policy.max_length),
+        ));
+    }
+
+    if character_classes(password) < policy.min_character_classes {
+        violations.push(PasswordViolation::new(
+            "missing_character_classes",
+            format!(
+                "Password must mix at least {} of: lowercase, uppercase, digits, symbols",
+                policy.min_character_classes
+            ),
+        ));
+    }
+
+    let lowered = password.to_lowercase();
+    let email = email.trim().to_lowercase();
+    let local_part = email.split('@').next().unwrap_or_default();
+    if !email.is_empty() && (lowered == email || lowered == local_part) {
+        violations.push(PasswordViolation::new(
+            "contains_email",
+            "Password must not be your email address",
+        ));
+    }
+
+    // Solo se consulta el volcado si la contraseña pasa el resto de reglas
+    if violations.is_empty() {
+        if let Some(dir) = &policy.breached_passwords_dir {
+            if is_breached(dir, password).await? {
+                violations.push(PasswordViolation::new(
+                    "breached",
+                    "Password appears in a known data breach",
+                ));
+            }
+        }
+    }
+
+    if violations.is_empty() {
+        Ok(())
+    } else {
+        Err(AppError::InvalidPassword(violations))
+    }
+}
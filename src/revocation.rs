@@ -0,0 +1,113 @@
+use chrono::Utc;
+use sqlx::{SqliteConnection, SqlitePool};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use crate::{error::AppError, tokens};
+
+/// Registro de access tokens revocados. La tabla `revoked_tokens` es la fuente de verdad;
+/// la caché en memoria evita una consulta por cada petición autenticada.
+#[derive(Clone)]
+pub struct RevocationStore {
+    pool: SqlitePool,
+    // jti -> exp (epoch seconds)
+    revoked: Arc<RwLock<HashMap<String, usize>>>,
+}
+
+impl RevocationStore {
+    /// Carga en memoria los tokens revocados que aún no han expirado.
+    pub async fn load(pool: SqlitePool) -> Result<Self, sqlx::Error> {
+        let now = tokens::now_secs() as i64;
+
+        sqlx::query("DELETE FROM revoked_tokens WHERE expires_at <= ?")
+            .bind(now)
+            .execute(&pool)
+            .await?;
+
+        let rows: Vec<(String, i64)> = sqlx::query_as("SELECT jti, expires_at FROM revoked_tokens")
+            .fetch_all(&pool)
+            .await?;
+
+        let revoked = rows
+            .into_iter()
+            .map(|(jti, exp)| (jti, exp as usize))
+            .collect();
+
+        Ok(Self {
+            pool,
+            revoked: Arc::new(RwLock::new(revoked)),
+        })
+    }
+
+    pub fn is_revoked(&self, jti: &str) -> bool {
+        self.revoked
+            .read()
+            .expect("revocation cache poisoned")
+            .contains_key(jti)
+    }
+
+    /// Revoca un access token concreto hasta su expiración.
+    pub async fn revoke(&self, jti: &str, user_id: i64, exp: usize) -> Result<(), AppError> {
+        sqlx::query("INSERT OR IGNORE INTO revoked_tokens (jti, user_id, expires_at) VALUES (?, ?, ?)")
+            .bind(jti)
+            .bind(user_id)
+            .bind(exp as i64)
+            .execute(&self.pool)
+            .await?;
+
+        let now = tokens::now_secs();
+        let mut revoked = self.revoked.write().expect("revocation cache poisoned");
+        // Aprovechamos para descartar entradas que ya expiraron por sí solas
+        revoked.retain(|_, expires_at| *expires_at > now);
+        revoked.insert(jti.to_string(), exp);
+
+        Ok(())
+    }
+
+    /// Invalida todos los tokens del usuario: los access tokens emitidos hasta ahora
+    /// (vía `tokens_valid_after`), todos sus refresh tokens, sus sesiones y sus API keys.
+    pub async fn revoke_all_for_user(&self, user_id: i64) -> Result<(), AppError> {
+        let mut tx = self.pool.begin().await?;
+        Self::revoke_all_in(&mut tx, user_id).await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    /// Igual que `revoke_all_for_user`, dentro de la transacción que hace el cambio que
+    /// la motiva (p. ej. el password nuevo): o se aplican los dos o ninguno.
+    pub async fn revoke_all_in(conn: &mut SqliteConnection, user_id: i64) -> Result<(), AppError> {
+        // `iat` tiene resolución de segundos: se corta en el segundo siguiente para incluir
+        // los tokens emitidos en el segundo actual (ver `tokens::create_access_token`).
+        let valid_after = tokens::now_secs() as i64 + 1;
+
+        sqlx::query("UPDATE users SET tokens_valid_after = ? WHERE id = ?")
+            .bind(valid_after)
+            .bind(user_id)
+            .execute(&mut *conn)
+            .await?;
+
+        let now = Utc::now().naive_utc();
+        sqlx::query("UPDATE refresh_tokens SET revoked_at = ? WHERE user_id = ? AND revoked_at IS NULL")
+            .bind(now)
+            .bind(user_id)
+            .execute(&mut *conn)
+            .await?;
+
+        sqlx::query("UPDATE sessions SET revoked_at = ? WHERE user_id = ? AND revoked_at IS NULL")
+            .bind(now)
+            .bind(user_id)
+            .execute(&mut *conn)
+            .await?;
+
+        // Una clave que siga viva mantendría el acceso tras un reset de password o un
+        // "cerrar todas las sesiones": se revocan también
+        sqlx::query("UPDATE api_keys SET revoked_at = ? WHERE user_id = ? AND revoked_at IS NULL")
+            .bind(now)
+            .bind(user_id)
+            .execute(&mut *conn)
+            .await?;
+
+        Ok(())
+    }
+}
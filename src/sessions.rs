@@ -0,0 +1,138 @@
+use chrono::{Duration, NaiveDateTime, Utc};
+use sqlx::{FromRow, SqliteConnection, SqlitePool};
+
+use crate::{
+    error::AppError,
+    middleware::ClientInfo,
+    models::Session,
+    tokens::{self, REFRESH_TOKEN_TTL_DAYS},
+};
+
+// No se actualiza `last_seen_at` más de una vez por minuto y sesión
+const LAST_SEEN_RESOLUTION_SECS: i64 = 60;
+// Se trunca el user agent que envía el cliente
+pub(crate) const MAX_USER_AGENT_LEN: usize = 255;
+
+pub(crate) const SESSION_COLUMNS: &str = "id, user_agent, ip, created_at, last_seen_at, revoked_at";
+
+#[derive(Debug, FromRow)]
+struct SessionState {
+    last_seen_at: NaiveDateTime,
+    revoked_at: Option<NaiveDateTime>,
+}
+
+/// Crea la sesión de un login nuevo y devuelve `(id, family_id)`: la familia de
+/// refresh tokens que la mantendrá viva.
+pub async fn create(pool: &SqlitePool, user_id: i64, client: &ClientInfo) -> Result<(i64, String), AppError> {
+    let family_id = tokens::generate_opaque_token();
+    let user_agent = client
+        .user_agent
+        .as_deref()
+        .map(|ua| ua.chars().take(MAX_USER_AGENT_LEN).collect::<String>());
+
+    let id = sqlx::query("INSERT INTO sessions (user_id, family_id, user_agent, ip) VALUES (?, ?, ?, ?)")
+        .bind(user_id)
+        .bind(&family_id)
+        .bind(user_agent)
+        .bind(client.ip.map(|ip| ip.to_string()))
+        .execute(pool)
+        .await?
+        .last_insert_rowid();
+
+    Ok((id, family_id))
+}
+
+/// Sesión viva de una familia de refresh tokens, usada al rotar.
+pub async fn find_by_family(pool: &SqlitePool, family_id: &str) -> Result<Option<i64>, AppError> {
+    let id = sqlx::query_scalar("SELECT id FROM sessions WHERE family_id = ? AND revoked_at IS NULL")
+        .bind(family_id)
+        .fetch_optional(pool)
+        .await?;
+
+    Ok(id)
+}
+
+/// Comprueba que la sesión de un access token siga viva y registra la actividad.
+pub async fn touch(pool: &SqlitePool, user_id: i64, session_id: i64) -> Result<(), AppError> {
+    let state = sqlx::query_as::<_, SessionState>(
+        "SELECT last_seen_at, revoked_at FROM sessions WHERE id = ? AND user_id = ?",
+    )
+    .bind(session_id)
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await?;
+
+    let state = match state {
+        Some(state) if state.revoked_at.is_none() => state,
+        _ => return Err(AppError::AuthError("Session has been revoked".to_string())),
+    };
+
+    let now = Utc::now().naive_utc();
+    if state.last_seen_at < now - Duration::seconds(LAST_SEEN_RESOLUTION_SECS) {
+        sqlx::query("UPDATE sessions SET last_seen_at = ? WHERE id = ?")
+            .bind(now)
+            .bind(session_id)
+            .execute(pool)
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Sesiones activas del usuario: no revocadas y con actividad dentro de la vida de
+/// un refresh token (pasado ese plazo ya no pueden renovarse).
+pub async fn list(pool: &SqlitePool, user_id: i64) -> Result<Vec<Session>, AppError> {
+    let since = (Utc::now() - Duration::days(REFRESH_TOKEN_TTL_DAYS)).naive_utc();
+
+    let sessions = sqlx::query_as::<_, Session>(&format!(
+        "SELECT {} FROM sessions
+        WHERE user_id = ? AND revoked_at IS NULL AND last_seen_at > ?
+        ORDER BY last_seen_at DESC, id DESC",
+        SESSION_COLUMNS
+    ))
+    .bind(user_id)
+    .bind(since)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(sessions)
+}
+
+/// Revoca una sesión del usuario junto con sus refresh tokens; los access tokens que
+/// la referencian dejan de aceptarse en `touch`. Devuelve `false` si no existe o ya
+/// estaba revocada.
+pub async fn revoke(pool: &SqlitePool, user_id: i64, session_id: i64) -> Result<bool, AppError> {
+    let mut tx = pool.begin().await?;
+    let revoked = revoke_in(&mut tx, user_id, session_id).await?;
+    tx.commit().await?;
+
+    Ok(revoked)
+}
+
+/// Igual que `revoke`, dentro de la transacción del llamador (p. ej. con su auditoría).
+pub async fn revoke_in(conn: &mut SqliteConnection, user_id: i64, session_id: i64) -> Result<bool, AppError> {
+    let now = Utc::now().naive_utc();
+
+    let family_id: Option<String> = sqlx::query_scalar(
+        "UPDATE sessions SET revoked_at = ?
+        WHERE id = ? AND user_id = ? AND revoked_at IS NULL
+        RETURNING family_id",
+    )
+    .bind(now)
+    .bind(session_id)
+    .bind(user_id)
+    .fetch_optional(&mut *conn)
+    .await?;
+
+    let Some(family_id) = family_id else {
+        return Ok(false);
+    };
+
+    sqlx::query("UPDATE refresh_tokens SET revoked_at = ? WHERE family_id = ? AND revoked_at IS NULL")
+        .bind(now)
+        .bind(&family_id)
+        .execute(&mut *conn)
+        .await?;
+
+    Ok(true)
+}
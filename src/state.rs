@@ -0,0 +1,46 @@
+use axum::extract::FromRef;
+use sqlx::SqlitePool;
+use std::sync::Arc;
+
+use crate::{
+    config::Config,
+    keys::JwtKeys,
+    login_attempts::{self, LoginAttemptStore},
+    mailer::{self, Mailer},
+    oidc::OidcClient,
+    revocation::RevocationStore,
+};
+
+#[derive(Clone, FromRef)]
+pub struct AppState {
+    pub pool: SqlitePool,
+    pub config: Arc<Config>,
+    pub keys: Arc<JwtKeys>,
+    pub revocations: RevocationStore,
+    pub mailer: Arc<dyn Mailer>,
+    pub login_attempts: Arc<dyn LoginAttemptStore>,
+    pub oidc: OidcClient,
+}
+
+impl AppState {
+    /// Estado con las claves JWT de la configuración; falla si no hay ninguna.
+    pub async fn new(pool: SqlitePool) -> anyhow::Result<Self> {
+        let keys = JwtKeys::from_env()?;
+        Self::with_keys(pool, keys).await
+    }
+
+    pub async fn with_keys(pool: SqlitePool, keys: JwtKeys) -> anyhow::Result<Self> {
+        let revocations = RevocationStore::load(pool.clone()).await?;
+        let login_attempts = login_attempts::from_env(pool.clone());
+
+        Ok(Self {
+            pool,
+            config: Arc::new(Config::from_env()),
+            keys: Arc::new(keys),
+            revocations,
+            mailer: mailer::from_env(),
+            login_attempts,
+            oidc: OidcClient::default(),
+        })
+    }
+}
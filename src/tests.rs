@@ -3,41 +3,172 @@ use axum::{
     http::{Request, StatusCode},
 };
 use serde_json::json;
-use sqlx::sqlite::SqlitePoolOptions;
+use sqlx::{sqlite::SqlitePoolOptions, SqlitePool};
+use std::sync::Arc;
 use tower::ServiceExt; // for `oneshot`
 
-use crate::create_app;
+use crate::{
+    config::{Config, UnverifiedAction},
+    create_app,
+    keys::JwtKeys,
+    mailer::MemoryMailer,
+    state::AppState,
+};
+
+/// Claves de firma de los tests: Ed25519 activa y una RSA "anterior" aún aceptada.
+fn test_keys() -> JwtKeys {
+    JwtKeys::from_pem(
+        include_bytes!("../tests/fixtures/jwt_ed25519.pem"),
+        include_bytes!("../tests/fixtures/jwt_ed25519.pub.pem"),
+        &[include_bytes!("../tests/fixtures/jwt_rsa.pub.pem").to_vec()],
+    )
+    .expect("Invalid test keys")
+}
 
 async fn setup_app() -> axum::Router {
-    // In-memory SQLite database for testing
+    setup().await.0
+}
+
+async fn setup() -> (axum::Router, SqlitePool) {
+    let (app, pool, _) = setup_with_mailer().await;
+    (app, pool)
+}
+
+async fn setup_with_mailer() -> (axum::Router, SqlitePool, MemoryMailer) {
+    setup_with_config(|_| {}).await
+}
+
+async fn setup_with_config(
+    configure: impl FnOnce(&mut Config),
+) -> (axum::Router, SqlitePool, MemoryMailer) {
+    // In-memory SQLite database for testing.
+    // Each connection to `sqlite::memory:` is a separate database, so keep a single one alive.
     let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .idle_timeout(None)
+        .max_lifetime(None)
         .connect("sqlite::memory:")
         .await
         .expect("Failed to create in-memory database");
 
     // Run migrations
-    sqlx::query(
-        "CREATE TABLE users (
-            id INTEGER PRIMARY KEY AUTOINCREMENT,
-            email TEXT NOT NULL UNIQUE,
-            hashed_password TEXT NOT NULL,
-            is_active BOOLEAN NOT NULL DEFAULT 1
-        );
-        CREATE TABLE tasks (
-            id INTEGER PRIMARY KEY AUTOINCREMENT,
-            title TEXT NOT NULL,
-            description TEXT,
-            completed BOOLEAN NOT NULL DEFAULT 0,
-            created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
-            owner_id INTEGER NOT NULL,
-            FOREIGN KEY(owner_id) REFERENCES users(id)
-        );",
-    )
-    .execute(&pool)
-    .await
-    .expect("Failed to run migrations");
+    sqlx::migrate!("./migrations")
+        .run(&pool)
+        .await
+        .expect("Failed to run migrations");
+
+    let mut state = AppState::with_keys(pool.clone(), test_keys())
+        .await
+        .expect("Failed to build app state");
+    let mailer = MemoryMailer::default();
+    state.mailer = Arc::new(mailer.clone());
+    let mut config = (*state.config).clone();
+    configure(&mut config);
+    state.config = Arc::new(config);
+    (create_app(state), pool, mailer)
+}
+
+/// Extrae el valor del parámetro `token=` del último correo enviado a `to`.
+fn token_from_last_email(mailer: &MemoryMailer, to: &str) -> String {
+    let email = mailer
+        .sent()
+        .into_iter()
+        .rev()
+        .find(|e| e.to == to)
+        .expect("No email sent");
+    let start = email.body.find("token=").expect("No token in email") + "token=".len();
+    email.body[start..]
+        .split(|c: char| c.is_whitespace() || c == '&')
+        .next()
+        .unwrap()
+        .to_string()
+}
+
+async fn send(app: &axum::Router, request: Request<Body>) -> (StatusCode, serde_json::Value) {
+    let response = app.clone().oneshot(request).await.unwrap();
+    let status = response.status();
+    let body_bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let body = serde_json::from_slice(&body_bytes).unwrap_or(serde_json::Value::Null);
+    (status, body)
+}
+
+fn authed_request(method: &str, uri: &str, token: &str, body: Option<serde_json::Value>) -> Request<Body> {
+    let builder = Request::builder()
+        .method(method)
+        .uri(uri)
+        .header("authorization", format!("Bearer {}", token));
+
+    match body {
+        Some(body) => builder
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap(),
+        None => builder.body(Body::empty()).unwrap(),
+    }
+}
+
+async fn make_admin(pool: &SqlitePool, email: &str) {
+    sqlx::query("UPDATE users SET role = 'admin' WHERE email = ?")
+        .bind(email)
+        .execute(pool)
+        .await
+        .unwrap();
+}
+
+/// Registra un admin y devuelve tokens emitidos ya con el rol `admin`.
+async fn register_admin(app: &axum::Router, pool: &SqlitePool, email: &str) -> serde_json::Value {
+    register_and_login(app, email, "password123").await;
+    make_admin(pool, email).await;
+
+    let (status, body) = send(
+        app,
+        json_request("POST", "/token", json!({ "username": email, "password": "password123" })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    body
+}
+
+async fn mark_verified(pool: &SqlitePool, email: &str) {
+    sqlx::query("UPDATE users SET email_verified_at = CURRENT_TIMESTAMP WHERE email = ?")
+        .bind(email)
+        .execute(pool)
+        .await
+        .unwrap();
+}
 
-    create_app(pool)
+async fn user_id(pool: &SqlitePool, email: &str) -> i64 {
+    sqlx::query_scalar("SELECT id FROM users WHERE email = ?")
+        .bind(email)
+        .fetch_one(pool)
+        .await
+        .unwrap()
+}
+
+fn json_request(method: &str, uri: &str, body: serde_json::Value) -> Request<Body> {
+    Request::builder()
+        .method(method)
+        .uri(uri)
+        .header("content-type", "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+async fn register_and_login(app: &axum::Router, email: &str, password: &str) -> serde_json::Value {
+    let (status, _) = send(
+        app,
+        json_request("POST", "/users/", json!({ "email": email, "password": password })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (status, body) = send(
+        app,
+        json_request("POST", "/token", json!({ "username": email, "password": password })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    body
 }
 
 #[tokio::test]
@@ -48,7 +179,7 @@ async fn test_register_user() {
         .oneshot(
             Request::builder()
                 .method("POST")
-                .uri("/users")
+                .uri("/users/")
                 .header("content-type", "application/json")
                 .body(Body::from(
                     json!({
@@ -79,7 +210,7 @@ async fn test_login_user() {
         .oneshot(
             Request::builder()
                 .method("POST")
-                .uri("/users")
+                .uri("/users/")
                 .header("content-type", "application/json")
                 .body(Body::from(
                     json!({
@@ -119,3 +250,2069 @@ async fn test_login_user() {
     let body_json: serde_json::Value = serde_json::from_slice(&body_bytes).unwrap();
     assert!(body_json.get("access_token").is_some());
 }
+
+#[tokio::test]
+async fn test_refresh_token_rotation() {
+    let app = setup_app().await;
+    let tokens = register_and_login(&app, "refresh@example.com", "password123").await;
+    let first = tokens["refresh_token"].as_str().unwrap().to_string();
+
+    // 1. Canjear el refresh token devuelve un par nuevo
+    let (status, rotated) = send(
+        &app,
+        json_request("POST", "/token/refresh", json!({ "refresh_token": first })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let second = rotated["refresh_token"].as_str().unwrap().to_string();
+    assert_ne!(first, second);
+    assert!(rotated.get("access_token").is_some());
+
+    // 2. Reutilizar el token rotado falla y revoca la familia completa
+    let (status, _) = send(
+        &app,
+        json_request("POST", "/token/refresh", json!({ "refresh_token": first })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let (status, _) = send(
+        &app,
+        json_request("POST", "/token/refresh", json!({ "refresh_token": second })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn test_logout_revokes_current_token() {
+    let app = setup_app().await;
+    let tokens = register_and_login(&app, "logout@example.com", "password123").await;
+    let access = tokens["access_token"].as_str().unwrap();
+    let refresh = tokens["refresh_token"].as_str().unwrap();
+
+    let (status, _) = send(&app, authed_request("GET", "/tasks/", access, None)).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (status, _) = send(
+        &app,
+        authed_request("POST", "/logout", access, Some(json!({ "refresh_token": refresh }))),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (status, _) = send(&app, authed_request("GET", "/tasks/", access, None)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let (status, _) = send(
+        &app,
+        json_request("POST", "/token/refresh", json!({ "refresh_token": refresh })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn test_logout_all_revokes_previous_tokens() {
+    let app = setup_app().await;
+    let first = register_and_login(&app, "logoutall@example.com", "password123").await;
+    let first_access = first["access_token"].as_str().unwrap();
+
+    let (status, second) = send(
+        &app,
+        json_request(
+            "POST",
+            "/token",
+            json!({ "username": "logoutall@example.com", "password": "password123" }),
+        ),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let second_access = second["access_token"].as_str().unwrap();
+
+    let (status, _) = send(&app, authed_request("POST", "/logout/all", first_access, None)).await;
+    assert_eq!(status, StatusCode::OK);
+
+    // Ambos tokens emitidos antes del corte quedan invalidados
+    for token in [first_access, second_access] {
+        let (status, _) = send(&app, authed_request("GET", "/tasks/", token, None)).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    // Un login posterior (aunque sea en el mismo segundo) sigue funcionando
+    let (status, third) = send(
+        &app,
+        json_request(
+            "POST",
+            "/token",
+            json!({ "username": "logoutall@example.com", "password": "password123" }),
+        ),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = send(
+        &app,
+        authed_request("GET", "/tasks/", third["access_token"].as_str().unwrap(), None),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_deactivated_account_is_locked_out_until_reactivated() {
+    let (app, pool) = setup().await;
+    let admin = register_admin(&app, &pool, "admin@example.com").await;
+    let admin_access = admin["access_token"].as_str().unwrap();
+
+    let tokens = register_and_login(&app, "inactive@example.com", "password123").await;
+    let access = tokens["access_token"].as_str().unwrap();
+    let login = json!({ "username": "inactive@example.com", "password": "password123" });
+
+    // 1. El usuario desactiva su propia cuenta
+    let (status, _) = send(&app, authed_request("POST", "/users/me/deactivate", access, None)).await;
+    assert_eq!(status, StatusCode::OK);
+
+    // 2. Login y peticiones autenticadas devuelven un error distinto de 401
+    let (status, body) = send(&app, json_request("POST", "/token", login.clone())).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(body["error_description"], "Account is deactivated");
+
+    let (status, body) = send(&app, authed_request("GET", "/tasks/", access, None)).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(body["error"], "Account is deactivated");
+
+    // 3. Solo un admin puede reactivarla
+    let id = user_id(&pool, "inactive@example.com").await;
+    let uri = format!("/admin/users/{}/reactivate", id);
+    let (status, _) = send(&app, authed_request("POST", &uri, access, None)).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    let (status, body) = send(&app, authed_request("POST", &uri, admin_access, None)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["is_active"], true);
+
+    let (status, _) = send(&app, json_request("POST", "/token", login)).await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_admin_deactivation_blocks_existing_tokens() {
+    let (app, pool) = setup().await;
+    let admin = register_admin(&app, &pool, "admin@example.com").await;
+    let tokens = register_and_login(&app, "target@example.com", "password123").await;
+    let access = tokens["access_token"].as_str().unwrap();
+
+    let id = user_id(&pool, "target@example.com").await;
+    let (status, _) = send(
+        &app,
+        authed_request(
+            "POST",
+            &format!("/admin/users/{}/deactivate", id),
+            admin["access_token"].as_str().unwrap(),
+            None,
+        ),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (status, body) = send(&app, authed_request("GET", "/tasks/", access, None)).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(body["error"], "Account is deactivated");
+}
+
+#[tokio::test]
+async fn test_admin_routes_require_admin_role() {
+    let (app, pool) = setup().await;
+    let admin = register_admin(&app, &pool, "admin@example.com").await;
+    let admin_access = admin["access_token"].as_str().unwrap();
+
+    let owner = register_and_login(&app, "owner@example.com", "password123").await;
+    mark_verified(&pool, "owner@example.com").await;
+    let owner_access = owner["access_token"].as_str().unwrap();
+    let (status, task) = send(
+        &app,
+        authed_request(
+            "POST",
+            "/tasks/",
+            owner_access,
+            Some(json!({ "title": "Private", "description": null, "completed": false })),
+        ),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let task_id = task["id"].as_i64().unwrap();
+    let owner_id = user_id(&pool, "owner@example.com").await;
+
+    // Un usuario normal no accede a /admin
+    let (status, _) = send(&app, authed_request("GET", "/admin/users", owner_access, None)).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    // El admin lista usuarios y ve/gestiona las tareas de otro usuario
+    let (status, users) = send(&app, authed_request("GET", "/admin/users", admin_access, None)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(users.as_array().unwrap().len(), 2);
+    // `limit` está acotado: un valor negativo no devuelve la tabla entera
+    let (status, users) = send(&app, authed_request("GET", "/admin/users?limit=-1&skip=-5", admin_access, None)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(users.as_array().unwrap().len(), 0);
+
+    let uri = format!("/admin/users/{}/tasks", owner_id);
+    let (status, tasks) = send(&app, authed_request("GET", &uri, admin_access, None)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(tasks[0]["id"], task_id);
+
+    let uri = format!("/admin/tasks/{}", task_id);
+    let (status, updated) = send(
+        &app,
+        authed_request("PUT", &uri, admin_access, Some(json!({ "completed": true }))),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(updated["completed"], true);
+
+    // Las rutas normales siguen filtrando por propietario, también para el admin
+    let (status, _) = send(
+        &app,
+        authed_request("GET", &format!("/tasks/{}", task_id), admin_access, None),
+    )
+    .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    let (status, _) = send(&app, authed_request("DELETE", &uri, admin_access, None)).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (status, tasks) = send(&app, authed_request("GET", "/tasks/", owner_access, None)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(tasks.as_array().unwrap().is_empty());
+
+    // El rol va firmado en el token: tras un ascenso hay que volver a iniciar sesión
+    make_admin(&pool, "owner@example.com").await;
+    let (status, _) = send(&app, authed_request("GET", "/admin/users", owner_access, None)).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn test_task_filters_and_sorting() {
+    let (app, pool) = setup().await;
+    let tokens = register_and_login(&app, "lists@example.com", "password123").await;
+    mark_verified(&pool, "lists@example.com").await;
+    let access = tokens["access_token"].as_str().unwrap();
+
+    let tasks = [
+        ("buy milk", None, true, "2024-01-10 09:00:00"),
+        ("Write report", Some("50% done"), false, "2024-02-01 12:00:00"),
+        ("call Bob", Some("about the milk"), false, "2024-03-05 18:30:00"),
+    ];
+    for (title, description, completed, created_at) in tasks {
+        let (status, task) = send(
+            &app,
+            authed_request(
+                "POST",
+                "/tasks/",
+                access,
+                Some(json!({ "title": title, "description": description, "completed": completed })),
+            ),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        sqlx::query("UPDATE tasks SET created_at = ? WHERE id = ?")
+            .bind(created_at)
+            .bind(task["id"].as_i64().unwrap())
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+
+    let titles = |uri: &str| {
+        let request = authed_request("GET", uri, access, None);
+        let (app, uri) = (app.clone(), uri.to_string());
+        async move {
+            let (status, body) = send(&app, request).await;
+            assert_eq!(status, StatusCode::OK, "{}", uri);
+            body.as_array()
+                .unwrap()
+                .iter()
+                .map(|t| t["title"].as_str().unwrap().to_string())
+                .collect::<Vec<_>>()
+        }
+    };
+
+    assert_eq!(titles("/tasks/").await, ["buy milk", "Write report", "call Bob"]);
+    assert_eq!(titles("/tasks/?sort=-created_at").await, ["call Bob", "Write report", "buy milk"]);
+    assert_eq!(titles("/tasks/?sort=title").await, ["buy milk", "call Bob", "Write report"]);
+    assert_eq!(titles("/tasks/?completed=false&sort=-id").await, ["call Bob", "Write report"]);
+    assert_eq!(
+        titles("/tasks/?created_after=2024-01-15T00:00:00&created_before=2024-03-01T00:00:00").await,
+        ["Write report"]
+    );
+
+    // La búsqueda mira título y descripción; `%` no actúa como comodín
+    assert_eq!(titles("/tasks/?search=MILK").await, ["buy milk", "call Bob"]);
+    assert_eq!(titles("/tasks/?search=50%25").await, ["Write report"]);
+    assert_eq!(titles("/tasks/?search=%25").await, ["Write report"]);
+    assert_eq!(titles("/tasks/?sort=-created_at&skip=1&limit=1").await, ["Write report"]);
+
+    // Solo se aceptan los órdenes de la lista
+    let (status, _) = send(&app, authed_request("GET", "/tasks/?sort=owner_id", access, None)).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn test_audit_log() {
+    let (app, pool) = setup().await;
+    let admin = register_admin(&app, &pool, "auditor@example.com").await;
+    let admin_access = admin["access_token"].as_str().unwrap();
+    let admin_id = user_id(&pool, "auditor@example.com").await;
+
+    let tokens = register_and_login(&app, "audited@example.com", "password123").await;
+    let access = tokens["access_token"].as_str().unwrap();
+    let audited_id = user_id(&pool, "audited@example.com").await;
+
+    let (status, _) = send(
+        &app,
+        json_request("POST", "/token", json!({ "username": "audited@example.com", "password": "wrong-password" })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) = send(
+        &app,
+        json_request("POST", "/token/refresh", json!({ "refresh_token": tokens["refresh_token"] })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+
+    let uri = format!("/admin/users/{}/role", audited_id);
+    let (status, _) = send(&app, authed_request("PUT", &uri, admin_access, Some(json!({ "role": "user" })))).await;
+    assert_eq!(status, StatusCode::OK);
+
+    // MFA y API keys también quedan registrados
+    let (_, enrollment) = send(&app, authed_request("POST", "/users/me/mfa/totp", access, None)).await;
+    let secret = enrollment["secret"].as_str().unwrap().to_string();
+    let confirm = json!({ "code": totp_code(&secret, 0) });
+    let (status, _) = send(&app, authed_request("POST", "/users/me/mfa/totp/confirm", access, Some(confirm))).await;
+    assert_eq!(status, StatusCode::OK);
+    let disable = json!({ "code": totp_code(&secret, 30) });
+    let (status, _) = send(&app, authed_request("DELETE", "/users/me/mfa/totp", access, Some(disable))).await;
+    assert_eq!(status, StatusCode::OK);
+
+    mark_verified(&pool, "audited@example.com").await;
+    let new_key = json!({ "name": "audited", "scope": "read" });
+    let (status, key) = send(&app, authed_request("POST", "/users/me/api-keys", access, Some(new_key))).await;
+    assert_eq!(status, StatusCode::OK);
+    let uri = format!("/users/me/api-keys/{}", key["id"]);
+    // Revocar dos veces deja un único evento
+    for _ in 0..2 {
+        let (status, _) = send(&app, authed_request("DELETE", &uri, access, None)).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    // El usuario ve lo ocurrido en su cuenta, del más reciente al más antiguo
+    let (status, activity) = send(&app, authed_request("GET", "/users/me/activity", access, None)).await;
+    assert_eq!(status, StatusCode::OK);
+    let actions: Vec<&str> = activity
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|e| e["action"].as_str().unwrap())
+        .collect();
+    assert_eq!(
+        actions,
+        [
+            "api_key_revoked",
+            "api_key_created",
+            "mfa_disabled",
+            "mfa_enabled",
+            "role_changed",
+            "token_refreshed",
+            "login_failed",
+            "login_succeeded",
+            "user_registered"
+        ]
+    );
+    assert_eq!(activity[1]["payload"]["api_key_id"], key["id"]);
+    assert_eq!(activity[4]["actor_id"], admin_id);
+    assert_eq!(activity[4]["payload"]["role"], "user");
+    assert_eq!(activity[6]["actor_id"], serde_json::Value::Null);
+
+    // `limit` está acotado: un valor negativo no devuelve la tabla entera
+    let (status, events) = send(&app, authed_request("GET", "/users/me/activity?limit=-1", access, None)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(events.as_array().unwrap().len(), 0);
+    let (status, events) = send(&app, authed_request("GET", "/admin/audit?limit=-1", admin_access, None)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(events.as_array().unwrap().len(), 0);
+
+    // Los admins filtran todo el registro; el resto no tiene acceso
+    let (status, _) = send(&app, authed_request("GET", "/admin/audit", access, None)).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    let (status, events) = send(
+        &app,
+        authed_request("GET", "/admin/audit?action=login_succeeded&limit=1", admin_access, None),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(events.as_array().unwrap().len(), 1);
+    assert_eq!(events[0]["user_id"], audited_id);
+
+    let uri = format!("/admin/audit?actor_id={}", admin_id);
+    let (_, events) = send(&app, authed_request("GET", &uri, admin_access, None)).await;
+    let actions: Vec<&str> = events
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|e| e["action"].as_str().unwrap())
+        .collect();
+    assert_eq!(actions, ["role_changed", "login_succeeded", "login_succeeded", "user_registered"]);
+
+    // El logout y los cambios de rol por CLI también se registran
+    let (status, _) = send(&app, authed_request("POST", "/logout", access, None)).await;
+    assert_eq!(status, StatusCode::OK);
+    let args = ["set-role", "audited@example.com", "admin"].map(String::from);
+    crate::cli::run(&pool, &args).await.unwrap();
+
+    let uri = format!("/admin/audit?user_id={}&limit=2", audited_id);
+    let (_, events) = send(&app, authed_request("GET", &uri, admin_access, None)).await;
+    assert_eq!(events[0]["action"], "role_changed");
+    assert_eq!(events[0]["actor_id"], serde_json::Value::Null);
+    assert_eq!(events[0]["payload"], json!({ "role": "admin", "via": "cli" }));
+    assert_eq!(events[1]["action"], "logged_out");
+    assert_eq!(events[1]["actor_id"], audited_id);
+
+    // El registro solo admite inserciones
+    assert!(sqlx::query("DELETE FROM audit_events").execute(&pool).await.is_err());
+    assert!(sqlx::query("UPDATE audit_events SET user_id = NULL").execute(&pool).await.is_err());
+}
+
+#[tokio::test]
+async fn test_password_reset_flow() {
+    let (app, _pool, mailer) = setup_with_config(|config| {
+        config.password_reset_url = Some("https://app.example.com/reset-password".to_string());
+    })
+    .await;
+    let tokens = register_and_login(&app, "forgot@example.com", "password123").await;
+    let old_access = tokens["access_token"].as_str().unwrap();
+
+    // Emails desconocidos reciben la misma respuesta y no generan correo
+    let (status, _) = send(
+        &app,
+        json_request("POST", "/password/forgot", json!({ "email": "nobody@example.com" })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    assert!(mailer.sent().iter().all(|e| e.to != "nobody@example.com"));
+
+    let (status, _) = send(
+        &app,
+        json_request("POST", "/password/forgot", json!({ "email": "forgot@example.com" })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    let reset_token = token_from_last_email(&mailer, "forgot@example.com");
+    // El enlace lleva a la página del frontend, no a la ruta POST de la API
+    let email = mailer.sent().pop().unwrap();
+    assert!(email.body.contains(&format!("https://app.example.com/reset-password?token={}", reset_token)));
+
+    let reset = json!({ "token": reset_token, "new_password": "new-password456" });
+    let (status, _) = send(&app, json_request("POST", "/password/reset", reset.clone())).await;
+    assert_eq!(status, StatusCode::OK);
+
+    // El token es de un solo uso
+    let (status, _) = send(&app, json_request("POST", "/password/reset", reset)).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    // Las sesiones anteriores quedan revocadas y solo vale la contraseña nueva
+    let (status, _) = send(&app, authed_request("GET", "/tasks/", old_access, None)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let (status, _) = send(
+        &app,
+        json_request("POST", "/token", json!({ "username": "forgot@example.com", "password": "password123" })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    let (status, _) = send(
+        &app,
+        json_request("POST", "/token", json!({ "username": "forgot@example.com", "password": "new-password456" })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_change_password() {
+    let app = setup_app().await;
+    let tokens = register_and_login(&app, "change@example.com", "password123").await;
+    let old_access = tokens["access_token"].as_str().unwrap();
+    let old_refresh = tokens["refresh_token"].as_str().unwrap();
+
+    // Contraseña actual incorrecta
+    let (status, _) = send(
+        &app,
+        authed_request(
+            "PUT",
+            "/users/me/password",
+            old_access,
+            Some(json!({ "current_password": "wrong-password", "new_password": "new-password456" })),
+        ),
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    // Contraseña nueva demasiado corta
+    let (status, _) = send(
+        &app,
+        authed_request(
+            "PUT",
+            "/users/me/password",
+            old_access,
+            Some(json!({ "current_password": "password123", "new_password": "short" })),
+        ),
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    let (status, new_tokens) = send(
+        &app,
+        authed_request(
+            "PUT",
+            "/users/me/password",
+            old_access,
+            Some(json!({ "current_password": "password123", "new_password": "new-password456" })),
+        ),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+
+    // Los tokens anteriores dejan de valer; los nuevos funcionan
+    let (status, _) = send(&app, authed_request("GET", "/tasks/", old_access, None)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = send(
+        &app,
+        json_request("POST", "/token/refresh", json!({ "refresh_token": old_refresh })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let new_access = new_tokens["access_token"].as_str().unwrap();
+    let (status, _) = send(&app, authed_request("GET", "/tasks/", new_access, None)).await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_change_email_flow() {
+    let (app, _pool, mailer) = setup_with_mailer().await;
+    let tokens = register_and_login(&app, "old@example.com", "password123").await;
+    let access = tokens["access_token"].as_str().unwrap();
+    register_and_login(&app, "taken@example.com", "password123").await;
+
+    // Hace falta el password y una dirección libre
+    let (status, _) = send(
+        &app,
+        authed_request("POST", "/users/me/email", access, Some(json!({ "new_email": "new@example.com" }))),
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) = send(
+        &app,
+        authed_request(
+            "POST",
+            "/users/me/email",
+            access,
+            Some(json!({ "new_email": "Taken@Example.com", "password": "password123" })),
+        ),
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    let (status, _) = send(
+        &app,
+        authed_request(
+            "POST",
+            "/users/me/email",
+            access,
+            Some(json!({ "new_email": " New@Example.com", "password": "password123" })),
+        ),
+    )
+    .await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    let token = token_from_last_email(&mailer, "new@example.com");
+
+    // Hasta confirmar no cambia nada
+    let (_, me) = send(&app, authed_request("GET", "/users/me", access, None)).await;
+    assert_eq!(me["email"], "old@example.com");
+
+    // El enlace del correo se abre con un GET; el token sirve una sola vez
+    let link = format!("/users/email/confirm?token={}", token);
+    let (status, _) = send(&app, Request::builder().uri(&link).body(Body::empty()).unwrap()).await;
+    assert_eq!(status, StatusCode::OK);
+    let confirm = json!({ "token": token });
+    let (status, _) = send(&app, json_request("POST", "/users/email/confirm", confirm)).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    // La sesión sigue abierta y la dirección anterior recibe el aviso
+    let (status, me) = send(&app, authed_request("GET", "/users/me", access, None)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(me["email"], "new@example.com");
+    assert!(mailer
+        .sent()
+        .iter()
+        .any(|e| e.to == "old@example.com" && e.body.contains("new@example.com")));
+
+    let (status, _) = send(
+        &app,
+        json_request("POST", "/token", json!({ "username": "old@example.com", "password": "password123" })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) = send(
+        &app,
+        json_request("POST", "/token", json!({ "username": "new@example.com", "password": "password123" })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_email_verification_gates_task_writes() {
+    let (app, _pool, mailer) = setup_with_mailer().await;
+    let tokens = register_and_login(&app, "verify@example.com", "password123").await;
+    let access = tokens["access_token"].as_str().unwrap();
+    let task = json!({ "title": "Pending", "description": null, "completed": false });
+
+    // Por defecto una cuenta sin verificar puede entrar y leer, pero no escribir
+    let (status, _) = send(&app, authed_request("GET", "/tasks/", access, None)).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, body) = send(&app, authed_request("POST", "/tasks/", access, Some(task.clone()))).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(body["error"], "Email address not verified");
+
+    let token = token_from_last_email(&mailer, "verify@example.com");
+    let verify = Request::builder()
+        .uri(format!("/users/verify?token={}", token))
+        .body(Body::empty())
+        .unwrap();
+    let (status, _) = send(&app, verify).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (status, _) = send(&app, authed_request("POST", "/tasks/", access, Some(task))).await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_unverified_login_can_be_disallowed() {
+    let (app, _pool, _mailer) = setup_with_config(|config| {
+        config.unverified_actions.remove(&UnverifiedAction::Login);
+    })
+    .await;
+
+    let (status, _) = send(
+        &app,
+        json_request("POST", "/users/", json!({ "email": "strict@example.com", "password": "password123" })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (status, _) = send(
+        &app,
+        json_request("POST", "/token", json!({ "username": "strict@example.com", "password": "password123" })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    // Tampoco se aceptan direcciones sin formato de email
+    let (status, _) = send(
+        &app,
+        json_request("POST", "/users/", json!({ "email": "not-an-email", "password": "password123" })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+fn totp_code(secret: &str, offset_secs: u64) -> String {
+    use totp_rs::{Algorithm, Secret, TOTP};
+
+    let totp = TOTP::new(
+        Algorithm::SHA1,
+        6,
+        1,
+        30,
+        Secret::Encoded(secret.to_string()).to_bytes().unwrap(),
+        None,
+        "test".to_string(),
+    )
+    .unwrap();
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    totp.generate(now + offset_secs)
+}
+
+#[tokio::test]
+async fn test_totp_enrollment_and_mfa_login() {
+    let app = setup_app().await;
+    let tokens = register_and_login(&app, "mfa@example.com", "password123").await;
+    let access = tokens["access_token"].as_str().unwrap();
+    let credentials = json!({ "username": "mfa@example.com", "password": "password123" });
+
+    // 1. Enrolar y confirmar con un código válido
+    let (status, enrollment) = send(&app, authed_request("POST", "/users/me/mfa/totp", access, None)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(enrollment["otpauth_uri"].as_str().unwrap().starts_with("otpauth://totp/"));
+    let secret = enrollment["secret"].as_str().unwrap().to_string();
+
+    let (status, codes) = send(
+        &app,
+        authed_request("POST", "/users/me/mfa/totp/confirm", access, Some(json!({ "code": totp_code(&secret, 0) }))),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let recovery_codes = codes["recovery_codes"].as_array().unwrap();
+    assert_eq!(recovery_codes.len(), 10);
+    let recovery_code = recovery_codes[0].as_str().unwrap().to_string();
+
+    // 2. El login ahora devuelve un challenge en lugar del access token
+    let (status, challenge) = send(&app, json_request("POST", "/token", credentials.clone())).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(challenge["mfa_required"], true);
+    assert!(challenge.get("access_token").is_none());
+    let mfa_token = challenge["mfa_token"].as_str().unwrap().to_string();
+
+    // El token intermedio no sirve como access token
+    let (status, _) = send(&app, authed_request("GET", "/tasks/", &mfa_token, None)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    // 3. Un código erróneo consume el challenge
+    let (status, _) = send(
+        &app,
+        json_request("POST", "/token/mfa", json!({ "mfa_token": mfa_token, "code": "000000" })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = send(
+        &app,
+        json_request("POST", "/token/mfa", json!({ "mfa_token": mfa_token, "code": totp_code(&secret, 30) })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    // 4. Con un challenge nuevo y un código del siguiente paso (no usado aún) se obtiene el token
+    let (_, challenge) = send(&app, json_request("POST", "/token", credentials.clone())).await;
+    let (status, token) = send(
+        &app,
+        json_request(
+            "POST",
+            "/token/mfa",
+            json!({ "mfa_token": challenge["mfa_token"], "code": totp_code(&secret, 30) }),
+        ),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(token.get("access_token").is_some());
+
+    // 5. Los códigos de recuperación valen una sola vez
+    for expected in [StatusCode::OK, StatusCode::UNAUTHORIZED] {
+        let (_, challenge) = send(&app, json_request("POST", "/token", credentials.clone())).await;
+        let (status, _) = send(
+            &app,
+            json_request(
+                "POST",
+                "/token/mfa",
+                json!({ "mfa_token": challenge["mfa_token"], "code": recovery_code }),
+            ),
+        )
+        .await;
+        assert_eq!(status, expected);
+    }
+}
+
+#[tokio::test]
+async fn test_api_keys_scopes_and_revocation() {
+    let (app, pool) = setup().await;
+    let tokens = register_and_login(&app, "ci@example.com", "password123").await;
+    mark_verified(&pool, "ci@example.com").await;
+    let access = tokens["access_token"].as_str().unwrap();
+    let task = json!({ "title": "From CI", "description": null, "completed": false });
+
+    let (status, read_key) = send(
+        &app,
+        authed_request("POST", "/users/me/api-keys", access, Some(json!({ "name": "dashboard", "scope": "read" }))),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let read_key_value = read_key["key"].as_str().unwrap().to_string();
+    assert!(read_key_value.starts_with("bak_"));
+
+    let (status, write_key) = send(
+        &app,
+        authed_request(
+            "POST",
+            "/users/me/api-keys",
+            access,
+            Some(json!({ "name": "ci", "scope": "read_write", "expires_in_days": 30 })),
+        ),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let write_key_value = write_key["key"].as_str().unwrap().to_string();
+
+    // Clave de solo lectura vía X-API-Key
+    let with_header = |method: &str, body: Option<serde_json::Value>| {
+        let builder = Request::builder()
+            .method(method)
+            .uri("/tasks/")
+            .header("x-api-key", &read_key_value);
+        match body {
+            Some(body) => builder
+                .header("content-type", "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap(),
+            None => builder.body(Body::empty()).unwrap(),
+        }
+    };
+    let (status, _) = send(&app, with_header("GET", None)).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = send(&app, with_header("POST", Some(task.clone()))).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    // Clave de lectura/escritura como Bearer
+    let (status, _) = send(&app, authed_request("POST", "/tasks/", &write_key_value, Some(task))).await;
+    assert_eq!(status, StatusCode::OK);
+
+    // Las API keys no sirven fuera de /tasks (p. ej. para crear más claves)
+    let (status, _) = send(&app, authed_request("GET", "/users/me/api-keys", &write_key_value, None)).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    let (status, keys) = send(&app, authed_request("GET", "/users/me/api-keys", access, None)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(keys.as_array().unwrap().len(), 2);
+    assert!(keys[1]["last_used_at"].is_string());
+    assert!(keys[0].get("key").is_none());
+
+    let uri = format!("/users/me/api-keys/{}", write_key["id"]);
+    let (status, renamed) = send(&app, authed_request("PATCH", &uri, access, Some(json!({ "name": "deploy" })))).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(renamed["name"], "deploy");
+
+    let (status, _) = send(&app, authed_request("DELETE", &uri, access, None)).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = send(&app, authed_request("GET", "/tasks/", &write_key_value, None)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    // Cerrar todas las sesiones revoca también las claves que quedaban
+    let (status, _) = send(&app, authed_request("POST", "/logout/all", access, None)).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = send(&app, with_header("GET", None)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+/// Petición de login desde `ip`, como la vería el servidor con `ConnectInfo`.
+fn login_request_from(ip: [u8; 4], email: &str, password: &str) -> Request<Body> {
+    let mut request = json_request("POST", "/token", json!({ "username": email, "password": password }));
+    request
+        .extensions_mut()
+        .insert(axum::extract::ConnectInfo(std::net::SocketAddr::from((ip, 4000))));
+    request
+}
+
+#[tokio::test]
+async fn test_login_lockout_and_admin_unlock() {
+    let (app, pool, _) = setup_with_config(|config| {
+        config.login_lockout.max_account_failures = 3;
+        config.login_lockout.max_ip_failures = 5;
+    })
+    .await;
+    let admin = register_admin(&app, &pool, "admin@example.com").await;
+    register_and_login(&app, "victim@example.com", "password123").await;
+
+    for _ in 0..3 {
+        let (status, _) = send(&app, login_request_from([10, 0, 0, 1], "victim@example.com", "wrong")).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    // Bloqueada: ni siquiera el password correcto entra, y se indica cuándo reintentar
+    let response = app
+        .clone()
+        .oneshot(login_request_from([10, 0, 0, 2], "victim@example.com", "password123"))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::LOCKED);
+    let retry_after: u64 = response.headers()["retry-after"].to_str().unwrap().parse().unwrap();
+    assert!(retry_after > 0 && retry_after <= 60);
+
+    // Un admin la desbloquea
+    let uri = format!("/admin/users/{}/unlock", user_id(&pool, "victim@example.com").await);
+    let (status, _) = send(&app, authed_request("POST", &uri, admin["access_token"].as_str().unwrap(), None)).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = send(&app, login_request_from([10, 0, 0, 2], "victim@example.com", "password123")).await;
+    assert_eq!(status, StatusCode::OK);
+
+    // La IP acumula fallos sobre cuentas distintas (incluidas inexistentes)
+    for i in 0..2 {
+        let email = format!("nobody{}@example.com", i);
+        let (status, _) = send(&app, login_request_from([10, 0, 0, 1], &email, "wrong")).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+    let (status, _) = send(&app, login_request_from([10, 0, 0, 1], "admin@example.com", "password123")).await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    let (status, _) = send(&app, login_request_from([10, 0, 0, 3], "admin@example.com", "password123")).await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_login_attempt_stores_are_interchangeable() {
+    use crate::login_attempts::{LoginAttemptStore, MemoryLoginAttemptStore, SqliteLoginAttemptStore};
+
+    let (_, pool) = setup().await;
+    let stores: Vec<Box<dyn LoginAttemptStore>> = vec![
+        Box::new(MemoryLoginAttemptStore::default()),
+        Box::new(SqliteLoginAttemptStore::new(pool)),
+    ];
+
+    for store in stores {
+        assert!(store.get("ip:10.0.0.1").await.unwrap().is_none());
+        store.record_failure("ip:10.0.0.1", 1000, 60).await.unwrap();
+        let record = store.record_failure("ip:10.0.0.1", 1010, 60).await.unwrap();
+        assert_eq!(record.failures, 2);
+
+        store.lock("ip:10.0.0.1", 1100).await.unwrap();
+        assert_eq!(store.get("ip:10.0.0.1").await.unwrap().unwrap().locked_until, Some(1100));
+
+        // Pasada la ventana tras el fin del bloqueo, el contador vuelve a empezar
+        let record = store.record_failure("ip:10.0.0.1", 1200, 60).await.unwrap();
+        assert_eq!((record.failures, record.locked_until), (1, None));
+
+        store.clear("ip:10.0.0.1").await.unwrap();
+        assert!(store.get("ip:10.0.0.1").await.unwrap().is_none());
+    }
+}
+
+fn form_request(uri: &str, body: &str) -> Request<Body> {
+    Request::builder()
+        .method("POST")
+        .uri(uri)
+        .header("content-type", "application/x-www-form-urlencoded")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_oauth2_token_endpoint_grants() {
+    let app = setup_app().await;
+    register_and_login(&app, "oauth@example.com", "password123").await;
+
+    // grant_type=password con cuerpo form-urlencoded, como cualquier cliente OAuth2
+    let response = app
+        .clone()
+        .oneshot(form_request(
+            "/token",
+            "grant_type=password&username=oauth%40example.com&password=password123&scope=",
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["cache-control"], "no-store");
+    let body_bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let tokens: serde_json::Value = serde_json::from_slice(&body_bytes).unwrap();
+    assert_eq!(tokens["token_type"], "bearer");
+    assert!(tokens["expires_in"].as_u64().unwrap() > 0);
+
+    // grant_type=refresh_token rota el refresh token
+    let refresh = tokens["refresh_token"].as_str().unwrap();
+    let (status, rotated) = send(
+        &app,
+        form_request("/token", &format!("grant_type=refresh_token&refresh_token={}", refresh)),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_ne!(rotated["refresh_token"], tokens["refresh_token"]);
+
+    // Errores con la forma de RFC 6749
+    let (status, body) = send(
+        &app,
+        form_request("/token", "grant_type=password&username=oauth%40example.com&password=wrong"),
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["error"], "invalid_grant");
+    assert!(body["error_description"].is_string());
+
+    let (status, body) = send(&app, form_request("/token", "grant_type=password&username=oauth%40example.com")).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["error"], "invalid_request");
+
+    let (status, body) = send(&app, form_request("/token", "grant_type=client_credentials")).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["error"], "unsupported_grant_type");
+
+    // JSON con grant_type también es válido
+    let (status, _) = send(
+        &app,
+        json_request(
+            "POST",
+            "/token",
+            json!({ "grant_type": "password", "username": "oauth@example.com", "password": "password123" }),
+        ),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_profile_update_and_account_deletion() {
+    let (app, pool) = setup().await;
+    let tokens = register_and_login(&app, "profile@example.com", "password123").await;
+    let access = tokens["access_token"].as_str().unwrap();
+    mark_verified(&pool, "profile@example.com").await;
+
+    let (status, me) = send(&app, authed_request("GET", "/users/me", access, None)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(me["email"], "profile@example.com");
+    assert!(me["display_name"].is_null());
+
+    let profile = json!({
+        "display_name": "  Ada  ",
+        "timezone": "Europe/Madrid",
+        "locale": "es-ES",
+        "avatar_url": "https://cdn.example.com/ada.png"
+    });
+    let (status, me) = send(&app, authed_request("PATCH", "/users/me", access, Some(profile))).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(me["display_name"], "Ada");
+    assert_eq!(me["timezone"], "Europe/Madrid");
+
+    // Los campos ausentes se conservan y "" borra el valor
+    let (status, me) = send(&app, authed_request("PATCH", "/users/me", access, Some(json!({ "avatar_url": "" })))).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(me["avatar_url"].is_null());
+    assert_eq!(me["locale"], "es-ES");
+
+    for invalid in [
+        json!({ "timezone": "Mars/Olympus" }),
+        json!({ "locale": "spanish!" }),
+        json!({ "avatar_url": "javascript:alert(1)" }),
+        json!({ "display_name": "x".repeat(101) }),
+    ] {
+        let (status, _) = send(&app, authed_request("PATCH", "/users/me", access, Some(invalid))).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    let (status, _) = send(
+        &app,
+        authed_request("POST", "/tasks/", access, Some(json!({ "title": "t", "completed": false }))),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+
+    // Borrar la cuenta exige el password y arrastra sus tareas
+    let (status, _) = send(&app, authed_request("DELETE", "/users/me", access, Some(json!({ "password": "wrong" })))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) = send(
+        &app,
+        authed_request("DELETE", "/users/me", access, Some(json!({ "password": "password123" }))),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+
+    let tasks: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM tasks").fetch_one(&pool).await.unwrap();
+    assert_eq!(tasks, 0);
+    let (status, _) = send(&app, authed_request("GET", "/users/me", access, None)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn test_personal_data_export() {
+    let (app, pool, mailer) = setup_with_config(|config| config.export_inline_max_tasks = 1).await;
+    let tokens = register_and_login(&app, "export@example.com", "password123").await;
+    let access = tokens["access_token"].as_str().unwrap();
+    mark_verified(&pool, "export@example.com").await;
+
+    let create_task = |title: &str| {
+        authed_request("POST", "/tasks/", access, Some(json!({ "title": title, "completed": false })))
+    };
+    let (status, _) = send(&app, create_task("first")).await;
+    assert_eq!(status, StatusCode::OK);
+
+    // Cuenta pequeña: el documento se descarga directamente
+    let response = app
+        .clone()
+        .oneshot(authed_request("GET", "/users/me/export", access, None))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.headers()["content-disposition"].to_str().unwrap().starts_with("attachment"));
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let export: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(export["user"]["email"], "export@example.com");
+    assert!(export["user"].get("hashed_password").is_none());
+    assert_eq!(export["tasks"].as_array().unwrap().len(), 1);
+    assert_eq!(export["sessions"].as_array().unwrap().len(), 1);
+    assert_eq!(export["activity"][0]["action"], "user_registered");
+
+    // Por encima del límite se genera en segundo plano
+    let (status, _) = send(&app, create_task("second")).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, job) = send(&app, authed_request("GET", "/users/me/export", access, None)).await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    let url = job["download_url"].as_str().unwrap().to_string();
+
+    let mut export = serde_json::Value::Null;
+    for _ in 0..100 {
+        let (status, body) = send(&app, authed_request("GET", &url, access, None)).await;
+        if status == StatusCode::OK {
+            export = body;
+            break;
+        }
+        assert_eq!(status, StatusCode::ACCEPTED);
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    assert_eq!(export["tasks"].as_array().unwrap().len(), 2);
+
+    // El enlace del correo descarga la exportación sin sesión, solo con su token
+    let token = token_from_last_email(&mailer, "export@example.com");
+    let link = format!("/exports/{}?token={}", job["id"], token);
+    let (status, body) = send(&app, Request::builder().uri(&link).body(Body::empty()).unwrap()).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["tasks"].as_array().unwrap().len(), 2);
+    let wrong = format!("/exports/{}?token=wrong", job["id"]);
+    let (status, _) = send(&app, Request::builder().uri(&wrong).body(Body::empty()).unwrap()).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    // Solo el dueño puede descargarla
+    let other = register_and_login(&app, "other-export@example.com", "password123").await;
+    let (status, _) = send(&app, authed_request("GET", &url, other["access_token"].as_str().unwrap(), None)).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    // Una exportación que lleva demasiado tiempo pendiente (p. ej. tras un reinicio) no
+    // bloquea pedir otra
+    sqlx::query("UPDATE data_exports SET status = 'pending', created_at = datetime('now', '-1 hour')")
+        .execute(&pool)
+        .await
+        .unwrap();
+    let (status, retry) = send(&app, authed_request("GET", "/users/me/export", access, None)).await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    assert_ne!(retry["id"], job["id"]);
+    let (status, _) = send(&app, authed_request("GET", &url, access, None)).await;
+    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+}
+
+#[tokio::test]
+async fn test_admin_impersonation() {
+    let (app, pool) = setup().await;
+    let admin = register_admin(&app, &pool, "support@example.com").await;
+    let admin = admin["access_token"].as_str().unwrap();
+    let user = register_and_login(&app, "customer@example.com", "password123").await;
+    mark_verified(&pool, "customer@example.com").await;
+    let (_, task) = send(
+        &app,
+        authed_request(
+            "POST",
+            "/tasks/",
+            user["access_token"].as_str().unwrap(),
+            Some(json!({ "title": "broken", "completed": false })),
+        ),
+    )
+    .await;
+
+    let customer_id = user_id(&pool, "customer@example.com").await;
+    let uri = format!("/admin/users/{}/impersonate", customer_id);
+    let (status, _) = send(&app, authed_request("POST", &uri, admin, Some(json!({ "reason": " " })))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    let (status, body) = send(&app, authed_request("POST", &uri, admin, Some(json!({ "reason": "ticket #42" })))).await;
+    assert_eq!(status, StatusCode::OK);
+    let token = body["access_token"].as_str().unwrap().to_string();
+    let claims = crate::tokens::decode_access_token(&test_keys(), &token).unwrap();
+    assert_eq!(claims.sub, customer_id.to_string());
+    assert_eq!(claims.act.unwrap().sub, user_id(&pool, "support@example.com").await.to_string());
+
+    // Ve lo mismo que el usuario, pero no puede borrar ni tocar la cuenta
+    let (status, tasks) = send(&app, authed_request("GET", "/tasks/", &token, None)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(tasks.as_array().unwrap().len(), 1);
+    let task_uri = format!("/tasks/{}", task["id"]);
+    let (status, _) = send(&app, authed_request("DELETE", &task_uri, &token, None)).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let edit = json!({ "title": "fixed", "completed": true });
+    let (status, _) = send(&app, authed_request("PUT", &task_uri, &token, Some(edit.clone()))).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let change = json!({ "current_password": "password123", "new_password": "another-pass-9" });
+    let (status, _) = send(&app, authed_request("PUT", "/users/me/password", &token, Some(change))).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    // Borrar solo si se permitió explícitamente
+    let request = json!({ "reason": "ticket #42", "allow_destructive": true });
+    let (_, body) = send(&app, authed_request("POST", &uri, admin, Some(request))).await;
+    let destructive = body["access_token"].as_str().unwrap();
+    let (status, _) = send(&app, authed_request("PUT", &task_uri, destructive, Some(edit))).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = send(&app, authed_request("DELETE", &task_uri, destructive, None)).await;
+    assert_eq!(status, StatusCode::OK);
+
+    // Cada petición suplantada queda en la auditoría a nombre del admin
+    let admin_id = user_id(&pool, "support@example.com").await;
+    let (status, events) = send(
+        &app,
+        authed_request("GET", "/admin/audit?action=impersonated_request", admin, None),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let events = events.as_array().unwrap();
+    assert!(events.iter().all(|e| e["actor_id"] == admin_id && e["user_id"] == customer_id));
+    assert_eq!(events[0]["payload"], json!({ "method": "DELETE", "path": task_uri }));
+
+    // No se puede suplantar a un admin, y quitar el rol invalida los tokens emitidos
+    let (status, _) = send(
+        &app,
+        authed_request("POST", &format!("/admin/users/{}/impersonate", admin_id), admin, Some(json!({ "reason": "x" }))),
+    )
+    .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    sqlx::query("UPDATE users SET role = 'user' WHERE id = ?").bind(admin_id).execute(&pool).await.unwrap();
+    let (status, _) = send(&app, authed_request("GET", "/tasks/", &token, None)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+/// Hace la petición y devuelve las cookies fijadas (`nombre=valor`, sin atributos) y el cuerpo.
+async fn send_for_cookies(app: &axum::Router, request: Request<Body>) -> (StatusCode, Vec<String>, serde_json::Value) {
+    let response = app.clone().oneshot(request).await.unwrap();
+    let status = response.status();
+    let cookies = response
+        .headers()
+        .get_all("set-cookie")
+        .iter()
+        .map(|v| v.to_str().unwrap().split(';').next().unwrap().to_string())
+        .collect();
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    (status, cookies, serde_json::from_slice(&body).unwrap_or(serde_json::Value::Null))
+}
+
+fn cookie_request(method: &str, uri: &str, cookies: &[String], csrf: Option<&str>) -> Request<Body> {
+    let mut builder = Request::builder()
+        .method(method)
+        .uri(uri)
+        .header("cookie", cookies.join("; "))
+        .header("content-type", "application/json");
+    if let Some(csrf) = csrf {
+        builder = builder.header("x-csrf-token", csrf);
+    }
+    builder
+        .body(Body::from(json!({ "title": "from browser", "completed": false }).to_string()))
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_cookie_session_with_csrf() {
+    let (app, pool) = setup().await;
+    register_and_login(&app, "browser@example.com", "password123").await;
+    mark_verified(&pool, "browser@example.com").await;
+
+    let (status, cookies, body) = send_for_cookies(
+        &app,
+        form_request("/token", "username=browser%40example.com&password=password123&cookie=true"),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["token_type"], "cookie");
+    assert!(body.get("access_token").is_none());
+    assert_eq!(cookies.len(), 3);
+    let csrf = body["csrf_token"].as_str().unwrap().to_string();
+
+    // Lecturas solo con la cookie; las escrituras exigen el token CSRF
+    let (status, _) = send(&app, cookie_request("GET", "/tasks/", &cookies, None)).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = send(&app, cookie_request("POST", "/tasks/", &cookies, None)).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (status, _) = send(&app, cookie_request("POST", "/tasks/", &cookies, Some("forged"))).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (status, _) = send(&app, cookie_request("POST", "/tasks/", &cookies, Some(&csrf))).await;
+    assert_eq!(status, StatusCode::OK);
+
+    // La renovación usa la cookie del refresh token y rota también el token CSRF
+    let mut request = form_request("/token", "grant_type=refresh_token");
+    request.headers_mut().insert("cookie", cookies.join("; ").parse().unwrap());
+    let (status, rotated, body) = send_for_cookies(&app, request).await;
+    assert_eq!(status, StatusCode::OK);
+    let new_csrf = body["csrf_token"].as_str().unwrap();
+    assert_ne!(new_csrf, csrf);
+    let (status, _) = send(&app, cookie_request("POST", "/tasks/", &rotated, Some(&csrf))).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (status, _) = send(&app, cookie_request("POST", "/tasks/", &rotated, Some(new_csrf))).await;
+    assert_eq!(status, StatusCode::OK);
+
+    // El logout borra las cookies
+    let (status, cleared, _) = send_for_cookies(&app, cookie_request("POST", "/logout", &rotated, Some(new_csrf))).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(cleared.iter().all(|c| c.ends_with('=')));
+    let (status, _) = send(&app, cookie_request("GET", "/tasks/", &rotated, None)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    // /token/refresh también renueva desde la cookie, y /logout/all borra las cookies
+    let (_, cookies, _) = send_for_cookies(
+        &app,
+        form_request("/token", "username=browser%40example.com&password=password123&cookie=true"),
+    )
+    .await;
+    let mut request = json_request("POST", "/token/refresh", json!({}));
+    request.headers_mut().insert("cookie", cookies.join("; ").parse().unwrap());
+    let (status, rotated, body) = send_for_cookies(&app, request).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(rotated.len(), 3);
+    assert!(body.get("access_token").is_none());
+    let csrf = body["csrf_token"].as_str().unwrap();
+
+    let (status, cleared, _) = send_for_cookies(&app, cookie_request("POST", "/logout/all", &rotated, Some(csrf))).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(cleared.len(), 3);
+    assert!(cleared.iter().all(|c| c.ends_with('=')));
+}
+
+#[tokio::test]
+async fn test_emails_are_normalized() {
+    let (app, pool) = setup().await;
+
+    let (status, user) = send(
+        &app,
+        json_request("POST", "/users/", json!({ "email": "  Bob@Bücher.Example ", "password": "password123" })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(user["email"], "bob@xn--bcher-kva.example");
+
+    // La misma dirección con otras mayúsculas es la misma cuenta
+    let (status, _) = send(
+        &app,
+        json_request("POST", "/users/", json!({ "email": "BOB@BÜCHER.example", "password": "password123" })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) = send(
+        &app,
+        json_request("POST", "/users/", json!({ "email": "bob@[127.0.0.1]", "password": "password123" })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    let (status, tokens) = send(
+        &app,
+        json_request("POST", "/token", json!({ "username": "Bob@bücher.example", "password": "password123" })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+
+    // Los tokens identifican al usuario por su id
+    let claims = crate::tokens::decode_access_token(&test_keys(), tokens["access_token"].as_str().unwrap()).unwrap();
+    assert_eq!(claims.sub, user["id"].to_string());
+
+    // El índice único no distingue mayúsculas aunque se escriba directamente en la DB
+    let duplicate = sqlx::query("INSERT INTO users (email, hashed_password) VALUES ('BOB@xn--bcher-kva.example', '')")
+        .execute(&pool)
+        .await;
+    assert!(duplicate.is_err());
+}
+
+#[tokio::test]
+async fn test_stored_emails_are_renormalized() {
+    let (app, pool) = setup().await;
+
+    // Filas anteriores a la normalización: la migración solo plegó ASCII
+    let hash = crate::password::hash_password(&crate::config::Argon2Config::from_env(), "password123").unwrap();
+    for email in ["Åsa@example.com", "ann@BÜCHER.example"] {
+        sqlx::query("INSERT INTO users (email, hashed_password) VALUES (?, ?)")
+            .bind(email)
+            .bind(&hash)
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+
+    assert_eq!(crate::email::normalize_stored_emails(&pool).await.unwrap(), 2);
+    for email in ["åsa@example.com", "Ann@bücher.example"] {
+        let (status, _) = send(&app, json_request("POST", "/token", json!({ "username": email, "password": "password123" }))).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+    assert_eq!(crate::email::normalize_stored_emails(&pool).await.unwrap(), 0);
+
+    // Si dos cuentas quedarían con el mismo email no se toca ninguna
+    sqlx::query("INSERT INTO users (email, hashed_password) VALUES ('ann@BÜCHER.example', '')")
+        .execute(&pool)
+        .await
+        .unwrap();
+    let error = crate::email::normalize_stored_emails(&pool).await.unwrap_err();
+    assert!(error.to_string().contains("ann@xn--bcher-kva.example"));
+    let stored: Vec<String> = sqlx::query_scalar("SELECT email FROM users WHERE email LIKE 'ann@%' ORDER BY id")
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+    assert_eq!(stored, ["ann@xn--bcher-kva.example", "ann@BÜCHER.example"]);
+}
+
+#[tokio::test]
+async fn test_list_and_revoke_sessions() {
+    let (app, _) = setup().await;
+    let laptop = register_and_login(&app, "sessions@example.com", "password123").await;
+    let laptop_access = laptop["access_token"].as_str().unwrap();
+
+    // Segundo login desde otro dispositivo
+    let mut request = login_request_from([10, 0, 0, 7], "sessions@example.com", "password123");
+    request
+        .headers_mut()
+        .insert("user-agent", "PhoneApp/1.0".parse().unwrap());
+    let (status, phone) = send(&app, request).await;
+    assert_eq!(status, StatusCode::OK);
+    let phone_access = phone["access_token"].as_str().unwrap();
+    let phone_refresh = phone["refresh_token"].as_str().unwrap();
+
+    let (status, sessions) = send(&app, authed_request("GET", "/users/me/sessions", laptop_access, None)).await;
+    assert_eq!(status, StatusCode::OK);
+    let sessions = sessions.as_array().unwrap();
+    assert_eq!(sessions.len(), 2);
+    let phone_session = sessions.iter().find(|s| s["user_agent"] == "PhoneApp/1.0").unwrap();
+    assert_eq!(phone_session["ip"], "10.0.0.7");
+    assert_eq!(phone_session["current"], false);
+    assert_eq!(sessions.iter().filter(|s| s["current"] == true).count(), 1);
+
+    // Revocar la sesión del teléfono invalida su access y su refresh token
+    let uri = format!("/users/me/sessions/{}", phone_session["id"]);
+    let (status, _) = send(&app, authed_request("DELETE", &uri, laptop_access, None)).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (status, _) = send(&app, authed_request("GET", "/tasks/", phone_access, None)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = send(
+        &app,
+        json_request("POST", "/token/refresh", json!({ "refresh_token": phone_refresh })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let (status, _) = send(&app, authed_request("DELETE", &uri, laptop_access, None)).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    // Las rotaciones conservan la sesión del portátil
+    let (status, rotated) = send(
+        &app,
+        json_request("POST", "/token/refresh", json!({ "refresh_token": laptop["refresh_token"] })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, sessions) = send(
+        &app,
+        authed_request("GET", "/users/me/sessions", rotated["access_token"].as_str().unwrap(), None),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(sessions.as_array().unwrap().len(), 1);
+    assert_eq!(sessions[0]["current"], true);
+
+    // Otro usuario no puede revocar sesiones ajenas
+    let other = register_and_login(&app, "other-sessions@example.com", "password123").await;
+    let uri = format!("/users/me/sessions/{}", sessions[0]["id"]);
+    let (status, _) = send(&app, authed_request("DELETE", &uri, other["access_token"].as_str().unwrap(), None)).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_asymmetric_jwt_and_jwks_rotation() {
+    let (app, pool) = setup().await;
+    let tokens = register_and_login(&app, "jwks@example.com", "password123").await;
+    let access = tokens["access_token"].as_str().unwrap();
+
+    // El JWKS publica la clave activa y la anterior, y los tokens indican su `kid`
+    let (status, jwks) = send(&app, Request::get("/.well-known/jwks.json").body(Body::empty()).unwrap()).await;
+    assert_eq!(status, StatusCode::OK);
+    let keys = jwks["keys"].as_array().unwrap();
+    assert_eq!(keys.len(), 2);
+    assert_eq!((keys[0]["kty"].as_str(), keys[0]["alg"].as_str()), (Some("OKP"), Some("EdDSA")));
+    assert_eq!((keys[1]["kty"].as_str(), keys[1]["alg"].as_str()), (Some("RSA"), Some("RS256")));
+    assert!(keys.iter().all(|k| k.get("d").is_none()));
+
+    let header = jsonwebtoken::decode_header(access).unwrap();
+    assert_eq!(header.alg, jsonwebtoken::Algorithm::EdDSA);
+    assert_eq!(header.kid.as_deref(), keys[0]["kid"].as_str());
+
+    // Un token firmado con la clave anterior (RSA) sigue siendo válido
+    let old_keys = JwtKeys::from_pem(
+        include_bytes!("../tests/fixtures/jwt_rsa.pem"),
+        include_bytes!("../tests/fixtures/jwt_rsa.pub.pem"),
+        &[],
+    )
+    .unwrap();
+    let user = sqlx::query_as::<_, crate::models::User>("SELECT * FROM users WHERE email = ?")
+        .bind("jwks@example.com")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    let sid = crate::tokens::decode_access_token(&test_keys(), access).unwrap().sid;
+    let old_token = crate::tokens::create_access_token(&old_keys, &user, sid).unwrap();
+    let (status, _) = send(&app, authed_request("GET", "/tasks/", &old_token, None)).await;
+    assert_eq!(status, StatusCode::OK);
+
+    // Un HS256 con el antiguo secreto por defecto ya no se acepta
+    let forged = jsonwebtoken::encode(
+        &jsonwebtoken::Header::default(),
+        &json!({ "sub": "jwks@example.com", "exp": usize::MAX, "iat": 0, "jti": "x", "role": "admin" }),
+        &jsonwebtoken::EncodingKey::from_secret(b"secret"),
+    )
+    .unwrap();
+    let (status, _) = send(&app, authed_request("GET", "/tasks/", &forged, None)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    // Una clave privada que no corresponde a la pública se rechaza al arrancar
+    assert!(JwtKeys::from_pem(
+        include_bytes!("../tests/fixtures/jwt_ed25519.pem"),
+        include_bytes!("../tests/fixtures/jwt_rsa.pub.pem"),
+        &[],
+    )
+    .is_err());
+}
+
+#[tokio::test]
+async fn test_magic_link_login() {
+    let (app, pool, mailer) = setup_with_mailer().await;
+    let (status, _) = send(
+        &app,
+        json_request("POST", "/users/", json!({ "email": "magic@example.com", "password": "password123" })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (status, _) = send(&app, json_request("POST", "/login/magic-link", json!({ "email": "magic@example.com" }))).await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    let token = token_from_last_email(&mailer, "magic@example.com");
+
+    // Abrir el enlace (o que lo abra un escáner de correo) no lo consume: solo da una página
+    let link = format!("/login/magic-link/exchange?token={}", token);
+    assert!(mailer.sent().pop().unwrap().body.contains(&link));
+    for _ in 0..2 {
+        let response = app
+            .clone()
+            .oneshot(Request::builder().uri(&link).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers()["content-type"].to_str().unwrap().starts_with("text/html"));
+        let page = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let page = String::from_utf8(page.to_vec()).unwrap();
+        assert!(page.contains(&format!(r#"name="token" value="{}""#, token)));
+        assert!(!page.contains("access_token"));
+    }
+
+    // El POST da el mismo par de tokens que /token, una sola vez
+    let exchange = json!({ "token": token });
+    let (status, tokens) = send(&app, json_request("POST", "/login/magic-link/exchange", exchange.clone())).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(tokens["refresh_token"].is_string());
+    let (status, _) = send(&app, json_request("POST", "/login/magic-link/exchange", exchange)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = send(&app, Request::builder().uri(&link).body(Body::empty()).unwrap()).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    // El formulario de la página termina en una sesión de cookies, sin tokens en el cuerpo
+    send(&app, json_request("POST", "/login/magic-link", json!({ "email": "magic@example.com" }))).await;
+    let token = token_from_last_email(&mailer, "magic@example.com");
+    let form = format!("token={}&cookie=true", token);
+    let (status, cookies, session) =
+        send_for_cookies(&app, form_request("/login/magic-link/exchange", &form)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(session["token_type"], "cookie");
+    assert!(session.get("access_token").is_none());
+    assert!(cookies.iter().any(|c| c.starts_with("access_token=")));
+
+    // Abrir el enlace verifica el email
+    let verified: Option<chrono::NaiveDateTime> =
+        sqlx::query_scalar("SELECT email_verified_at FROM users WHERE email = 'magic@example.com'")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+    assert!(verified.is_some());
+
+    // Límite por email, exista o no la cuenta
+    for email in ["magic@example.com", "ghost@example.com"] {
+        let emails_before = mailer.sent().len();
+        let mut statuses = Vec::new();
+        for _ in 0..4 {
+            let (status, _) = send(&app, json_request("POST", "/login/magic-link", json!({ "email": email }))).await;
+            statuses.push(status);
+        }
+        let expected_ok = if email == "magic@example.com" { 1 } else { 3 };
+        assert_eq!(statuses.iter().filter(|s| **s == StatusCode::ACCEPTED).count(), expected_ok);
+        assert_eq!(*statuses.last().unwrap(), StatusCode::TOO_MANY_REQUESTS);
+        if email == "ghost@example.com" {
+            assert_eq!(mailer.sent().len(), emails_before);
+        }
+    }
+}
+
+struct MockGrant {
+    code_challenge: String,
+    claims: serde_json::Value,
+}
+
+/// IdP OpenID Connect mínimo, servido en un puerto local durante el test.
+#[derive(Clone)]
+struct MockIdp {
+    issuer: String,
+    keys: Arc<JwtKeys>,
+    grants: Arc<std::sync::Mutex<std::collections::HashMap<String, MockGrant>>>,
+}
+
+impl MockIdp {
+    async fn start() -> Self {
+        use axum::{extract::State, routing::{get, post}, Form, Json};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let idp = MockIdp {
+            issuer: format!("http://{}", listener.local_addr().unwrap()),
+            keys: Arc::new(
+                JwtKeys::from_pem(
+                    include_bytes!("../tests/fixtures/jwt_rsa.pem"),
+                    include_bytes!("../tests/fixtures/jwt_rsa.pub.pem"),
+                    &[],
+                )
+                .unwrap(),
+            ),
+            grants: Default::default(),
+        };
+
+        async fn discovery(State(idp): State<MockIdp>) -> Json<serde_json::Value> {
+            Json(json!({
+                "issuer": idp.issuer,
+                "authorization_endpoint": format!("{}/authorize", idp.issuer),
+                "token_endpoint": format!("{}/token", idp.issuer),
+                "jwks_uri": format!("{}/jwks", idp.issuer),
+            }))
+        }
+
+        async fn jwks(State(idp): State<MockIdp>) -> Json<jsonwebtoken::jwk::JwkSet> {
+            Json(idp.keys.jwks())
+        }
+
+        async fn token(
+            State(idp): State<MockIdp>,
+            Form(form): Form<std::collections::HashMap<String, String>>,
+        ) -> Result<Json<serde_json::Value>, StatusCode> {
+            let grant = idp.grants.lock().unwrap().remove(&form["code"]).ok_or(StatusCode::BAD_REQUEST)?;
+            if form["grant_type"] != "authorization_code"
+                || form["client_id"] != "test-client"
+                || crate::oidc::pkce_challenge(&form["code_verifier"]) != grant.code_challenge
+            {
+                return Err(StatusCode::BAD_REQUEST);
+            }
+
+            let id_token = idp.keys.encode(&grant.claims).unwrap();
+            Ok(Json(json!({ "access_token": "opaque", "token_type": "Bearer", "id_token": id_token })))
+        }
+
+        let router = axum::Router::new()
+            .route("/.well-known/openid-configuration", get(discovery))
+            .route("/jwks", get(jwks))
+            .route("/token", post(token))
+            .with_state(idp.clone());
+        tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+
+        idp
+    }
+
+    fn provider(&self) -> crate::config::OidcProviderConfig {
+        crate::config::OidcProviderConfig {
+            name: "mock".to_string(),
+            issuer: self.issuer.clone(),
+            client_id: "test-client".to_string(),
+            client_secret: Some("test-secret".to_string()),
+            scopes: "openid email".to_string(),
+            allow_signup: true,
+        }
+    }
+
+    /// Recorre el flujo completo como lo haría el navegador, con el usuario `claims`
+    /// ya autenticado en el IdP. Devuelve la respuesta del callback y el `state` usado.
+    async fn login(&self, app: &axum::Router, claims: serde_json::Value) -> (StatusCode, serde_json::Value, String) {
+        let response = app
+            .clone()
+            .oneshot(Request::get("/auth/oidc/mock/authorize").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        let location = response.headers()["location"].to_str().unwrap().to_string();
+        let state_cookie = oidc_state_cookie(&response);
+        self.callback(app, &location, Some(&state_cookie), claims).await
+    }
+
+    /// Como `login`, pero vinculando la identidad a la cuenta de `access`.
+    async fn link(&self, app: &axum::Router, access: &str, claims: serde_json::Value) -> (StatusCode, serde_json::Value) {
+        let (status, cookies, body) =
+            send_for_cookies(app, authed_request("POST", "/auth/oidc/mock/link", access, None)).await;
+        assert_eq!(status, StatusCode::OK);
+        let state_cookie = cookies.iter().find(|c| c.starts_with("oidc_state=")).unwrap();
+        let authorization_url = body["authorization_url"].as_str().unwrap();
+        let (status, body, _) = self.callback(app, authorization_url, Some(state_cookie), claims).await;
+        (status, body)
+    }
+
+    /// Autoriza en el IdP la petición de `authorization_url` y vuelve al callback, con la
+    /// cookie `oidc_state` que guardó el navegador al iniciar el flujo (si se indica).
+    async fn callback(
+        &self,
+        app: &axum::Router,
+        authorization_url: &str,
+        state_cookie: Option<&str>,
+        claims: serde_json::Value,
+    ) -> (StatusCode, serde_json::Value, String) {
+        let location = url::Url::parse(authorization_url).unwrap();
+        assert!(location.as_str().starts_with(&format!("{}/authorize", self.issuer)));
+        let query: std::collections::HashMap<_, _> = location.query_pairs().into_owned().collect();
+        assert_eq!(query["code_challenge_method"], "S256");
+
+        let mut claims = claims;
+        claims["iss"] = json!(self.issuer);
+        claims["aud"] = json!("test-client");
+        claims["exp"] = json!(crate::tokens::now_secs() + 300);
+        claims["nonce"] = json!(query["nonce"]);
+
+        let code = crate::tokens::generate_opaque_token();
+        self.grants.lock().unwrap().insert(
+            code.clone(),
+            MockGrant { code_challenge: query["code_challenge"].clone(), claims },
+        );
+
+        let uri = format!("/auth/oidc/mock/callback?code={}&state={}", code, query["state"]);
+        let mut request = Request::get(uri);
+        if let Some(state_cookie) = state_cookie {
+            request = request.header("cookie", state_cookie);
+        }
+        let (status, body) = send(app, request.body(Body::empty()).unwrap()).await;
+        (status, body, query["state"].clone())
+    }
+}
+
+/// Cookie `oidc_state` (`nombre=valor`) que fija el inicio de un flujo OIDC.
+fn oidc_state_cookie(response: &axum::response::Response) -> String {
+    response
+        .headers()
+        .get_all("set-cookie")
+        .iter()
+        .map(|v| v.to_str().unwrap().split(';').next().unwrap().to_string())
+        .find(|c| c.starts_with("oidc_state="))
+        .expect("authorize sets the oidc_state cookie")
+}
+
+#[tokio::test]
+async fn test_oidc_login_provisions_and_links_users() {
+    let idp = MockIdp::start().await;
+    let provider = idp.provider();
+    let (app, pool, _) = setup_with_config(|config| {
+        config.oidc_providers.insert("mock".to_string(), provider);
+    })
+    .await;
+
+    // Primer login: se crea la cuenta (con el email ya verificado)
+    let sso_user = json!({ "sub": "idp-1", "email": "sso@example.com", "email_verified": true });
+    let (status, tokens, used_state) = idp.login(&app, sso_user.clone()).await;
+    assert_eq!(status, StatusCode::OK);
+    let access = tokens["access_token"].as_str().unwrap();
+    let task = json!({ "title": "From SSO", "description": null, "completed": false });
+    let (status, _) = send(&app, authed_request("POST", "/tasks/", access, Some(task))).await;
+    assert_eq!(status, StatusCode::OK);
+
+    // Siguiente login: misma cuenta; el state no puede reutilizarse
+    let (status, _, _) = idp.login(&app, sso_user).await;
+    assert_eq!(status, StatusCode::OK);
+    let uri = format!("/auth/oidc/mock/callback?code=whatever&state={}", used_state);
+    let state_cookie = format!("oidc_state={}", crate::tokens::hash_token(&used_state));
+    let (status, _) = send(&app, Request::get(uri).header("cookie", state_cookie).body(Body::empty()).unwrap()).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    // Una cuenta local existente se vincula por email verificado
+    register_and_login(&app, "local@example.com", "password123").await;
+    let (status, _, _) = idp
+        .login(&app, json!({ "sub": "idp-2", "email": "local@example.com", "email_verified": true }))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+
+    let users: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM users").fetch_one(&pool).await.unwrap();
+    assert_eq!(users, 2);
+    let linked: i64 = sqlx::query_scalar(
+        "SELECT COUNT(*) FROM user_identities JOIN users ON users.id = user_id
+        WHERE email_verified_at IS NOT NULL",
+    )
+    .fetch_one(&pool)
+    .await
+    .unwrap();
+    assert_eq!(linked, 2);
+
+    // El alta por SSO y cada vinculación quedan en la auditoría
+    let events: Vec<(String, Option<i64>)> =
+        sqlx::query_as("SELECT action, user_id FROM audit_events WHERE action IN ('user_registered', 'identity_linked') ORDER BY id")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+    let sso_id = user_id(&pool, "sso@example.com").await;
+    let local_id = user_id(&pool, "local@example.com").await;
+    assert_eq!(
+        events,
+        [
+            ("user_registered".to_string(), Some(sso_id)),
+            ("identity_linked".to_string(), Some(sso_id)),
+            ("user_registered".to_string(), Some(local_id)),
+            ("identity_linked".to_string(), Some(local_id)),
+        ]
+    );
+
+    // Sin email verificado no se vincula ni se crea nada
+    let (status, _, _) = idp
+        .login(&app, json!({ "sub": "idp-3", "email": "local@example.com", "email_verified": false }))
+        .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    // Un flujo iniciado con `cookie=true` termina en una sesión de cookies
+    let response = app
+        .clone()
+        .oneshot(Request::get("/auth/oidc/mock/authorize?cookie=true").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    let location = response.headers()["location"].to_str().unwrap().to_string();
+    let state_cookie = oidc_state_cookie(&response);
+    let (status, session, _) = idp
+        .callback(
+            &app,
+            &location,
+            Some(&state_cookie),
+            json!({ "sub": "idp-1", "email": "sso@example.com", "email_verified": true }),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(session["token_type"], "cookie");
+    assert!(session.get("access_token").is_none());
+
+    // Proveedor desconocido
+    let (status, _) = send(&app, Request::get("/auth/oidc/nope/authorize").body(Body::empty()).unwrap()).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_oidc_login_does_not_keep_preregistered_access() {
+    let idp = MockIdp::start().await;
+    let provider = idp.provider();
+    let (app, pool, _) = setup_with_config(|config| {
+        config.oidc_providers.insert("mock".to_string(), provider);
+    })
+    .await;
+
+    // Un atacante registra la cuenta con el email de la víctima (sin verificarlo) y
+    // deja una sesión y una API key abiertas
+    let attacker = register_and_login(&app, "victim@example.com", "attacker-pass1").await;
+    let attacker_access = attacker["access_token"].as_str().unwrap();
+    let (status, key) = send(
+        &app,
+        authed_request("POST", "/users/me/api-keys", attacker_access, Some(json!({ "name": "backdoor", "scope": "read" }))),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+
+    // La víctima entra por SSO: la cuenta se vincula, pero el atacante pierde el acceso
+    let victim = json!({ "sub": "idp-victim", "email": "victim@example.com", "email_verified": true });
+    let (status, tokens, _) = idp.login(&app, victim).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = send(&app, authed_request("GET", "/users/me", tokens["access_token"].as_str().unwrap(), None)).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (status, _) = send(&app, authed_request("GET", "/users/me", attacker_access, None)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = send(&app, authed_request("GET", "/tasks/", key["key"].as_str().unwrap(), None)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = send(
+        &app,
+        json_request("POST", "/token", json!({ "username": "victim@example.com", "password": "attacker-pass1" })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    // Un admin no se vincula por email, solo desde su propia sesión
+    let admin = register_admin(&app, &pool, "boss@example.com").await;
+    let admin_access = admin["access_token"].as_str().unwrap();
+    let boss = json!({ "sub": "idp-boss", "email": "boss@example.com", "email_verified": true });
+    let (status, _, _) = idp.login(&app, boss.clone()).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    let (status, tokens) = idp.link(&app, admin_access, boss.clone()).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(tokens["access_token"].is_string());
+    let (status, _, _) = idp.login(&app, boss).await;
+    assert_eq!(status, StatusCode::OK);
+
+    // Una identidad ya vinculada no se puede pasar a otra cuenta
+    let other = register_and_login(&app, "other@example.com", "password123").await;
+    let (status, _) = idp
+        .link(
+            &app,
+            other["access_token"].as_str().unwrap(),
+            json!({ "sub": "idp-victim", "email": "victim@example.com", "email_verified": true }),
+        )
+        .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn test_oidc_callback_requires_the_browser_that_started_the_flow() {
+    let idp = MockIdp::start().await;
+    let provider = idp.provider();
+    let (app, pool, _) = setup_with_config(|config| {
+        config.oidc_providers.insert("mock".to_string(), provider);
+    })
+    .await;
+    let victim = json!({ "sub": "idp-victim", "email": "victim@example.com", "email_verified": true });
+
+    // El atacante inicia una vinculación con su sesión y hace abrir la URL a la víctima,
+    // cuyo navegador no tiene la cookie del flujo
+    let attacker = register_and_login(&app, "attacker@example.com", "password123").await;
+    let attacker_access = attacker["access_token"].as_str().unwrap();
+    let (status, cookies, body) =
+        send_for_cookies(&app, authed_request("POST", "/auth/oidc/mock/link", attacker_access, None)).await;
+    assert_eq!(status, StatusCode::OK);
+    let attacker_cookie = cookies.iter().find(|c| c.starts_with("oidc_state=")).unwrap().clone();
+    let link_url = body["authorization_url"].as_str().unwrap().to_string();
+
+    let (status, _, _) = idp.callback(&app, &link_url, None, victim.clone()).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    // Tampoco vale la cookie de otro flujo de la víctima
+    let response = app
+        .clone()
+        .oneshot(Request::get("/auth/oidc/mock/authorize").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    let victim_cookie = oidc_state_cookie(&response);
+    let (status, _, _) = idp.callback(&app, &link_url, Some(&victim_cookie), victim.clone()).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let linked: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM user_identities").fetch_one(&pool).await.unwrap();
+    assert_eq!(linked, 0);
+
+    // Lo mismo con un login (login CSRF): sin la cookie no hay sesión
+    let response = app
+        .clone()
+        .oneshot(Request::get("/auth/oidc/mock/authorize?cookie=true").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    let location = response.headers()["location"].to_str().unwrap().to_string();
+    let (status, _, _) = idp.callback(&app, &location, None, victim.clone()).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    // El rechazo no consume el flujo: el navegador que lo inició aún puede terminarlo
+    let (status, tokens, _) = idp.callback(&app, &link_url, Some(&attacker_cookie), victim).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(tokens["access_token"].is_string());
+}
+
+#[tokio::test]
+async fn test_login_rehashes_legacy_and_weak_password_hashes() {
+    let (app, pool, _) = setup_with_config(|config| config.argon2.iterations = 3).await;
+
+    let weak = crate::config::Argon2Config { memory_kib: 8 * 1024, iterations: 1, parallelism: 1 };
+    let legacy_hashes = [
+        ("bcrypt@example.com", bcrypt::hash("password123", 4).unwrap()),
+        ("weak@example.com", crate::password::hash_password(&weak, "password123").unwrap()),
+    ];
+
+    for (email, hash) in legacy_hashes {
+        sqlx::query("INSERT INTO users (email, hashed_password) VALUES (?, ?)")
+            .bind(email)
+            .bind(&hash)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        // Un password erróneo no toca el hash
+        let (status, _) = send(&app, json_request("POST", "/token", json!({ "username": email, "password": "wrong" }))).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, _) = send(&app, json_request("POST", "/token", json!({ "username": email, "password": "password123" }))).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let upgraded: String = sqlx::query_scalar("SELECT hashed_password FROM users WHERE email = ?")
+            .bind(email)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_ne!(upgraded, hash);
+        assert!(upgraded.starts_with("$argon2id$"));
+        assert!(upgraded.contains("m=19456,t=3,p=1"));
+
+        // El hash nuevo sigue funcionando y ya no se rehace
+        let (status, _) = send(&app, json_request("POST", "/token", json!({ "username": email, "password": "password123" }))).await;
+        assert_eq!(status, StatusCode::OK);
+        let current: String = sqlx::query_scalar("SELECT hashed_password FROM users WHERE email = ?")
+            .bind(email)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(current, upgraded);
+    }
+}
+
+#[tokio::test]
+async fn test_password_policy_violations() {
+    use sha1::{Digest, Sha1};
+
+    // Volcado de contraseñas filtradas con un único rango que contiene "Summer2024!"
+    let breached_dir = std::env::temp_dir().join(format!("breached-{}", crate::tokens::generate_opaque_token()));
+    std::fs::create_dir_all(&breached_dir).unwrap();
+    let hash: String = Sha1::digest(b"Summer2024!").iter().map(|b| format!("{:02X}", b)).collect();
+    std::fs::write(
+        breached_dir.join(format!("{}.txt", &hash[..5])),
+        format!("0018A45C4D1DEF81644B54AB7F969B88D65:1\r\n{}:42\r\n", &hash[5..]),
+    )
+    .unwrap();
+
+    let dir = breached_dir.clone();
+    let (app, _, _) = setup_with_config(move |config| config.password_policy.breached_passwords_dir = Some(dir)).await;
+    let register = |password: &str| {
+        json_request("POST", "/users/", json!({ "email": "policy@example.com", "password": password }))
+    };
+    let codes = |body: &serde_json::Value| -> Vec<String> {
+        body["violations"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|v| v["code"].as_str().unwrap().to_string())
+            .collect()
+    };
+
+    let (status, body) = send(&app, register("")).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(codes(&body), ["too_short", "missing_character_classes"]);
+
+    let (status, body) = send(&app, register(&"a1".repeat(100))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(codes(&body), ["too_long"]);
+
+    let (status, body) = send(&app, register("Policy@Example.com")).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(codes(&body), ["contains_email"]);
+
+    let (status, body) = send(&app, register("Summer2024!")).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(codes(&body), ["breached"]);
+
+    // El cambio de contraseña aplica la misma política
+    let (status, _) = send(&app, register("correct horse 42")).await;
+    assert_eq!(status, StatusCode::OK);
+    let (_, tokens) = send(
+        &app,
+        json_request("POST", "/token", json!({ "username": "policy@example.com", "password": "correct horse 42" })),
+    )
+    .await;
+    let change = json!({ "current_password": "correct horse 42", "new_password": "Summer2024!" });
+    let (status, body) = send(
+        &app,
+        authed_request("PUT", "/users/me/password", tokens["access_token"].as_str().unwrap(), Some(change)),
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(codes(&body), ["breached"]);
+
+    std::fs::remove_dir_all(breached_dir).unwrap();
+}
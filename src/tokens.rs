@@ -0,0 +1,334 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{Duration, NaiveDateTime, Utc};
+use rand_core::{OsRng, RngCore};
+use sha2::{Digest, Sha256};
+use sqlx::{FromRow, SqlitePool};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{
+    audit,
+    error::AppError,
+    keys::JwtKeys,
+    middleware::ClientInfo,
+    models::{Actor, AuditAction, Claims, MfaClaims, Token, User},
+    sessions,
+};
+
+pub const ACCESS_TOKEN_TTL_SECS: usize = 60 * 30; // 30 minutos
+pub const REFRESH_TOKEN_TTL_DAYS: i64 = 30;
+pub const MFA_TOKEN_TTL_SECS: usize = 60 * 5;
+pub const IMPERSONATION_TOKEN_TTL_SECS: usize = 60 * 15;
+const MFA_AUDIENCE: &str = "mfa";
+
+#[derive(Debug, FromRow)]
+struct StoredRefreshToken {
+    id: i64,
+    user_id: i64,
+    family_id: String,
+    expires_at: NaiveDateTime,
+    used_at: Option<NaiveDateTime>,
+    revoked_at: Option<NaiveDateTime>,
+}
+
+/// Genera un token opaco de 256 bits codificado en base64url.
+pub fn generate_opaque_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/// Hash SHA-256 (hex) con el que se guardan los tokens opacos en la DB.
+pub fn hash_token(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Epoch seconds actuales, el formato de 'iat' y 'exp' en el JWT.
+pub fn now_secs() -> usize {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs() as usize
+}
+
+/// Id del usuario al que se refiere el claim `sub`.
+pub fn subject_user_id(sub: &str) -> Result<i64, AppError> {
+    sub.parse()
+        .map_err(|_| AppError::AuthError("Invalid token subject".to_string()))
+}
+
+fn sign_access_token(
+    keys: &JwtKeys,
+    user: &User,
+    session_id: i64,
+    ttl_secs: usize,
+    act: Option<Actor>,
+) -> Result<String, AppError> {
+    // Tras un "logout all" `tokens_valid_after` puede estar hasta un segundo en el futuro;
+    // los tokens nuevos nunca deben quedar por debajo del corte.
+    let issued_at = now_secs().max(user.tokens_valid_after.unwrap_or(0) as usize);
+
+    let claims = Claims {
+        sub: user.id.to_string(),
+        exp: issued_at + ttl_secs,
+        iat: issued_at,
+        jti: generate_opaque_token(),
+        role: user.role,
+        sid: session_id,
+        act,
+        csrf: None,
+    };
+
+    keys.encode(&claims)
+        .map_err(|e| AppError::AuthError(format!("Token creation failed: {}", e)))
+}
+
+pub fn create_access_token(keys: &JwtKeys, user: &User, session_id: i64) -> Result<String, AppError> {
+    sign_access_token(keys, user, session_id, ACCESS_TOKEN_TTL_SECS, None)
+}
+
+/// Token con el que `actor` (un admin) actúa como `user`. Es corto y no se puede renovar.
+pub fn create_impersonation_token(
+    keys: &JwtKeys,
+    user: &User,
+    session_id: i64,
+    actor: Actor,
+) -> Result<String, AppError> {
+    sign_access_token(keys, user, session_id, IMPERSONATION_TOKEN_TTL_SECS, Some(actor))
+}
+
+pub fn decode_access_token(keys: &JwtKeys, token: &str) -> Result<Claims, AppError> {
+    keys.decode::<Claims>(token, None)
+        .map_err(|e| AppError::AuthError(format!("Invalid token: {}", e)))
+}
+
+/// Vuelve a firmar el access token del par con el hash de un token CSRF nuevo, que se
+/// devuelve. Para los tokens que viajan en cookies (ver `cookies::verify_csrf`).
+pub fn bind_csrf(keys: &JwtKeys, token: &mut Token) -> Result<String, AppError> {
+    let mut claims = decode_access_token(keys, &token.access_token)?;
+    let csrf_token = generate_opaque_token();
+    claims.csrf = Some(hash_token(&csrf_token));
+
+    token.access_token = keys
+        .encode(&claims)
+        .map_err(|e| AppError::AuthError(format!("Token creation failed: {}", e)))?;
+
+    Ok(csrf_token)
+}
+
+/// Token intermedio que prueba que el password fue correcto; solo sirve en `/token/mfa`.
+/// Lleva `aud`, así que `decode_access_token` lo rechaza.
+pub fn create_mfa_token(keys: &JwtKeys, user: &User) -> Result<String, AppError> {
+    let issued_at = now_secs();
+
+    let claims = MfaClaims {
+        sub: user.id.to_string(),
+        exp: issued_at + MFA_TOKEN_TTL_SECS,
+        iat: issued_at,
+        jti: generate_opaque_token(),
+        aud: MFA_AUDIENCE.to_string(),
+    };
+
+    keys.encode(&claims)
+        .map_err(|e| AppError::AuthError(format!("Token creation failed: {}", e)))
+}
+
+pub fn decode_mfa_token(keys: &JwtKeys, token: &str) -> Result<MfaClaims, AppError> {
+    keys.decode::<MfaClaims>(token, Some(MFA_AUDIENCE))
+        .map_err(|e| AppError::AuthError(format!("Invalid MFA token: {}", e)))
+}
+
+/// Crea un refresh token de la familia indicada: la de la sesión que abrió el login,
+/// que las rotaciones conservan.
+async fn issue_refresh_token(
+    pool: &SqlitePool,
+    user_id: i64,
+    family_id: &str,
+) -> Result<String, AppError> {
+    let token = generate_opaque_token();
+    let expires_at = (Utc::now() + Duration::days(REFRESH_TOKEN_TTL_DAYS)).naive_utc();
+
+    sqlx::query(
+        "INSERT INTO refresh_tokens (user_id, token_hash, family_id, expires_at) VALUES (?, ?, ?, ?)",
+    )
+    .bind(user_id)
+    .bind(hash_token(&token))
+    .bind(family_id)
+    .bind(expires_at)
+    .execute(pool)
+    .await?;
+
+    Ok(token)
+}
+
+async fn build_token_pair(
+    pool: &SqlitePool,
+    keys: &JwtKeys,
+    user: &User,
+    session_id: i64,
+    family_id: &str,
+) -> Result<Token, AppError> {
+    let access_token = create_access_token(keys, user, session_id)?;
+    let refresh_token = issue_refresh_token(pool, user.id, family_id).await?;
+
+    Ok(Token {
+        access_token,
+        token_type: "bearer".to_string(),
+        expires_in: ACCESS_TOKEN_TTL_SECS,
+        refresh_token,
+    })
+}
+
+/// Abre una sesión nueva para el dispositivo del cliente y emite su primer par de tokens.
+pub async fn issue_token_pair(
+    pool: &SqlitePool,
+    keys: &JwtKeys,
+    user: &User,
+    client: &ClientInfo,
+) -> Result<Token, AppError> {
+    let (session_id, family_id) = sessions::create(pool, user.id, client).await?;
+
+    build_token_pair(pool, keys, user, session_id, &family_id).await
+}
+
+/// Invalida toda la familia de un refresh token y su sesión (logout o detección de
+/// reutilización).
+async fn revoke_family(pool: &SqlitePool, family_id: &str) -> Result<(), AppError> {
+    let now = Utc::now().naive_utc();
+    let mut tx = pool.begin().await?;
+
+    sqlx::query(
+        "UPDATE refresh_tokens SET revoked_at = ? WHERE family_id = ? AND revoked_at IS NULL",
+    )
+    .bind(now)
+    .bind(family_id)
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query("UPDATE sessions SET revoked_at = ? WHERE family_id = ? AND revoked_at IS NULL")
+        .bind(now)
+        .bind(family_id)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// Revoca la familia de un refresh token del usuario (logout). Los tokens desconocidos
+/// o de otro usuario se ignoran.
+pub async fn revoke_refresh_token(
+    pool: &SqlitePool,
+    user_id: i64,
+    presented: &str,
+) -> Result<(), AppError> {
+    let family_id: Option<String> = sqlx::query_scalar(
+        "SELECT family_id FROM refresh_tokens WHERE token_hash = ? AND user_id = ?",
+    )
+    .bind(hash_token(presented))
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await?;
+
+    if let Some(family_id) = family_id {
+        revoke_family(pool, &family_id).await?;
+    }
+
+    Ok(())
+}
+
+/// Canjea un refresh token por un par nuevo. El token presentado queda marcado como
+/// usado; si un token ya rotado vuelve a presentarse se revoca toda su familia.
+pub async fn rotate_refresh_token(
+    pool: &SqlitePool,
+    keys: &JwtKeys,
+    client: &ClientInfo,
+    presented: &str,
+) -> Result<Token, AppError> {
+    let stored = sqlx::query_as::<_, StoredRefreshToken>(
+        "SELECT id, user_id, family_id, expires_at, used_at, revoked_at
+        FROM refresh_tokens WHERE token_hash = ?",
+    )
+    .bind(hash_token(presented))
+    .fetch_optional(pool)
+    .await?
+    .ok_or(AppError::AuthError("Invalid refresh token".to_string()))?;
+
+    if stored.revoked_at.is_some() {
+        return Err(AppError::AuthError("Refresh token revoked".to_string()));
+    }
+
+    if stored.used_at.is_some() {
+        tracing::warn!(
+            "Refresh token reuse detected for user {}, revoking family",
+            stored.user_id
+        );
+        revoke_family(pool, &stored.family_id).await?;
+        audit_reuse(pool, client, &stored).await;
+        return Err(AppError::AuthError("Refresh token reuse detected".to_string()));
+    }
+
+    let now = Utc::now().naive_utc();
+    if stored.expires_at <= now {
+        return Err(AppError::AuthError("Refresh token expired".to_string()));
+    }
+
+    // Marcar como usado de forma atómica: si otra petición lo canjeó antes
+    // tratamos el caso como reutilización.
+    let result = sqlx::query(
+        "UPDATE refresh_tokens SET used_at = ? WHERE id = ? AND used_at IS NULL AND revoked_at IS NULL",
+    )
+    .bind(now)
+    .bind(stored.id)
+    .execute(pool)
+    .await?;
+
+    if result.rows_affected() == 0 {
+        revoke_family(pool, &stored.family_id).await?;
+        audit_reuse(pool, client, &stored).await;
+        return Err(AppError::AuthError("Refresh token reuse detected".to_string()));
+    }
+
+    let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = ?")
+        .bind(stored.user_id)
+        .fetch_optional(pool)
+        .await?
+        .ok_or(AppError::AuthError("User not found".to_string()))?;
+
+    if !user.is_active {
+        return Err(AppError::AccountDisabled("Account is deactivated".to_string()));
+    }
+
+    let session_id = sessions::find_by_family(pool, &stored.family_id)
+        .await?
+        .ok_or(AppError::AuthError("Session has been revoked".to_string()))?;
+    sessions::touch(pool, user.id, session_id).await?;
+
+    audit::record_applied(
+        pool,
+        client,
+        AuditAction::TokenRefreshed,
+        Some(user.id),
+        Some(user.id),
+        serde_json::json!({ "session_id": session_id }),
+    )
+    .await;
+
+    build_token_pair(pool, keys, &user, session_id, &stored.family_id).await
+}
+
+/// La reutilización de un refresh token suele indicar que lo robaron.
+async fn audit_reuse(pool: &SqlitePool, client: &ClientInfo, stored: &StoredRefreshToken) {
+    audit::record_applied(
+        pool,
+        client,
+        AuditAction::RefreshTokenReused,
+        Some(stored.user_id),
+        None,
+        serde_json::json!({ "family_id": stored.family_id }),
+    )
+    .await
+}